@@ -1,15 +1,22 @@
 pub mod input {
-    use std::net::SocketAddr;
+    use std::{collections::HashMap, net::SocketAddr};
 
+    use chrono::{DateTime, Utc};
     use serde::{Deserialize, Serialize};
 
-    use crate::input::Input;
+    use crate::{input::Input, principal::UserKind};
 
-    #[derive(Clone, Debug, Default)]
+    #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+    #[serde(default)]
     pub struct ConditionCtx {
         pub from: Condition,
         pub proxy: Condition,
         pub to: Condition,
+        pub request: RequestAttrs,
+        /// Kind of the user that sent the request
+        pub user_kind: Option<UserKind>,
+        /// The time the request is evaluated at, the system clock is used if not set
+        pub at: Option<DateTime<Utc>>,
     }
 
     #[derive(Clone, Debug, Default, Serialize, Deserialize)]
@@ -19,6 +26,21 @@ pub mod input {
         pub env: Option<String>,
     }
 
+    /// Attributes of the incoming request and the client connection it came from
+    #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct RequestAttrs {
+        pub tls: bool,
+        pub method: Option<String>,
+        pub user_agent: Option<String>,
+        /// Keyed by lowercase header name, values of a repeated header are joined by `, `.
+        /// Only the ones passing [`is_kept_header`] are kept.
+        pub headers: HashMap<String, String>,
+        /// Size of the request body if known from headers, the decoded size is used
+        /// for aws-chunked uploads
+        pub content_length: Option<u64>,
+    }
+
     impl ConditionCtx {
         pub fn from(mut self, from: Condition) -> Self {
             self.from = from;
@@ -34,6 +56,25 @@ pub mod input {
             self.to = to;
             self
         }
+
+        pub fn request(mut self, request: RequestAttrs) -> Self {
+            self.request = request;
+            self
+        }
+
+        pub fn user_kind(mut self, user_kind: UserKind) -> Self {
+            self.user_kind = Some(user_kind);
+            self
+        }
+
+        pub fn at(mut self, at: DateTime<Utc>) -> Self {
+            self.at = Some(at);
+            self
+        }
+
+        pub fn time(&self) -> DateTime<Utc> {
+            self.at.unwrap_or_else(Utc::now)
+        }
     }
 
     impl Condition {
@@ -53,5 +94,160 @@ pub mod input {
         }
     }
 
+    impl RequestAttrs {
+        pub fn new_with<B>(req: &http::Request<B>, tls: bool) -> Self {
+            let mut headers: HashMap<String, String> = HashMap::new();
+            for (name, value) in req.headers() {
+                if !is_kept_header(name.as_str()) {
+                    continue;
+                }
+                let Ok(value) = value.to_str() else {
+                    continue;
+                };
+                headers
+                    .entry(name.as_str().to_string())
+                    .and_modify(|v| {
+                        v.push_str(", ");
+                        v.push_str(value);
+                    })
+                    .or_insert_with(|| value.to_string());
+            }
+            let header = |name: &str| headers.get(name).map(String::as_str);
+            let aws_chunked = header("x-amz-content-sha256")
+                .is_some_and(|v| v.starts_with("STREAMING-"))
+                || header("content-encoding")
+                    .is_some_and(|v| v.split(',').any(|e| e.trim() == "aws-chunked"));
+            // Content-Length of an aws-chunked upload counts the chunk signatures too
+            let content_length = match aws_chunked {
+                true => header("x-amz-decoded-content-length"),
+                false => header("content-length"),
+            }
+            .and_then(|v| v.parse().ok());
+            Self {
+                tls,
+                method: Some(req.method().to_string()),
+                user_agent: headers.get("user-agent").cloned(),
+                headers,
+                content_length,
+            }
+        }
+
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .get(&name.to_ascii_lowercase())
+                .map(String::as_str)
+        }
+    }
+
+    /// Headers kept in [`RequestAttrs`], the ones carrying credentials such as `Authorization`
+    /// are left out since the attributes get logged
+    const KEPT_HEADERS: [&str; 10] = [
+        "cache-control",
+        "content-disposition",
+        "content-encoding",
+        "content-language",
+        "content-length",
+        "content-md5",
+        "content-type",
+        "expect",
+        "host",
+        "user-agent",
+    ];
+
+    /// `x-amz-*` headers are kept except for these, which carry credentials or SSE-C keys
+    const DROPPED_AMZ_HEADERS: [&str; 6] = [
+        "x-amz-security-token",
+        "x-amz-credential",
+        "x-amz-server-side-encryption-customer-key",
+        "x-amz-server-side-encryption-customer-key-md5",
+        "x-amz-copy-source-server-side-encryption-customer-key",
+        "x-amz-copy-source-server-side-encryption-customer-key-md5",
+    ];
+
+    pub fn is_kept_header(name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        match name.starts_with("x-amz-") {
+            true => !DROPPED_AMZ_HEADERS.contains(&name.as_str()),
+            false => KEPT_HEADERS.contains(&name.as_str()),
+        }
+    }
+
     impl Input for ConditionCtx {}
+
+    #[cfg(test)]
+    mod test {
+        use crate::condition::input::RequestAttrs;
+
+        #[test]
+        fn request_attrs_from_request() {
+            let req = http::Request::put("/bucket/key")
+                .header("User-Agent", "aws-sdk-java/1.11.0")
+                .header("X-Amz-Meta-Tag", "a")
+                .header("x-amz-meta-tag", "b")
+                .header("Authorization", "AWS4-HMAC-SHA256 Credential=...")
+                .header("X-Amz-Security-Token", "token")
+                .header("X-Amz-Server-Side-Encryption-Customer-Algorithm", "AES256")
+                .header("X-Amz-Server-Side-Encryption-Customer-Key", "a2V5")
+                .header("X-Amz-Server-Side-Encryption-Customer-Key-MD5", "bWQ1")
+                .header(
+                    "X-Amz-Copy-Source-Server-Side-Encryption-Customer-Key",
+                    "a2V5",
+                )
+                .header(
+                    "X-Amz-Copy-Source-Server-Side-Encryption-Customer-Key-MD5",
+                    "bWQ1",
+                )
+                .header("Content-Length", "1100")
+                .header("x-amz-decoded-content-length", "1024")
+                .body(())
+                .unwrap();
+            let attrs = RequestAttrs::new_with(&req, true);
+            assert!(attrs.tls);
+            assert_eq!(attrs.method.as_deref(), Some("PUT"));
+            assert_eq!(attrs.user_agent.as_deref(), Some("aws-sdk-java/1.11.0"));
+            assert_eq!(attrs.header("X-AMZ-META-TAG"), Some("a, b"));
+            assert_eq!(attrs.header("authorization"), None);
+            assert_eq!(attrs.header("x-amz-security-token"), None);
+            assert_eq!(
+                attrs.header("x-amz-server-side-encryption-customer-algorithm"),
+                Some("AES256")
+            );
+            for name in [
+                "x-amz-server-side-encryption-customer-key",
+                "x-amz-server-side-encryption-customer-key-md5",
+                "x-amz-copy-source-server-side-encryption-customer-key",
+                "x-amz-copy-source-server-side-encryption-customer-key-md5",
+            ] {
+                assert_eq!(attrs.header(name), None);
+            }
+            // not an aws-chunked upload
+            assert_eq!(attrs.content_length, Some(1100));
+
+            let streaming = |name: &str, value: &str| {
+                let req = http::Request::put("/bucket/key")
+                    .header(name, value)
+                    .header("Content-Length", "1100")
+                    .header("x-amz-decoded-content-length", "1024")
+                    .body(())
+                    .unwrap();
+                RequestAttrs::new_with(&req, true).content_length
+            };
+            assert_eq!(
+                streaming("x-amz-content-sha256", "STREAMING-AWS4-HMAC-SHA256-PAYLOAD"),
+                Some(1024)
+            );
+            assert_eq!(streaming("content-encoding", "aws-chunked"), Some(1024));
+            assert_eq!(
+                streaming("x-amz-content-sha256", "UNSIGNED-PAYLOAD"),
+                Some(1100)
+            );
+
+            let req = http::Request::put("/bucket/key")
+                .header("x-amz-content-sha256", "STREAMING-AWS4-HMAC-SHA256-PAYLOAD")
+                .header("Content-Length", "1100")
+                .body(())
+                .unwrap();
+            assert_eq!(RequestAttrs::new_with(&req, true).content_length, None);
+        }
+    }
 }
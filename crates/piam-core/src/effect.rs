@@ -2,6 +2,8 @@ use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 
+use crate::principal::UserKind;
+
 #[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub enum Effect {
     /// If multiple effects hit with all Allow, the request should be allowed
@@ -72,5 +74,71 @@ pub struct RateLimit {
     pub count: u32,
 }
 
+impl RateLimit {
+    /// Applies to users none of whose policies set a `rate_limit`. People get a lower one than
+    /// services and the accounts shared by teams and companies.
+    pub fn default_for(user_kind: UserKind) -> Self {
+        let count = match user_kind {
+            UserKind::Service | UserKind::Team | UserKind::Company => 1000,
+            UserKind::Person | UserKind::Customer => 100,
+        };
+        Self {
+            duration: Duration::from_secs(1),
+            count,
+        }
+    }
+
+    /// `rate_limit` of the first Allow effect setting one, so `effects` should be in the order of
+    /// precedence of their policies, or the default of `user_kind`
+    pub fn find_or_default<'a>(
+        effects: impl IntoIterator<Item = &'a Effect>,
+        user_kind: UserKind,
+    ) -> Self {
+        effects
+            .into_iter()
+            .find_map(|effect| match effect {
+                Effect::Allow { rate_limit, .. } => rate_limit.clone(),
+                Effect::Deny(_) => None,
+            })
+            .unwrap_or_else(|| Self::default_for(user_kind))
+    }
+}
+
 #[derive(Clone, Debug, Default, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub struct Modify {}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        effect::{Effect, RateLimit},
+        principal::UserKind,
+    };
+
+    #[test]
+    fn rate_limit_by_user_kind() {
+        let person = RateLimit::default_for(UserKind::Person);
+        let service = RateLimit::default_for(UserKind::Service);
+        assert_eq!(person.duration, service.duration);
+        assert!(person.count < service.count);
+
+        let limited = |count: u32| Effect::Allow {
+            emit_event: None,
+            rate_limit: Some(RateLimit {
+                duration: Duration::from_secs(1),
+                count,
+            }),
+            modify: None,
+        };
+        let effects = [Effect::allow(), limited(10), limited(20)];
+        assert_eq!(
+            RateLimit::find_or_default(&effects, UserKind::Person).count,
+            10
+        );
+        assert_eq!(
+            RateLimit::find_or_default(&[Effect::allow()], UserKind::Person),
+            person
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Upstream endpoints the proxy forwards requests to, defined by region or by account.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{account::AccountId, type_alias::IamEntityIdType, IamIdentity};
+
+pub type EndpointId = IamEntityIdType;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    Http,
+    #[default]
+    Https,
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Http => write!(f, "http"),
+            Self::Https => write!(f, "https"),
+        }
+    }
+}
+
+/// An S3 compatible service such as AWS S3, Tencent COS, a private VPC endpoint or a MinIO/Ceph
+/// cluster.
+///
+/// An endpoint of an account takes precedence over the endpoints of the region, `region` of an
+/// endpoint of an account can be `ANY` to serve all regions of it.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Endpoint {
+    pub id: EndpointId,
+    pub region: String,
+    #[serde(default)]
+    pub account_id: Option<AccountId>,
+    #[serde(default)]
+    pub scheme: Scheme,
+    /// With the port if not the default one of the scheme, e.g. `minio.internal:9000`
+    pub host: String,
+    /// Buckets are addressed as the first segment of the path instead of a subdomain of the host,
+    /// as most self-hosted services require
+    #[serde(default)]
+    pub path_style: bool,
+    /// PEM of the CA certificates to verify the endpoint with instead of the system ones
+    #[serde(default)]
+    pub ca_pem: Option<String>,
+}
+
+impl IamIdentity for Endpoint {
+    fn id_str(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Endpoint {
+    /// An endpoint of the region for all accounts
+    pub fn new(id: &str, region: &str, scheme: Scheme, host: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            region: region.to_string(),
+            scheme,
+            host: host.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// e.g. `https://s3.us-east-1.amazonaws.com`
+    pub fn base_url(&self) -> String {
+        format!("{}://{}", self.scheme, self.host)
+    }
+
+    /// Url of `path_and_query` in `bucket`, `path_and_query` does not contain the bucket and
+    /// starts with `/`. Buckets with dots are addressed path-style over HTTPS, as a subdomain of
+    /// several labels is not covered by the wildcard certificate of the host.
+    pub fn url(&self, bucket: Option<&str>, path_and_query: &str) -> String {
+        match bucket {
+            None => format!("{}{}", self.base_url(), path_and_query),
+            Some(bucket)
+                if self.path_style || (self.scheme == Scheme::Https && bucket.contains('.')) =>
+            {
+                format!("{}/{}{}", self.base_url(), bucket, path_and_query)
+            }
+            Some(bucket) => format!(
+                "{}://{}.{}{}",
+                self.scheme, bucket, self.host, path_and_query
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::endpoint::{Endpoint, Scheme};
+
+    #[test]
+    fn url() {
+        let mut endpoint = Endpoint::new("e1", "us-east-1", Scheme::Https, "s3.amazonaws.com");
+        assert_eq!(endpoint.url(None, "/"), "https://s3.amazonaws.com/");
+        assert_eq!(
+            endpoint.url(Some("bucket1"), "/a/b?x=1"),
+            "https://bucket1.s3.amazonaws.com/a/b?x=1"
+        );
+        assert_eq!(
+            endpoint.url(Some("bucket.1"), "/a"),
+            "https://s3.amazonaws.com/bucket.1/a"
+        );
+        endpoint.scheme = Scheme::Http;
+        assert_eq!(
+            endpoint.url(Some("bucket.1"), "/a"),
+            "http://bucket.1.s3.amazonaws.com/a"
+        );
+        endpoint.path_style = true;
+        assert_eq!(
+            endpoint.url(Some("bucket1"), "/a/b?x=1"),
+            "http://s3.amazonaws.com/bucket1/a/b?x=1"
+        );
+
+        let parsed: Endpoint = serde_yaml::from_str(
+            "{id: minio, region: local, host: 'minio:9000', path_style: true}",
+        )
+        .unwrap();
+        assert_eq!(parsed.scheme, Scheme::Https);
+        assert_eq!(parsed.account_id, None);
+        assert_eq!(parsed.base_url(), "https://minio:9000");
+    }
+}
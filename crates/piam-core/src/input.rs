@@ -14,6 +14,10 @@ impl<T> InputAndRequest<T> {
         Self { input, request }
     }
 
+    pub const fn input(&self) -> &T {
+        &self.input
+    }
+
     pub fn into_parts(self) -> (T, HttpRequest) {
         (self.input, self.request)
     }
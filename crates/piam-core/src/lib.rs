@@ -7,9 +7,11 @@ pub mod condition;
 pub mod config;
 pub mod crypto;
 pub mod effect;
+pub mod endpoint;
 pub mod error;
 pub mod group;
 pub mod input;
+pub mod lint;
 pub mod manager_api_constant;
 pub mod policy;
 pub mod principal;
@@ -0,0 +1,425 @@
+//! Static analysis of policies and relationships.
+//!
+//! Problems that would otherwise only show up when a request happens to hit them are reported
+//! when config is loaded, each one with the location of the offending item, e.g.
+//! `policies/ObjectStorage[policy1].modeled_policy[modeled1].input_policy.keys[1].path`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use busylib::ANY;
+
+use crate::{
+    account::aws::AwsAccount,
+    endpoint::Endpoint,
+    relation_model::{
+        GroupParentRelationship, PolicyRelationship, UserGroupRelationship, UserRoleRelationship,
+    },
+    IamIdentity,
+};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LintKind {
+    EmptyMatcher,
+    DuplicateId,
+    UnknownAction,
+    OverlappingPath,
+    DanglingRelationship,
+    UnknownPolicyModel,
+    UnreachableRule,
+    /// Rules that fail every request evaluating them, e.g. see `StringMatcher::check_conflict`
+    ConflictingRule,
+    GroupCycle,
+    AmbiguousEndpoint,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Lint {
+    pub kind: LintKind,
+    pub location: String,
+    pub message: String,
+}
+
+impl Lint {
+    pub fn new(kind: LintKind, location: &str, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            location: location.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} at {}: {}", self.kind, self.location, self.message)
+    }
+}
+
+/// Ids of entities that relationships are allowed to point at
+#[derive(Debug, Default)]
+pub struct KnownIds<'a> {
+    pub accounts: HashSet<&'a str>,
+    pub users: HashSet<&'a str>,
+    pub groups: HashSet<&'a str>,
+    pub roles: HashSet<&'a str>,
+    /// Policy ids by policy model
+    pub policies: HashMap<&'a str, HashSet<&'a str>>,
+}
+
+pub fn lint_duplicate_ids<T: IamIdentity>(items: &[T], location: &str) -> Vec<Lint> {
+    let mut seen = HashSet::new();
+    items
+        .iter()
+        .filter(|item| !seen.insert(item.id_str()))
+        .map(|item| {
+            Lint::new(
+                LintKind::DuplicateId,
+                &format!("{}[{}]", location, item.id_str()),
+                format!("id '{}' is used more than once", item.id_str()),
+            )
+        })
+        .collect()
+}
+
+/// Accounts are found by `code` in requests, so a duplicate one hides the other account
+pub fn lint_account_codes(accounts: &[AwsAccount], location: &str) -> Vec<Lint> {
+    let mut seen = HashMap::new();
+    accounts
+        .iter()
+        .filter_map(|account| {
+            let first = seen.insert(account.code.as_str(), &account.id)?;
+            Some(Lint::new(
+                LintKind::DuplicateId,
+                &format!("{}[{}]", location, account.id),
+                format!(
+                    "code '{}' is also used by account '{}'",
+                    account.code, first
+                ),
+            ))
+        })
+        .collect()
+}
+
+pub fn lint_user_group_relationships(
+    relationships: &[UserGroupRelationship],
+    known: &KnownIds,
+    location: &str,
+) -> Vec<Lint> {
+    let mut lints = lint_duplicate_ids(relationships, location);
+    for rel in relationships {
+        let location = format!("{}[{}]", location, rel.id);
+        dangling(&mut lints, &location, "user", &rel.user_id, &known.users);
+        dangling(&mut lints, &location, "group", &rel.group_id, &known.groups);
+    }
+    lints
+}
+
+/// Dangling groups and cycles, a group being its own ancestor
+pub fn lint_group_parent_relationships(
+    relationships: &[GroupParentRelationship],
+    known: &KnownIds,
+    location: &str,
+) -> Vec<Lint> {
+    let mut lints = lint_duplicate_ids(relationships, location);
+    let mut parents: HashMap<&str, Vec<&str>> = HashMap::new();
+    for rel in relationships {
+        let rel_location = format!("{}[{}]", location, rel.id);
+        dangling(
+            &mut lints,
+            &rel_location,
+            "group",
+            &rel.group_id,
+            &known.groups,
+        );
+        dangling(
+            &mut lints,
+            &rel_location,
+            "parent group",
+            &rel.parent_group_id,
+            &known.groups,
+        );
+        parents
+            .entry(&rel.group_id)
+            .or_default()
+            .push(&rel.parent_group_id);
+    }
+
+    let mut reported = HashSet::new();
+    for rel in relationships {
+        if reported.contains(rel.group_id.as_str()) {
+            continue;
+        }
+        if let Some(cycle) = find_cycle(&rel.group_id, &parents) {
+            reported.extend(cycle.iter().copied());
+            lints.push(Lint::new(
+                LintKind::GroupCycle,
+                &format!("{}[{}]", location, rel.id),
+                format!("group cycle found: {}", cycle.join(" -> ")),
+            ));
+        }
+    }
+    lints
+}
+
+/// Depth first search for a path from the group back to itself
+fn find_cycle<'a>(
+    start: &'a str,
+    parents: &HashMap<&'a str, Vec<&'a str>>,
+) -> Option<Vec<&'a str>> {
+    let mut visited = HashSet::new();
+    let mut stack: Vec<Vec<&str>> = vec![vec![start]];
+    while let Some(path) = stack.pop() {
+        let last = *path.last()?;
+        for parent in parents.get(last).into_iter().flatten() {
+            if *parent == start {
+                let mut cycle = path.clone();
+                cycle.push(start);
+                return Some(cycle);
+            }
+            if visited.insert(*parent) {
+                let mut next = path.clone();
+                next.push(parent);
+                stack.push(next);
+            }
+        }
+    }
+    None
+}
+
+pub fn lint_user_role_relationships(
+    relationships: &[UserRoleRelationship],
+    known: &KnownIds,
+    location: &str,
+) -> Vec<Lint> {
+    let mut lints = lint_duplicate_ids(relationships, location);
+    for rel in relationships {
+        let location = format!("{}[{}]", location, rel.id);
+        dangling(&mut lints, &location, "user", &rel.user_id, &known.users);
+        dangling(&mut lints, &location, "role", &rel.role_id, &known.roles);
+    }
+    lints
+}
+
+pub fn lint_policy_relationships(
+    relationships: &[PolicyRelationship],
+    known: &KnownIds,
+    location: &str,
+) -> Vec<Lint> {
+    let mut lints = lint_duplicate_ids(relationships, location);
+    for rel in relationships {
+        let location = format!("{}[{}]", location, rel.id);
+        dangling(
+            &mut lints,
+            &location,
+            "account",
+            &rel.account_id,
+            &known.accounts,
+        );
+        if let Some(user_id) = &rel.user_id {
+            dangling(&mut lints, &location, "user", user_id, &known.users);
+        }
+        if let Some(group_id) = &rel.group_id {
+            dangling(&mut lints, &location, "group", group_id, &known.groups);
+        }
+        if let Some(role_id) = &rel.role_id {
+            dangling(&mut lints, &location, "role", role_id, &known.roles);
+        }
+        match known.policies.get(rel.policy_model.as_str()) {
+            // e.g. of another kind of proxy sharing the config, so only a warning
+            None => lints.push(Lint::new(
+                LintKind::UnknownPolicyModel,
+                &location,
+                format!(
+                    "policy model '{}' is not loaded, the relationship is ignored",
+                    rel.policy_model
+                ),
+            )),
+            Some(ids) => {
+                let what = format!("{} policy", rel.policy_model);
+                dangling(&mut lints, &location, &what, &rel.policy_id, ids);
+            }
+        }
+    }
+    lints
+}
+
+/// Dangling accounts, and endpoints of the same account and region of which only one is used
+pub fn lint_endpoints(endpoints: &[Endpoint], known: &KnownIds, location: &str) -> Vec<Lint> {
+    let mut lints = lint_duplicate_ids(endpoints, location);
+    let mut seen = HashMap::new();
+    for endpoint in endpoints {
+        let endpoint_location = format!("{}[{}]", location, endpoint.id);
+        if let Some(account_id) = &endpoint.account_id {
+            dangling(
+                &mut lints,
+                &endpoint_location,
+                "account",
+                account_id,
+                &known.accounts,
+            );
+        }
+        let scope = (endpoint.account_id.as_deref(), endpoint.region.as_str());
+        match seen.get(&scope) {
+            Some(first) => lints.push(Lint::new(
+                LintKind::AmbiguousEndpoint,
+                &endpoint_location,
+                format!("endpoint '{}' serves the same account and region", first),
+            )),
+            None => {
+                seen.insert(scope, &endpoint.id);
+            }
+        }
+    }
+    lints
+}
+
+fn dangling(lints: &mut Vec<Lint>, location: &str, what: &str, id: &str, ids: &HashSet<&str>) {
+    if id != ANY && !ids.contains(id) {
+        lints.push(Lint::new(
+            LintKind::DanglingRelationship,
+            location,
+            format!("{} '{}' not found", what, id),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, HashSet};
+
+    use busylib::ANY;
+
+    use crate::{
+        account::aws::AwsAccount,
+        endpoint::{Endpoint, Scheme},
+        lint::{
+            lint_account_codes, lint_endpoints, lint_policy_relationships, KnownIds, Lint, LintKind,
+        },
+        relation_model::PolicyRelationship,
+    };
+
+    #[test]
+    fn dangling_policy_relationships() {
+        let known = KnownIds {
+            accounts: HashSet::from(["account1"]),
+            groups: HashSet::from(["group1"]),
+            policies: HashMap::from([("ObjectStorage", HashSet::from(["policy1"]))]),
+            ..Default::default()
+        };
+        let relationships = vec![
+            PolicyRelationship {
+                id: "rel1".to_string(),
+                policy_model: "ObjectStorage".to_string(),
+                group_id: Some("group1".to_string()),
+                account_id: ANY.to_string(),
+                region: ANY.to_string(),
+                policy_id: "policy1".to_string(),
+                ..Default::default()
+            },
+            PolicyRelationship {
+                id: "rel2".to_string(),
+                policy_model: "ObjectStorage".to_string(),
+                group_id: Some("group2".to_string()),
+                account_id: "account1".to_string(),
+                region: ANY.to_string(),
+                policy_id: "policy2".to_string(),
+                ..Default::default()
+            },
+            PolicyRelationship {
+                id: "rel2".to_string(),
+                policy_model: "Condition".to_string(),
+                account_id: "account1".to_string(),
+                region: ANY.to_string(),
+                policy_id: "policy1".to_string(),
+                ..Default::default()
+            },
+        ];
+
+        let lints = lint_policy_relationships(&relationships, &known, "policy_relationships");
+        assert_eq!(
+            lints,
+            vec![
+                Lint::new(
+                    LintKind::DuplicateId,
+                    "policy_relationships[rel2]",
+                    "id 'rel2' is used more than once"
+                ),
+                Lint::new(
+                    LintKind::DanglingRelationship,
+                    "policy_relationships[rel2]",
+                    "group 'group2' not found"
+                ),
+                Lint::new(
+                    LintKind::DanglingRelationship,
+                    "policy_relationships[rel2]",
+                    "ObjectStorage policy 'policy2' not found"
+                ),
+                Lint::new(
+                    LintKind::UnknownPolicyModel,
+                    "policy_relationships[rel2]",
+                    "policy model 'Condition' is not loaded, the relationship is ignored"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_account_codes() {
+        let account = |id: &str, code: &str| AwsAccount {
+            id: id.to_string(),
+            code: code.to_string(),
+            ..Default::default()
+        };
+        let accounts = vec![
+            account("account1", "0001"),
+            account("account2", "0002"),
+            account("account3", "0001"),
+        ];
+        assert_eq!(
+            lint_account_codes(&accounts, "accounts"),
+            vec![Lint::new(
+                LintKind::DuplicateId,
+                "accounts[account3]",
+                "code '0001' is also used by account 'account1'"
+            )]
+        );
+    }
+
+    #[test]
+    fn ambiguous_endpoints() {
+        let known = KnownIds {
+            accounts: HashSet::from(["account1"]),
+            ..Default::default()
+        };
+        let endpoint = |id: &str, account_id: Option<&str>| Endpoint {
+            account_id: account_id.map(String::from),
+            ..Endpoint::new(id, "us-east-1", Scheme::Https, "s3.us-east-1.amazonaws.com")
+        };
+        let endpoints = vec![
+            endpoint("e1", None),
+            endpoint("e2", Some("account1")),
+            endpoint("e3", None),
+            endpoint("e4", Some("account2")),
+        ];
+
+        let lints = lint_endpoints(&endpoints, &known, "endpoints");
+        assert_eq!(
+            lints,
+            vec![
+                Lint::new(
+                    LintKind::AmbiguousEndpoint,
+                    "endpoints[e3]",
+                    "endpoint 'e1' serves the same account and region"
+                ),
+                Lint::new(
+                    LintKind::DanglingRelationship,
+                    "endpoints[e4]",
+                    "account 'account2' not found"
+                ),
+            ]
+        );
+    }
+}
@@ -6,15 +6,29 @@ pub const USERS: &str = "users";
 
 pub const GROUPS: &str = "groups";
 
+pub const ROLES: &str = "roles";
+
 pub const POLICIES: &str = "policies";
 
 pub const CONDITION: &str = "Condition";
 
 pub const USER_GROUP_RELATIONSHIPS: &str = "user_group_relationships";
 
+pub const GROUP_PARENT_RELATIONSHIPS: &str = "group_parent_relationships";
+
+pub const USER_ROLE_RELATIONSHIPS: &str = "user_role_relationships";
+
 pub const POLICY_RELATIONSHIPS: &str = "policy_relationships";
 
+pub const ENDPOINTS: &str = "endpoints";
+
 pub const EXTENDED_CONFIG: &str = "extended_config";
+
+/// Bumped on every write, served as the ETag of the whole config
+pub const REVISION: &str = "revision";
+
+/// All the config a proxy needs, read at once
+pub const SNAPSHOT: &str = "snapshot";
 /// for manager use only
 pub const CONFIG_TYPE: &str = "config_type";
 
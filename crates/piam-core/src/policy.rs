@@ -8,6 +8,7 @@ use crate::{
     effect::Effect,
     error::{PiamError, PiamResult},
     input::Input,
+    lint::{lint_duplicate_ids, Lint, LintKind},
     type_alias::IamEntityIdType,
     IamIdentity,
 };
@@ -34,6 +35,11 @@ pub trait Modeled: Debug {
     fn id(&self) -> String;
 
     fn find_effect_by_input(&self, input: &Self::Input) -> PiamResult<Option<&Effect>>;
+
+    /// Static analysis of the modeled policy, `location` is the path to it in the config
+    fn lint(&self, _location: &str) -> Vec<Lint> {
+        Vec::new()
+    }
 }
 
 impl<P: Modeled> IamIdentity for Policy<P> {
@@ -42,19 +48,50 @@ impl<P: Modeled> IamIdentity for Policy<P> {
     }
 }
 
+impl<P: Modeled> Policy<P> {
+    pub fn lint(&self, location: &str) -> Vec<Lint> {
+        let location = format!("{}[{}]", location, self.id);
+        let mut lints = Vec::new();
+        let mut seen = HashSet::new();
+        for modeled in &self.modeled_policy {
+            let location = format!("{}.modeled_policy[{}]", location, modeled.id());
+            if !seen.insert(modeled.id()) {
+                lints.push(Lint::new(
+                    LintKind::DuplicateId,
+                    &location,
+                    format!("id '{}' is used more than once", modeled.id()),
+                ));
+            }
+            lints.extend(modeled.lint(&location));
+        }
+        lints
+    }
+
+    pub fn lint_all(policies: &[Self], location: &str) -> Vec<Lint> {
+        let mut lints = lint_duplicate_ids(policies, location);
+        for policy in policies {
+            lints.extend(policy.lint(location));
+        }
+        lints
+    }
+}
+
 impl<P, I> Policy<P>
 where
     P: Modeled<Input = I> + DeserializeOwned,
     I: Input,
 {
+    /// Effects in the order of the modeled policies found them, each one only once
     pub fn find_effects(&self, input: &I) -> PiamResult<Vec<&Effect>> {
-        let mut effects = HashSet::new();
+        let mut effects: Vec<&Effect> = Vec::new();
         for modeled in &self.modeled_policy {
             if let Some(effect) = modeled.find_effect_by_input(input)? {
-                effects.insert(effect);
+                if !effects.contains(&effect) {
+                    effects.push(effect);
+                }
             }
         }
-        Ok(effects.into_iter().collect())
+        Ok(effects)
     }
 }
 
@@ -73,9 +110,6 @@ pub struct StringMatcher {
 
 impl StringMatcher {
     pub fn matches(&self, value: &str) -> bool {
-        // TODO: static analyze
-        // should have at least one of eq or start_with
-        // should not conflict
         if let Some(eq) = &self.eq {
             if eq.contains(&value.to_string()) {
                 return true;
@@ -135,6 +169,55 @@ impl StringMatcher {
         None
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.eq_values().next().is_none() && self.start_with_values().next().is_none()
+    }
+
+    /// A matcher should have at least one of `eq` or `start_with`
+    pub fn lint(&self, location: &str) -> Vec<Lint> {
+        match self.is_empty() {
+            true => vec![Lint::new(
+                LintKind::EmptyMatcher,
+                location,
+                "matcher has neither eq nor start_with, it matches nothing",
+            )],
+            false => Vec::new(),
+        }
+    }
+
+    /// Returns true if every value matched by `other` is also matched by `self`
+    pub fn covers(&self, other: &Self) -> bool {
+        let eq_covered = other.eq_values().all(|v| self.matches(v));
+        let start_with_covered = other
+            .start_with_values()
+            .all(|p| self.start_with_values().any(|q| p.starts_with(q)));
+        eq_covered && start_with_covered
+    }
+
+    /// Returns the first value or prefix that can be matched by both matchers
+    pub fn overlap_with(&self, other: &Self) -> Option<String> {
+        let eq = self
+            .eq_values()
+            .find(|v| other.matches(v))
+            .or_else(|| other.eq_values().find(|v| self.matches(v)));
+        let start_with = || {
+            self.start_with_values().find(|p| {
+                other
+                    .start_with_values()
+                    .any(|q| p.starts_with(q) || q.starts_with(p.as_str()))
+            })
+        };
+        eq.or_else(start_with).cloned()
+    }
+
+    fn eq_values(&self) -> impl Iterator<Item = &String> {
+        self.eq.iter().flatten()
+    }
+
+    fn start_with_values(&self) -> impl Iterator<Item = &String> {
+        self.start_with.iter().flatten()
+    }
+
     fn get_first_same(a: &[String], b: &[String]) -> Option<String> {
         let set_a: HashSet<&String> = a.iter().collect();
         let set_b: HashSet<&String> = b.iter().collect();
@@ -228,21 +311,54 @@ mod test {
             Ok(()),
         );
     }
+
+    #[test]
+    fn covers_and_overlap_with() {
+        use super::StringMatcher;
+
+        let matcher = |eq: &[&str], start_with: &[&str]| StringMatcher {
+            eq: Some(eq.iter().map(|s| s.to_string()).collect()),
+            start_with: Some(start_with.iter().map(|s| s.to_string()).collect()),
+        };
+
+        let broad = matcher(&[], &["a/"]);
+        let narrow = matcher(&["a/b"], &["a/c/"]);
+        let other = matcher(&["b/a"], &["b/"]);
+        assert!(broad.covers(&narrow));
+        assert!(!narrow.covers(&broad));
+        assert!(!broad.covers(&other));
+
+        assert_eq!(broad.overlap_with(&narrow), Some("a/b".to_string()));
+        assert_eq!(narrow.overlap_with(&broad), Some("a/b".to_string()));
+        assert_eq!(
+            matcher(&[], &["a/c/d"]).overlap_with(&narrow),
+            Some("a/c/d".to_string())
+        );
+        assert_eq!(broad.overlap_with(&other), None);
+
+        assert!(matcher(&[], &[]).is_empty());
+        assert!(StringMatcher::default().is_empty());
+        assert!(!broad.is_empty());
+    }
 }
 
 pub mod condition {
-    use std::net::Ipv4Addr;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
     use busylib::prelude::EnhancedUnwrap;
-    use cidr::{AnyIpCidr, Ipv4Cidr};
+    use chrono::{DateTime, Datelike, NaiveTime, Utc, Weekday};
+    use chrono_tz::Tz;
+    use cidr::{AnyIpCidr, Ipv4Cidr, Ipv6Cidr};
     use serde::{Deserialize, Serialize};
 
     use crate::{
-        condition::input::{Condition, ConditionCtx},
+        condition::input::{is_kept_header, Condition, ConditionCtx, RequestAttrs},
         effect::Effect,
         error::PiamResult,
         group::GroupId,
-        policy::Modeled,
+        lint::{Lint, LintKind},
+        policy::{Modeled, StringMatcher},
+        principal::UserKind,
     };
 
     /// If ConditionPolicy is not specified, this phase of effect finding should be skipped.
@@ -259,9 +375,16 @@ pub mod condition {
     #[derive(Clone, Debug, Default, Serialize, Deserialize)]
     pub struct ConditionRange {
         pub group_ids: Option<Vec<GroupId>>,
+        /// e.g. deny CreateBucket for `Customer`, or rate limit `Person` more than `Service`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub user_kinds: Option<Vec<UserKind>>,
         pub from: Option<Range>,
         pub proxy: Option<Range>,
         pub to: Option<Range>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub time: Option<TimeRange>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub request: Option<RequestRange>,
     }
 
     #[derive(Clone, Debug, Default, Serialize, Deserialize)]
@@ -271,9 +394,58 @@ pub mod condition {
         pub env: Option<Vec<String>>,
     }
 
+    /// Matches if the evaluation time is within all of the specified windows.
+    /// `hours` and `weekdays` are local to `timezone`, which is UTC if not specified.
+    #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+    pub struct TimeRange {
+        pub timezone: Option<Tz>,
+        pub hours: Option<Vec<HoursWindow>>,
+        pub weekdays: Option<Vec<Weekday>>,
+        pub not_before: Option<DateTime<Utc>>,
+        pub not_after: Option<DateTime<Utc>>,
+    }
+
+    /// Matches if the incoming request satisfies all of the specified attributes
+    #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+    pub struct RequestRange {
+        pub tls: Option<bool>,
+        /// Compared case-insensitively, e.g. `[GET, HEAD]`
+        pub methods: Option<Vec<String>>,
+        /// A request without `User-Agent` does not match
+        pub user_agent: Option<StringMatcher>,
+        /// Every header in the list must match, credentials such as `Authorization` can not be
+        /// matched
+        pub headers: Option<Vec<HeaderMatcher>>,
+        /// A request of unknown size does not match an Allow policy but matches a Deny one,
+        /// so that leaving out `Content-Length` gains nothing
+        pub content_length: Option<SizeRange>,
+    }
+
+    /// Matches if the header is present and, if `value` is specified, its value matches
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct HeaderMatcher {
+        pub name: String,
+        pub value: Option<StringMatcher>,
+    }
+
+    /// Both bounds are inclusive
+    #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+    pub struct SizeRange {
+        pub min: Option<u64>,
+        pub max: Option<u64>,
+    }
+
+    /// `start` is inclusive and `end` is exclusive, a window with `end` before `start`
+    /// spans midnight, e.g. 22:00 to 06:00
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct HoursWindow {
+        pub start: NaiveTime,
+        pub end: NaiveTime,
+    }
+
     impl ConditionPolicy {
         pub fn find_effect(&self, condition_ctx: &ConditionCtx) -> Option<&Effect> {
-            match self.range.matches(condition_ctx) {
+            match self.range.matches_for(condition_ctx, &self.effect) {
                 false => None,
                 true => Some(&self.effect),
             }
@@ -292,15 +464,56 @@ pub mod condition {
         }
 
         fn find_effect_by_input(&self, condition_ctx: &Self::Input) -> PiamResult<Option<&Effect>> {
-            Ok(match self.range.matches(condition_ctx) {
+            Ok(match self.range.matches_for(condition_ctx, &self.effect) {
                 false => None,
                 true => Some(&self.effect),
             })
         }
+
+        fn lint(&self, location: &str) -> Vec<Lint> {
+            let ranges = [
+                ("from", &self.range.from),
+                ("proxy", &self.range.proxy),
+                ("to", &self.range.to),
+            ];
+            let mut lints: Vec<Lint> = ranges
+                .into_iter()
+                .filter_map(|(name, range)| range.as_ref().map(|r| (name, r)))
+                .flat_map(|(name, range)| range.lint(&format!("{}.range.{}", location, name)))
+                .collect();
+            if matches!(&self.range.user_kinds, Some(vec) if vec.is_empty()) {
+                lints.push(Lint::new(
+                    LintKind::UnreachableRule,
+                    &format!("{}.range.user_kinds", location),
+                    "empty list never matches",
+                ));
+            }
+            if let Some(time) = &self.range.time {
+                lints.extend(time.lint(&format!("{}.range.time", location)));
+            }
+            if let Some(request) = &self.range.request {
+                lints.extend(request.lint(&format!("{}.range.request", location)));
+            }
+            lints
+        }
     }
 
     impl ConditionRange {
+        /// Matches the way an Allow policy does
         pub fn matches(&self, condition_ctx: &ConditionCtx) -> bool {
+            self.matches_for(condition_ctx, &Effect::allow())
+        }
+
+        /// Only an unknown content length depends on `effect`, see
+        /// [`RequestRange::matches_for`]. An unknown user kind never matches whatever the effect.
+        pub fn matches_for(&self, condition_ctx: &ConditionCtx, effect: &Effect) -> bool {
+            let user_kind_matched = match &self.user_kinds {
+                None => true,
+                Some(vec) => match &condition_ctx.user_kind {
+                    None => false,
+                    Some(kind) => vec.contains(kind),
+                },
+            };
             let from_matched = match &self.from {
                 None => true,
                 Some(range) => range.matches(&condition_ctx.from),
@@ -313,7 +526,185 @@ pub mod condition {
                 None => true,
                 Some(range) => range.matches(&condition_ctx.to),
             };
-            from_matched && proxy_matched && to_matched
+            let time_matched = match &self.time {
+                None => true,
+                Some(range) => range.matches(condition_ctx.time()),
+            };
+            let request_matched = match &self.request {
+                None => true,
+                Some(range) => range.matches_for(&condition_ctx.request, effect),
+            };
+            user_kind_matched
+                && from_matched
+                && proxy_matched
+                && to_matched
+                && time_matched
+                && request_matched
+        }
+    }
+
+    impl TimeRange {
+        pub fn matches(&self, time: DateTime<Utc>) -> bool {
+            let not_before_matched = match self.not_before {
+                None => true,
+                Some(not_before) => time >= not_before,
+            };
+            let not_after_matched = match self.not_after {
+                None => true,
+                Some(not_after) => time <= not_after,
+            };
+            let local = time.with_timezone(&self.timezone.unwrap_or(Tz::UTC));
+            let hours_matched = match &self.hours {
+                None => true,
+                Some(vec) => vec.iter().any(|window| window.contains(local.time())),
+            };
+            let weekdays_matched = match &self.weekdays {
+                None => true,
+                Some(vec) => vec.contains(&local.weekday()),
+            };
+            not_before_matched && not_after_matched && hours_matched && weekdays_matched
+        }
+
+        pub fn lint(&self, location: &str) -> Vec<Lint> {
+            let mut lints = Vec::new();
+            let mut unreachable = |field: &str, message: &str| {
+                lints.push(Lint::new(
+                    LintKind::UnreachableRule,
+                    &format!("{}.{}", location, field),
+                    message,
+                ))
+            };
+            if let (Some(not_before), Some(not_after)) = (self.not_before, self.not_after) {
+                if not_before > not_after {
+                    unreachable("not_before", "not_before is later than not_after");
+                }
+            }
+            if matches!(&self.hours, Some(vec) if vec.is_empty()) {
+                unreachable("hours", "empty list never matches");
+            }
+            if matches!(&self.weekdays, Some(vec) if vec.is_empty()) {
+                unreachable("weekdays", "empty list never matches");
+            }
+            lints
+        }
+    }
+
+    impl RequestRange {
+        /// Matches the way an Allow policy does
+        pub fn matches(&self, request: &RequestAttrs) -> bool {
+            self.matches_for(request, &Effect::allow())
+        }
+
+        /// An unknown content length matches only if `effect` is Deny. An unknown method or
+        /// `User-Agent`, or a missing header, never matches whatever the effect.
+        pub fn matches_for(&self, request: &RequestAttrs, effect: &Effect) -> bool {
+            let tls_matched = match self.tls {
+                None => true,
+                Some(tls) => tls == request.tls,
+            };
+            let method_matched = match &self.methods {
+                None => true,
+                Some(vec) => match &request.method {
+                    None => false,
+                    Some(method) => vec.iter().any(|m| m.eq_ignore_ascii_case(method)),
+                },
+            };
+            let user_agent_matched = match &self.user_agent {
+                None => true,
+                Some(matcher) => match &request.user_agent {
+                    None => false,
+                    Some(user_agent) => matcher.matches(user_agent),
+                },
+            };
+            let headers_matched = match &self.headers {
+                None => true,
+                Some(vec) => vec.iter().all(|h| h.matches(request)),
+            };
+            let content_length_matched = match &self.content_length {
+                None => true,
+                Some(range) => match request.content_length {
+                    None => effect.is_deny(),
+                    Some(len) => range.contains(len),
+                },
+            };
+            tls_matched
+                && method_matched
+                && user_agent_matched
+                && headers_matched
+                && content_length_matched
+        }
+
+        pub fn lint(&self, location: &str) -> Vec<Lint> {
+            let mut lints = Vec::new();
+            if matches!(&self.methods, Some(vec) if vec.is_empty()) {
+                lints.push(Lint::new(
+                    LintKind::UnreachableRule,
+                    &format!("{}.methods", location),
+                    "empty list never matches",
+                ));
+            }
+            if let Some(matcher) = &self.user_agent {
+                lints.extend(matcher.lint(&format!("{}.user_agent", location)));
+            }
+            for (i, header) in self.headers.iter().flatten().enumerate() {
+                if !is_kept_header(&header.name) {
+                    lints.push(Lint::new(
+                        LintKind::UnreachableRule,
+                        &format!("{}.headers[{}].name", location, i),
+                        format!("header '{}' is never kept for matching", header.name),
+                    ));
+                }
+                if let Some(matcher) = &header.value {
+                    lints.extend(matcher.lint(&format!("{}.headers[{}].value", location, i)));
+                }
+            }
+            if let Some(SizeRange {
+                min: Some(min),
+                max: Some(max),
+            }) = self.content_length
+            {
+                if min > max {
+                    lints.push(Lint::new(
+                        LintKind::UnreachableRule,
+                        &format!("{}.content_length", location),
+                        "min is greater than max",
+                    ));
+                }
+            }
+            lints
+        }
+    }
+
+    impl HeaderMatcher {
+        pub fn matches(&self, request: &RequestAttrs) -> bool {
+            match (request.header(&self.name), &self.value) {
+                (None, _) => false,
+                (Some(_), None) => true,
+                (Some(value), Some(matcher)) => matcher.matches(value),
+            }
+        }
+    }
+
+    impl SizeRange {
+        pub fn contains(&self, size: u64) -> bool {
+            let min_matched = match self.min {
+                None => true,
+                Some(min) => size >= min,
+            };
+            let max_matched = match self.max {
+                None => true,
+                Some(max) => size <= max,
+            };
+            min_matched && max_matched
+        }
+    }
+
+    impl HoursWindow {
+        pub fn contains(&self, time: NaiveTime) -> bool {
+            match self.start <= self.end {
+                true => self.start <= time && time < self.end,
+                false => self.start <= time || time < self.end,
+            }
         }
     }
 
@@ -323,7 +714,10 @@ pub mod condition {
                 None => true,
                 Some(vec) => match condition.addr {
                     None => false,
-                    Some(addr) => vec.iter().any(|cidr| cidr.contains(&addr.ip())),
+                    Some(addr) => {
+                        let ip = canonical_ip(addr.ip());
+                        vec.iter().any(|cidr| cidr.contains(&ip))
+                    }
                 },
             };
             let region_matched = match &self.region {
@@ -342,6 +736,26 @@ pub mod condition {
             };
             ip_cidr_matched && region_matched && env_matched
         }
+
+        /// An empty list can never be matched, so the policy never takes effect
+        pub fn lint(&self, location: &str) -> Vec<Lint> {
+            let empty = [
+                ("ip_cidr", self.ip_cidr.as_ref().map(Vec::is_empty)),
+                ("region", self.region.as_ref().map(Vec::is_empty)),
+                ("env", self.env.as_ref().map(Vec::is_empty)),
+            ];
+            empty
+                .into_iter()
+                .filter(|(_, is_empty)| *is_empty == Some(true))
+                .map(|(name, _)| {
+                    Lint::new(
+                        LintKind::UnreachableRule,
+                        &format!("{}.{}", location, name),
+                        "empty list never matches",
+                    )
+                })
+                .collect()
+        }
     }
 
     pub fn private_ip_cidr() -> Vec<AnyIpCidr> {
@@ -350,16 +764,164 @@ pub mod condition {
             AnyIpCidr::V4(Ipv4Cidr::new(Ipv4Addr::new(172, 16, 0, 0), 12).unwp()),
             AnyIpCidr::V4(Ipv4Cidr::new(Ipv4Addr::new(192, 168, 0, 0), 16).unwp()),
             AnyIpCidr::V4(Ipv4Cidr::new(Ipv4Addr::new(127, 0, 0, 1), 32).unwp()),
+            // unique local
+            AnyIpCidr::V6(Ipv6Cidr::new(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7).unwp()),
+            // link local
+            AnyIpCidr::V6(Ipv6Cidr::new(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), 10).unwp()),
+            AnyIpCidr::V6(Ipv6Cidr::new(Ipv6Addr::LOCALHOST, 128).unwp()),
         ]
     }
 
+    /// IPv4-mapped IPv6 addresses, as seen on dual-stack sockets, are converted to IPv4 so that
+    /// they match IPv4 cidrs
+    pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        }
+    }
+
     #[cfg(test)]
     mod test {
+        use chrono::{DateTime, Utc};
+
+        use crate::{
+            condition::input::{ConditionCtx, RequestAttrs},
+            effect::Effect,
+            lint::LintKind,
+            policy::condition::{ConditionRange, RequestRange, TimeRange},
+        };
+
         #[test]
         fn condition_range_contains() {
             let vec = &vec!["a".to_string(), "b".to_string()];
             let val = &"b".to_string();
             assert!(vec.contains(val))
         }
+
+        #[test]
+        fn time_range_matches() {
+            let range: TimeRange = serde_yaml::from_str(
+                r#"
+timezone: Asia/Shanghai
+hours:
+  - start: "09:00:00"
+    end: "18:00:00"
+weekdays: [Mon, Tue, Wed, Thu, Fri]
+not_after: 2023-12-31T16:00:00Z
+"#,
+            )
+            .unwrap();
+            let at = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+
+            // Friday 09:30 in Shanghai
+            assert!(range.matches(at("2023-06-02T01:30:00Z")));
+            // Friday 18:00 in Shanghai
+            assert!(!range.matches(at("2023-06-02T10:00:00Z")));
+            // Saturday 09:30 in Shanghai
+            assert!(!range.matches(at("2023-06-03T01:30:00Z")));
+            // Monday 09:30 in Shanghai, on 2024-01-01
+            assert!(!range.matches(at("2024-01-01T01:30:00Z")));
+
+            // weekdays are the ones of the timezone, not of UTC
+            let monday: TimeRange = serde_yaml::from_str(
+                r#"
+timezone: Asia/Shanghai
+weekdays: [Mon]
+"#,
+            )
+            .unwrap();
+            // Monday 07:30 in Shanghai, still Sunday in UTC
+            assert!(monday.matches(at("2023-06-04T23:30:00Z")));
+            // Monday 01:30 in Shanghai, still Sunday 17:30 in UTC
+            assert!(monday.matches(at("2023-06-04T17:30:00Z")));
+            // Tuesday 01:30 in Shanghai, still Monday 17:30 in UTC
+            assert!(!monday.matches(at("2023-06-05T17:30:00Z")));
+
+            let overnight: TimeRange = serde_yaml::from_str(
+                r#"
+hours:
+  - start: "22:00:00"
+    end: "06:00:00"
+"#,
+            )
+            .unwrap();
+            assert!(overnight.matches(at("2023-06-02T23:00:00Z")));
+            assert!(overnight.matches(at("2023-06-02T05:59:59Z")));
+            assert!(!overnight.matches(at("2023-06-02T06:00:00Z")));
+
+            let condition_range = ConditionRange {
+                time: Some(range),
+                ..Default::default()
+            };
+            let ctx = ConditionCtx::default().at(at("2023-06-02T01:30:00Z"));
+            assert!(condition_range.matches(&ctx));
+            let ctx = ConditionCtx::default().at(at("2023-06-03T01:30:00Z"));
+            assert!(!condition_range.matches(&ctx));
+        }
+
+        #[test]
+        fn request_range_matches() {
+            let range: RequestRange = serde_yaml::from_str(
+                r#"
+tls: true
+methods: [put, post]
+user_agent:
+  start_with: [aws-sdk-java/1.]
+headers:
+  - name: X-Amz-Server-Side-Encryption
+  - name: x-amz-acl
+    value:
+      eq: [private]
+content_length:
+  max: 1024
+"#,
+            )
+            .unwrap();
+            let req = http::Request::put("/bucket/key")
+                .header("user-agent", "aws-sdk-java/1.11.0")
+                .header("x-amz-server-side-encryption", "AES256")
+                .header("x-amz-acl", "private")
+                .header("content-length", "1024")
+                .body(())
+                .unwrap();
+            assert!(range.matches(&RequestAttrs::new_with(&req, true)));
+            assert!(!range.matches(&RequestAttrs::new_with(&req, false)));
+
+            let mut attrs = RequestAttrs::new_with(&req, true);
+            attrs.headers.remove("x-amz-server-side-encryption");
+            assert!(!range.matches(&attrs));
+
+            let mut attrs = RequestAttrs::new_with(&req, true);
+            attrs.content_length = None;
+            assert!(!range.matches(&attrs));
+            // unknown size can not escape a deny
+            assert!(range.matches_for(&attrs, &Effect::deny()));
+            attrs.content_length = Some(2048);
+            assert!(!range.matches_for(&attrs, &Effect::deny()));
+
+            let mut attrs = RequestAttrs::new_with(&req, true);
+            attrs.method = Some("GET".to_string());
+            assert!(!range.matches(&attrs));
+
+            let condition_range = ConditionRange {
+                request: Some(range),
+                ..Default::default()
+            };
+            let ctx = ConditionCtx::default().request(RequestAttrs::new_with(&req, true));
+            assert!(condition_range.matches(&ctx));
+            let range: RequestRange = serde_yaml::from_str(
+                r#"
+headers:
+  - name: Authorization
+  - name: x-amz-acl
+"#,
+            )
+            .unwrap();
+            let lints = range.lint("p");
+            assert_eq!(lints.len(), 1);
+            assert_eq!(lints[0].kind, LintKind::UnreachableRule);
+            assert_eq!(lints[0].location, "p.headers[0].name");
+        }
     }
 }
@@ -21,7 +21,7 @@ impl IamIdentity for User {
     }
 }
 
-#[derive(Clone, Debug, Default, Eq, Hash, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub enum UserKind {
     // SVCS
     Service,
@@ -36,10 +36,15 @@ pub enum UserKind {
     Customer,
 }
 
-#[derive(Debug, Default, Eq, Hash, PartialEq, Serialize, Deserialize)]
+/// A role is assumed by users through `UserRoleRelationship`s, the sessions get the policies
+/// related to the role instead of those of the user
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub struct Role {
     pub id: RoleId,
     pub name: String,
+    /// In seconds, 3600 if not specified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_session_duration: Option<u64>,
 }
 
 impl IamIdentity for Role {
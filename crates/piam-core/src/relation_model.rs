@@ -6,8 +6,9 @@ use crate::{
     account::AccountId,
     group::GroupId,
     policy::PolicyId,
-    principal::{RoleId, UserId},
+    principal::{RoleId, UserId, UserKind},
     type_alias::IamEntityIdType,
+    IamIdentity,
 };
 
 /// n to n
@@ -18,7 +19,43 @@ pub struct UserGroupRelationship {
     pub group_id: GroupId,
 }
 
-/// Policy IDs can be filtered by data_model, group_id, user_id, role_id, account_id and region
+impl IamIdentity for UserGroupRelationship {
+    fn id_str(&self) -> &str {
+        &self.id
+    }
+}
+
+/// n to n, members of the group are also members of the parent group, so policies of the
+/// parent group apply to all of its descendants
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct GroupParentRelationship {
+    pub id: IamEntityIdType,
+    pub group_id: GroupId,
+    pub parent_group_id: GroupId,
+}
+
+impl IamIdentity for GroupParentRelationship {
+    fn id_str(&self) -> &str {
+        &self.id
+    }
+}
+
+/// n to n, the user is trusted to assume the role
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UserRoleRelationship {
+    pub id: IamEntityIdType,
+    pub user_id: UserId,
+    pub role_id: RoleId,
+}
+
+impl IamIdentity for UserRoleRelationship {
+    fn id_str(&self) -> &str {
+        &self.id
+    }
+}
+
+/// Policy IDs can be filtered by data_model, group_id, user_id, role_id, user_kind, account_id
+/// and region
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct PolicyRelationship {
     pub id: IamEntityIdType,
@@ -27,7 +64,17 @@ pub struct PolicyRelationship {
     pub user_id: Option<UserId>,
     pub group_id: Option<GroupId>,
     pub role_id: Option<RoleId>,
+    /// Targets all users of the kind, without mirroring kinds in groups. Along with a user, group
+    /// or role, only the ones of the kind among them are targeted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_kind: Option<UserKind>,
     pub account_id: AccountId,
     pub region: String,
     pub policy_id: PolicyId,
 }
+
+impl IamIdentity for PolicyRelationship {
+    fn id_str(&self) -> &str {
+        &self.id
+    }
+}
@@ -0,0 +1,581 @@
+//! Authentication and authorization of the manager API.
+//!
+//! Proxies and operators send `Authorization: Bearer <token>`, where the token is signed by
+//! `MANAGER_AUTH_KEY` (see [`Token`]). What an identity can do is decided by piam's own policy
+//! model: a list of `Policy<ManagerPolicy>` matched against the action and the resource path.
+//! A leaked token is revoked by listing its id in `MANAGER_REVOKED_TOKENS`.
+
+use std::{
+    collections::HashSet,
+    fmt,
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::State,
+    http::{header::AUTHORIZATION, HeaderMap, Method, Request},
+    middleware::Next,
+    response::Response,
+};
+use busylib::{config::dev_mode, prelude::EnhancedExpect};
+use hmac::{Hmac, Mac};
+use log::{error, info};
+use piam_core::{
+    effect::Effect,
+    error::PiamResult,
+    input::Input,
+    lint::{Lint, LintKind},
+    policy::{Modeled, Policy, StringMatcher},
+};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::{
+    config::{MANAGER_AUTH_KEY, MANAGER_POLICY_PATH, MANAGER_REVOKED_TOKENS},
+    error::{ManagerError, ManagerResult},
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdentityKind {
+    /// A piam-proxy, fetching its config
+    Proxy,
+    /// A person or a tool managing the config
+    Operator,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Identity {
+    pub kind: IdentityKind,
+    pub id: String,
+}
+
+/// `{kind}.{id}.{token_id}.{expires_at}.{signature}`, the signature is the hex encoded
+/// HMAC-SHA256 of the rest of the token
+#[derive(Debug)]
+pub struct Token {
+    pub identity: Identity,
+    /// Unique among the tokens issued, to revoke one without changing the key
+    pub token_id: String,
+    /// Unix timestamp in seconds
+    pub expires_at: u64,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Read,
+    Write,
+}
+
+/// A call to the manager API. `resource` is the path without the version, like
+/// `users/user1` or `policies/Condition`
+#[derive(Debug)]
+pub struct ManagerInput {
+    pub identity: Identity,
+    pub action: Action,
+    pub resource: String,
+}
+
+impl Input for ManagerInput {}
+
+/// Every present field must match for the effect to apply
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManagerPolicy {
+    pub version: i32,
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kinds: Option<Vec<IdentityKind>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identities: Option<StringMatcher>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actions: Option<Vec<Action>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resources: Option<StringMatcher>,
+    pub effect: Effect,
+}
+
+pub struct Authorizer {
+    key: String,
+    policies: Vec<Policy<ManagerPolicy>>,
+    /// Ids of the tokens rejected even if signed by `key` and not expired
+    revoked: HashSet<String>,
+}
+
+impl fmt::Display for IdentityKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdentityKind::Proxy => write!(f, "proxy"),
+            IdentityKind::Operator => write!(f, "operator"),
+        }
+    }
+}
+
+impl FromStr for IdentityKind {
+    type Err = ManagerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "proxy" => Ok(IdentityKind::Proxy),
+            "operator" => Ok(IdentityKind::Operator),
+            _ => Err(ManagerError::BadRequest(format!(
+                "unknown identity kind: {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.kind, self.id)
+    }
+}
+
+impl Token {
+    /// A token of a new id
+    pub fn new(identity: Identity, expires_at: u64) -> Self {
+        Self {
+            identity,
+            token_id: Uuid::new_v4().simple().to_string(),
+            expires_at,
+        }
+    }
+
+    pub fn sign(&self, key: &str) -> String {
+        let payload = format!(
+            "{}.{}.{}.{}",
+            self.identity.kind, self.identity.id, self.token_id, self.expires_at
+        );
+        let signature = hex::encode(mac(key, &payload).finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    pub fn verify(token: &str, key: &str, now: u64) -> ManagerResult<Self> {
+        let invalid = |msg: &str| ManagerError::Unauthorized(format!("invalid token: {}", msg));
+        let (payload, signature) = token.rsplit_once('.').ok_or_else(|| invalid("malformed"))?;
+        let signature = hex::decode(signature).map_err(|_| invalid("malformed signature"))?;
+        mac(key, payload)
+            .verify_slice(&signature)
+            .map_err(|_| invalid("bad signature"))?;
+
+        // the id may contain dots, kind, token_id and expires_at do not
+        let (kind, rest) = payload
+            .split_once('.')
+            .ok_or_else(|| invalid("malformed"))?;
+        let (rest, expires_at) = rest.rsplit_once('.').ok_or_else(|| invalid("malformed"))?;
+        let (id, token_id) = rest.rsplit_once('.').ok_or_else(|| invalid("malformed"))?;
+        let kind = kind.parse().map_err(|_| invalid("unknown identity kind"))?;
+        let expires_at: u64 = expires_at
+            .parse()
+            .map_err(|_| invalid("malformed expiration"))?;
+        if expires_at <= now {
+            return Err(invalid("expired"));
+        }
+        Ok(Self {
+            identity: Identity {
+                kind,
+                id: id.to_string(),
+            },
+            token_id: token_id.to_string(),
+            expires_at,
+        })
+    }
+}
+
+impl Action {
+    pub fn from_method(method: &Method) -> Self {
+        match method == Method::GET || method == Method::HEAD {
+            true => Action::Read,
+            false => Action::Write,
+        }
+    }
+}
+
+impl Modeled for ManagerPolicy {
+    type Input = ManagerInput;
+
+    fn version(&self) -> i32 {
+        self.version
+    }
+
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn find_effect_by_input(&self, input: &Self::Input) -> PiamResult<Option<&Effect>> {
+        let matched = matches_any(&self.kinds, &input.identity.kind)
+            && matches_str(&self.identities, &input.identity.id)
+            && matches_any(&self.actions, &input.action)
+            && matches_str(&self.resources, &input.resource);
+        Ok(matched.then_some(&self.effect))
+    }
+
+    fn lint(&self, location: &str) -> Vec<Lint> {
+        let mut lints = Vec::new();
+        if let Some(identities) = &self.identities {
+            lints.extend(identities.lint(&format!("{}.identities", location)));
+        }
+        if let Some(resources) = &self.resources {
+            lints.extend(resources.lint(&format!("{}.resources", location)));
+        }
+        for (field, empty) in [
+            ("kinds", matches!(&self.kinds, Some(vec) if vec.is_empty())),
+            (
+                "actions",
+                matches!(&self.actions, Some(vec) if vec.is_empty()),
+            ),
+        ] {
+            if empty {
+                lints.push(Lint::new(
+                    LintKind::UnreachableRule,
+                    &format!("{}.{}", location, field),
+                    "empty list never matches",
+                ));
+            }
+        }
+        lints
+    }
+}
+
+impl Authorizer {
+    pub fn new(key: String, policies: Vec<Policy<ManagerPolicy>>) -> ManagerResult<Self> {
+        if key.is_empty() {
+            return Err(ManagerError::Internal(
+                "auth key must not be empty".to_string(),
+            ));
+        }
+        let lints = Policy::lint_all(&policies, "manager_policies");
+        if !lints.is_empty() {
+            return Err(ManagerError::Internal(format!(
+                "invalid manager policies: {:?}",
+                lints
+            )));
+        }
+        Ok(Self {
+            key,
+            policies,
+            revoked: HashSet::new(),
+        })
+    }
+
+    /// Rejects the tokens of these ids from now on
+    pub fn revoke(mut self, token_ids: impl IntoIterator<Item = String>) -> Self {
+        self.revoked.extend(token_ids);
+        self
+    }
+
+    /// [`None`] means the API is not authenticated, which is only allowed in dev mode, and then
+    /// only for reads, see [`read_only`]
+    pub fn from_env() -> ManagerResult<Option<Self>> {
+        let key = MANAGER_AUTH_KEY.load().to_string();
+        if key.is_empty() {
+            return match dev_mode() {
+                true => {
+                    error!(
+                        "MANAGER_AUTH_KEY is not set, the manager API is NOT AUTHENTICATED: \
+                        anyone can read the config, writes are rejected"
+                    );
+                    Ok(None)
+                }
+                false => Err(ManagerError::Internal(
+                    "MANAGER_AUTH_KEY is required".to_string(),
+                )),
+            };
+        }
+        let path = MANAGER_POLICY_PATH.load();
+        let policies = match path.is_empty() {
+            true => Self::default_policies(),
+            false => {
+                let yaml = std::fs::read_to_string(path.as_str()).map_err(|e| {
+                    ManagerError::Internal(format!("failed to read {}: {}", path, e))
+                })?;
+                serde_yaml::from_str(&yaml).map_err(|e| {
+                    ManagerError::Internal(format!("failed to parse {}: {}", path, e))
+                })?
+            }
+        };
+        let revoked = MANAGER_REVOKED_TOKENS
+            .load()
+            .split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        if !revoked.is_empty() {
+            info!("{} manager tokens revoked", revoked.len());
+        }
+        Ok(Some(Self::new(key, policies)?.revoke(revoked)))
+    }
+
+    /// Proxies can read everything, operators can read and write everything
+    pub fn default_policies() -> Vec<Policy<ManagerPolicy>> {
+        let policy = |kind, actions| ManagerPolicy {
+            version: 1,
+            id: format!("{}_default", kind),
+            kinds: Some(vec![kind]),
+            identities: None,
+            actions: Some(actions),
+            resources: None,
+            effect: Effect::allow(),
+        };
+        vec![Policy {
+            kind: "Manager".to_string(),
+            version: 1,
+            id: "default".to_string(),
+            name: "default".to_string(),
+            modeled_policy: vec![
+                policy(IdentityKind::Proxy, vec![Action::Read]),
+                policy(IdentityKind::Operator, vec![Action::Read, Action::Write]),
+            ],
+        }]
+    }
+
+    pub fn authenticate(&self, headers: &HeaderMap, now: u64) -> ManagerResult<Identity> {
+        let token = headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or_else(|| ManagerError::Unauthorized("missing bearer token".to_string()))?;
+        let token = Token::verify(token.trim(), &self.key, now)?;
+        if self.revoked.contains(&token.token_id) {
+            return Err(ManagerError::Unauthorized(format!(
+                "token {} of {} is revoked",
+                token.token_id, token.identity
+            )));
+        }
+        Ok(token.identity)
+    }
+
+    /// Any deny wins, at least one allow is needed
+    pub fn authorize(&self, input: &ManagerInput) -> ManagerResult<()> {
+        let mut allowed = false;
+        for policy in &self.policies {
+            let effects = policy
+                .find_effects(input)
+                .map_err(|e| ManagerError::Internal(e.to_string()))?;
+            if effects.iter().any(|effect| effect.is_deny()) {
+                return Err(forbidden(input, "denied by policy"));
+            }
+            allowed |= !effects.is_empty();
+        }
+        match allowed {
+            true => Ok(()),
+            false => Err(forbidden(input, "no policy allows it")),
+        }
+    }
+}
+
+/// Middleware authenticating and authorizing every request of the routes it wraps
+pub async fn auth<B>(
+    State(authorizer): State<Arc<Authorizer>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> ManagerResult<Response> {
+    let identity = authorizer.authenticate(req.headers(), now())?;
+    let input = ManagerInput {
+        identity,
+        action: Action::from_method(req.method()),
+        resource: resource_of(req.uri().path()),
+    };
+    authorizer.authorize(&input)?;
+    if input.action == Action::Write {
+        info!("{} {}", input.identity, req.uri().path());
+    }
+    Ok(next.run(req).await)
+}
+
+/// Middleware of the API when it is not authenticated, in dev mode only: writes are rejected, so
+/// that the config can not be changed by anyone who can reach the manager
+pub async fn read_only<B>(req: Request<B>, next: Next<B>) -> ManagerResult<Response> {
+    check_unauthenticated(req.method())?;
+    Ok(next.run(req).await)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ex("system time should be after unix epoch")
+        .as_secs()
+}
+
+fn check_unauthenticated(method: &Method) -> ManagerResult<()> {
+    match Action::from_method(method) {
+        Action::Read => Ok(()),
+        Action::Write => Err(ManagerError::Forbidden(
+            "writes need MANAGER_AUTH_KEY to be set and a token of an operator".to_string(),
+        )),
+    }
+}
+
+fn mac(key: &str, payload: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).ex("HMAC should accept keys of any size");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+/// `/v2/users/user1` -> `users/user1`
+fn resource_of(path: &str) -> String {
+    let path = path.trim_start_matches('/');
+    match path.split_once('/') {
+        Some((_version, resource)) => resource.to_string(),
+        None => path.to_string(),
+    }
+}
+
+fn matches_any<T: PartialEq>(list: &Option<Vec<T>>, value: &T) -> bool {
+    match list {
+        Some(list) => list.contains(value),
+        None => true,
+    }
+}
+
+fn matches_str(matcher: &Option<StringMatcher>, value: &str) -> bool {
+    match matcher {
+        Some(matcher) => matcher.matches(value),
+        None => true,
+    }
+}
+
+fn forbidden(input: &ManagerInput, reason: &str) -> ManagerError {
+    ManagerError::Forbidden(format!(
+        "{} is not allowed to {:?} {}: {}",
+        input.identity, input.action, input.resource, reason
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::{header::AUTHORIZATION, HeaderMap, Method};
+    use piam_core::{
+        effect::Effect,
+        policy::{Policy, StringMatcher},
+    };
+
+    use crate::{
+        auth::{
+            check_unauthenticated, resource_of, Action, Authorizer, Identity, IdentityKind,
+            ManagerInput, ManagerPolicy, Token,
+        },
+        error::ManagerError,
+    };
+
+    #[test]
+    fn sign_and_verify_token() {
+        let token = Token::new(
+            Identity {
+                kind: IdentityKind::Proxy,
+                id: "proxy.us-east-1".to_string(),
+            },
+            100,
+        );
+        let token_id = token.token_id.clone();
+        let token = token.sign("key1");
+        let verified = Token::verify(&token, "key1", 99).unwrap();
+        assert_eq!(verified.identity.id, "proxy.us-east-1");
+        assert_eq!(verified.identity.kind, IdentityKind::Proxy);
+        assert_eq!(verified.token_id, token_id);
+
+        assert!(Token::verify(&token, "key1", 100).is_err());
+        assert!(Token::verify(&token, "key2", 99).is_err());
+        let tampered = token.replacen("proxy", "operator", 1);
+        assert!(Token::verify(&tampered, "key1", 99).is_err());
+    }
+
+    #[test]
+    fn authorize_by_policies() {
+        let mut policies = Authorizer::default_policies();
+        policies[0].modeled_policy.push(ManagerPolicy {
+            version: 1,
+            id: "no_accounts".to_string(),
+            kinds: None,
+            identities: Some(StringMatcher {
+                eq: Some(vec!["alice".to_string()]),
+                ..Default::default()
+            }),
+            actions: Some(vec![Action::Write]),
+            resources: Some(StringMatcher {
+                start_with: Some(vec!["accounts".to_string()]),
+                ..Default::default()
+            }),
+            effect: Effect::deny(),
+        });
+        let authorizer = Authorizer::new("key".to_string(), policies).unwrap();
+        let input = |kind, id: &str, action, resource: &str| ManagerInput {
+            identity: Identity {
+                kind,
+                id: id.to_string(),
+            },
+            action,
+            resource: resource.to_string(),
+        };
+        let (proxy, operator) = (IdentityKind::Proxy, IdentityKind::Operator);
+
+        assert!(authorizer
+            .authorize(&input(proxy, "p1", Action::Read, "users"))
+            .is_ok());
+        assert!(matches!(
+            authorizer.authorize(&input(proxy, "p1", Action::Write, "users")),
+            Err(ManagerError::Forbidden(_))
+        ));
+        assert!(authorizer
+            .authorize(&input(operator, "alice", Action::Write, "users/u1"))
+            .is_ok());
+        assert!(authorizer
+            .authorize(&input(operator, "bob", Action::Write, "accounts/a1"))
+            .is_ok());
+        assert!(matches!(
+            authorizer.authorize(&input(operator, "alice", Action::Write, "accounts/a1")),
+            Err(ManagerError::Forbidden(_))
+        ));
+
+        assert_eq!(
+            resource_of("/v2/policies/Condition/p1"),
+            "policies/Condition/p1"
+        );
+        assert!(Authorizer::new(String::new(), Vec::<Policy<ManagerPolicy>>::new()).is_err());
+    }
+
+    #[test]
+    fn reject_revoked_tokens() {
+        let identity = || Identity {
+            kind: IdentityKind::Operator,
+            id: "alice".to_string(),
+        };
+        let (leaked, other) = (Token::new(identity(), 100), Token::new(identity(), 100));
+        assert_ne!(leaked.token_id, other.token_id);
+        let authorizer = Authorizer::new("key".to_string(), Authorizer::default_policies())
+            .unwrap()
+            .revoke([leaked.token_id.clone()]);
+        let headers = |token: &Token| {
+            let mut headers = HeaderMap::new();
+            let bearer = format!("Bearer {}", token.sign("key"));
+            headers.insert(AUTHORIZATION, bearer.parse().unwrap());
+            headers
+        };
+
+        assert!(matches!(
+            authorizer.authenticate(&headers(&leaked), 99),
+            Err(ManagerError::Unauthorized(_))
+        ));
+        assert_eq!(
+            authorizer.authenticate(&headers(&other), 99).unwrap(),
+            identity()
+        );
+    }
+
+    #[test]
+    fn reject_unauthenticated_writes() {
+        assert!(check_unauthenticated(&Method::GET).is_ok());
+        for method in [Method::POST, Method::PUT, Method::DELETE] {
+            assert!(matches!(
+                check_unauthenticated(&method),
+                Err(ManagerError::Forbidden(_))
+            ));
+        }
+    }
+}
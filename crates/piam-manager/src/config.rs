@@ -1,7 +1,22 @@
 use busylib::config::{dev_mode, env_var_with_default, GlobalString};
 
+/// `redis`, `fs` or `sqlite`, see `persist::init_from_env`
+pub static CONFIG_STORE: GlobalString =
+    GlobalString::new(|| env_var_with_default("CONFIG_STORE", "redis"));
+/// The directory of the `fs` store or the database file of the `sqlite` store
+pub static CONFIG_STORE_PATH: GlobalString =
+    GlobalString::new(|| env_var_with_default("CONFIG_STORE_PATH", ""));
 pub static REDIS_ADDRESS: GlobalString =
     GlobalString::new(|| env_var_with_default("REDIS_ADDRESS", "redis://localhost/1"));
+/// The key signing the bearer tokens of the API, see `auth::Token`
+pub static MANAGER_AUTH_KEY: GlobalString =
+    GlobalString::new(|| env_var_with_default("MANAGER_AUTH_KEY", ""));
+/// Comma separated ids of the tokens rejected before they expire, see `auth::Token`
+pub static MANAGER_REVOKED_TOKENS: GlobalString =
+    GlobalString::new(|| env_var_with_default("MANAGER_REVOKED_TOKENS", ""));
+/// YAML file of `Policy<ManagerPolicy>` list, built-in policies are used if not set
+pub static MANAGER_POLICY_PATH: GlobalString =
+    GlobalString::new(|| env_var_with_default("MANAGER_POLICY_PATH", ""));
 
 pub fn port() -> u16 {
     if dev_mode() {
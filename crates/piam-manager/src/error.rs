@@ -2,17 +2,28 @@ use std::fmt;
 
 pub type ManagerResult<T> = Result<T, ManagerError>;
 
-#[allow(dead_code)]
+#[derive(Debug)]
 pub enum ManagerError {
     BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
     Internal(String),
+    /// The config store can not be used now
+    Unavailable(String),
 }
 
 impl fmt::Display for ManagerError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ManagerError::BadRequest(msg) => write!(f, "BadRequest: {msg}"),
+            ManagerError::Unauthorized(msg) => write!(f, "Unauthorized: {msg}"),
+            ManagerError::Forbidden(msg) => write!(f, "Forbidden: {msg}"),
+            ManagerError::NotFound(msg) => write!(f, "NotFound: {msg}"),
+            ManagerError::Conflict(msg) => write!(f, "Conflict: {msg}"),
             ManagerError::Internal(msg) => write!(f, "Internal: {msg}"),
+            ManagerError::Unavailable(msg) => write!(f, "Unavailable: {msg}"),
         }
     }
 }
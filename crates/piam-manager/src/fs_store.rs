@@ -0,0 +1,138 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use piam_core::manager_api_constant::VERSION;
+use tokio::sync::Mutex;
+
+use crate::{
+    error::{ManagerError, ManagerResult},
+    persist::{spawn_blocking, ConfigStore, Update},
+};
+
+/// One YAML file per key under `{root}/{VERSION}`, `policies:Condition` is stored at
+/// `policies/Condition.yaml`. The directory can be a git work tree, changes made through the API
+/// are left to be committed by the operator. Proxies only refetch the config once `revision.yaml`
+/// changes, so files changed by hand should come with a bump of it.
+pub struct FsStore {
+    root: PathBuf,
+    /// Serializes the writes of this process, files are replaced atomically by renaming. Held
+    /// across the file I/O which runs on the blocking thread pool.
+    write_lock: Mutex<()>,
+}
+
+impl FsStore {
+    pub fn new(root: impl AsRef<Path>) -> ManagerResult<Self> {
+        let root = root.as_ref().join(VERSION);
+        fs::create_dir_all(&root).map_err(|e| internal("create", &root, e))?;
+        Ok(Self {
+            root,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    fn path(&self, key: &str) -> ManagerResult<PathBuf> {
+        let mut path = self.root.clone();
+        for segment in key.split(':') {
+            if segment.is_empty() || segment.starts_with('.') || segment.contains(['/', '\\']) {
+                return Err(ManagerError::BadRequest(format!("invalid key: {}", key)));
+            }
+            path.push(segment);
+        }
+        path.set_extension("yaml");
+        Ok(path)
+    }
+
+    fn read(path: &Path) -> ManagerResult<Option<String>> {
+        match fs::read_to_string(path) {
+            Ok(s) => Ok(Some(s)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(internal("read", path, e)),
+        }
+    }
+
+    fn write(path: &Path, value: String) -> ManagerResult<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| internal("create", parent, e))?;
+        }
+        let tmp = path.with_extension("yaml.tmp");
+        fs::write(&tmp, value).map_err(|e| internal("write", &tmp, e))?;
+        fs::rename(&tmp, path).map_err(|e| internal("rename", &tmp, e))
+    }
+}
+
+#[async_trait]
+impl ConfigStore for FsStore {
+    async fn get(&self, key: &str) -> ManagerResult<Option<String>> {
+        let path = self.path(key)?;
+        spawn_blocking(move || Self::read(&path)).await
+    }
+
+    async fn get_many(&self, keys: &[String]) -> ManagerResult<Vec<Option<String>>> {
+        let paths = keys
+            .iter()
+            .map(|key| self.path(key))
+            .collect::<ManagerResult<Vec<_>>>()?;
+        // writes of this process are excluded, those by hand are not
+        let _guard = self.write_lock.lock().await;
+        spawn_blocking(move || paths.iter().map(|path| Self::read(path)).collect()).await
+    }
+
+    async fn update(&self, key: &str, update: &mut Update<'_>) -> ManagerResult<()> {
+        let path = self.path(key)?;
+        let _guard = self.write_lock.lock().await;
+        let read_path = path.clone();
+        let current = spawn_blocking(move || Self::read(&read_path)).await?;
+        let new = update(current)?;
+        spawn_blocking(move || Self::write(&path, new)).await
+    }
+
+    async fn delete(&self, key: &str) -> ManagerResult<()> {
+        let path = self.path(key)?;
+        let _guard = self.write_lock.lock().await;
+        let key = key.to_string();
+        spawn_blocking(move || match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(ManagerError::NotFound(format!("{} not found", key)))
+            }
+            Err(e) => Err(internal("delete", &path, e)),
+        })
+        .await
+    }
+
+    async fn health(&self) -> ManagerResult<()> {
+        let root = self.root.clone();
+        spawn_blocking(move || match root.is_dir() {
+            true => Ok(()),
+            false => Err(ManagerError::Unavailable(format!(
+                "{} is not a directory",
+                root.display()
+            ))),
+        })
+        .await
+    }
+}
+
+fn internal(op: &str, path: &Path, e: io::Error) -> ManagerError {
+    ManagerError::Internal(format!("failed to {} {}: {}", op, path.display(), e))
+}
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use crate::{fs_store::FsStore, persist::test::check_store};
+
+    #[tokio::test]
+    async fn fs_store() {
+        let root = std::env::temp_dir().join(format!("piam-fs-store-{}", Uuid::new_v4()));
+        let store = FsStore::new(&root).unwrap();
+        check_store(&store).await;
+        assert!(root.join("v3/policies/Condition.yaml").exists());
+        assert!(store.path("policies:../../etc").is_err());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,18 +1,141 @@
+use std::time::Duration;
+
 use axum::{
-    extract::Path,
-    http::StatusCode,
+    extract::{Path, Query},
+    http::{
+        header::{ETAG, IF_NONE_MATCH},
+        HeaderMap, StatusCode,
+    },
     response::{IntoResponse, Response},
 };
 use log::{error, info};
-use piam_core::crypto::encrypt;
+use piam_core::{
+    crypto::encrypt,
+    manager_api_constant::{
+        ACCOUNTS, CONDITION, ENDPOINTS, GROUPS, GROUP_PARENT_RELATIONSHIPS, POLICY_RELATIONSHIPS,
+        REVISION, ROLES, USERS, USER_GROUP_RELATIONSHIPS, USER_ROLE_RELATIONSHIPS,
+    },
+    policy::{condition::ConditionPolicy, Policy},
+};
+use piam_object_storage::{config::POLICY_MODEL as OBJECT_STORAGE, policy::ObjectStoragePolicy};
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+use tokio::sync::Mutex;
 
 use crate::{
     error::{ManagerError, ManagerResult},
-    persist::get_resource_string,
+    integrity,
+    persist::{
+        check_health, delete_resource_string, get_resource_string, get_resource_strings,
+        get_revision, update_resource_string, wait_revision,
+    },
+    resource::{apply, Resource, Write},
 };
 
-pub async fn health() -> impl IntoResponse {
-    "OK"
+/// Reports whether the config store is usable, 503 if not
+pub async fn health() -> ManagerResult<&'static str> {
+    check_health().await?;
+    Ok("OK")
+}
+
+/// At most seconds to wait in a long poll of the revision
+const MAX_REVISION_WAIT: u64 = 60;
+
+#[derive(Debug, Deserialize)]
+pub struct RevisionQuery {
+    /// Seconds to wait for a revision other than the one in `If-None-Match`
+    wait: Option<u64>,
+}
+
+/// The revision of the whole config as the ETag, 304 if it matches `If-None-Match`. Not
+/// encrypted as it reveals nothing. With `?wait=` it is a long poll returning as soon as the
+/// revision changes, so that proxies can apply changes at once.
+pub async fn revision(
+    Path(ver): Path<String>,
+    Query(query): Query<RevisionQuery>,
+    headers: HeaderMap,
+) -> ManagerResult<Response> {
+    let known = headers
+        .get(IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim_matches('"').parse().ok());
+    let revision = match (known, query.wait) {
+        (Some(known), Some(wait)) => {
+            wait_revision(known, Duration::from_secs(wait.min(MAX_REVISION_WAIT))).await?
+        }
+        _ => get_revision().await?,
+    };
+    let etag = format!("\"{}\"", revision);
+    if matches!(headers.get(IF_NONE_MATCH), Some(v) if v.as_bytes() == etag.as_bytes()) {
+        return Ok((StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response());
+    }
+    info!("version: {} api: revision {}", ver, revision);
+    Ok(([(ETAG, etag)], revision.to_string()).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SnapshotQuery {
+    policy_model: String,
+    extended_config_type: Option<String>,
+}
+
+/// The config of a proxy in one document read atomically, so that it is never torn by a
+/// concurrent write: `revision`, `core_config` with the fields of the proxy's `CoreConfig`, and
+/// `extended_config`. Resources not set are left out.
+pub async fn snapshot(
+    Path(ver): Path<String>,
+    Query(query): Query<SnapshotQuery>,
+) -> ManagerResult<String> {
+    info!(
+        "version: {} api: snapshot policy_model: {} extended_config_type: {:?}",
+        ver, query.policy_model, query.extended_config_type
+    );
+    let core_config_keys = [
+        (ACCOUNTS, ACCOUNTS.to_string()),
+        (USERS, USERS.to_string()),
+        (GROUPS, GROUPS.to_string()),
+        (ROLES, ROLES.to_string()),
+        (
+            "user_input_policies",
+            format!("policies:{}", query.policy_model),
+        ),
+        ("condition_policies", format!("policies:{}", CONDITION)),
+        (
+            USER_GROUP_RELATIONSHIPS,
+            USER_GROUP_RELATIONSHIPS.to_string(),
+        ),
+        (
+            GROUP_PARENT_RELATIONSHIPS,
+            GROUP_PARENT_RELATIONSHIPS.to_string(),
+        ),
+        (USER_ROLE_RELATIONSHIPS, USER_ROLE_RELATIONSHIPS.to_string()),
+        (POLICY_RELATIONSHIPS, POLICY_RELATIONSHIPS.to_string()),
+        (ENDPOINTS, ENDPOINTS.to_string()),
+    ];
+    let mut keys = vec![REVISION.to_string()];
+    keys.extend(core_config_keys.iter().map(|(_, key)| key.clone()));
+    if let Some(config_type) = &query.extended_config_type {
+        keys.push(format!("extended_config:{}", config_type));
+    }
+    let mut values = get_resource_strings(&keys).await?.into_iter();
+
+    let mut snapshot = Mapping::new();
+    let revision = values.next().flatten().unwrap_or_else(|| "0".to_string());
+    snapshot.insert("revision".into(), revision.trim().into());
+    let mut core_config = Mapping::new();
+    for ((field, key), value) in core_config_keys.iter().zip(values.by_ref()) {
+        if let Some(value) = value {
+            core_config.insert((*field).into(), parse_stored(key, &value)?);
+        }
+    }
+    snapshot.insert("core_config".into(), core_config.into());
+    if let (Some(config_type), Some(Some(value))) = (&query.extended_config_type, values.next()) {
+        let key = format!("extended_config:{}", config_type);
+        snapshot.insert("extended_config".into(), parse_stored(&key, &value)?);
+    }
+    let snapshot = serde_yaml::to_string(&snapshot)
+        .map_err(|e| ManagerError::Internal(format!("failed to serialize snapshot: {}", e)))?;
+    wrap(snapshot)
 }
 
 pub async fn get_accounts(Path(ver): Path<String>) -> ManagerResult<String> {
@@ -33,6 +156,12 @@ pub async fn get_groups(Path(ver): Path<String>) -> ManagerResult<String> {
     wrap(r)
 }
 
+pub async fn get_roles(Path(ver): Path<String>) -> ManagerResult<String> {
+    info!("version: {} api: get_roles", ver);
+    let r = get_resource_string("roles").await?;
+    wrap(r)
+}
+
 pub async fn get_policies(
     Path((ver, policy_model)): Path<(String, String)>,
 ) -> ManagerResult<String> {
@@ -50,12 +179,30 @@ pub async fn get_user_group_relationships(Path(ver): Path<String>) -> ManagerRes
     wrap(r)
 }
 
+pub async fn get_group_parent_relationships(Path(ver): Path<String>) -> ManagerResult<String> {
+    info!("version: {} api: get_group_parent_relationships", ver);
+    let r = get_resource_string("group_parent_relationships").await?;
+    wrap(r)
+}
+
+pub async fn get_user_role_relationships(Path(ver): Path<String>) -> ManagerResult<String> {
+    info!("version: {} api: get_user_role_relationships", ver);
+    let r = get_resource_string("user_role_relationships").await?;
+    wrap(r)
+}
+
 pub async fn get_policy_relationships(Path(ver): Path<String>) -> ManagerResult<String> {
     info!("version: {} api: get_policy_relationships", ver);
     let r = get_resource_string("policy_relationships").await?;
     wrap(r)
 }
 
+pub async fn get_endpoints(Path(ver): Path<String>) -> ManagerResult<String> {
+    info!("version: {} api: get_endpoints", ver);
+    let r = get_resource_string("endpoints").await?;
+    wrap(r)
+}
+
 pub async fn extended_config(
     Path((ver, config_type)): Path<(String, String)>,
 ) -> ManagerResult<String> {
@@ -64,6 +211,99 @@ pub async fn extended_config(
     wrap(r)
 }
 
+/// Writes an item of the resource list stored under `key`, see [`Write`]
+pub async fn write_resource<T: Resource>(
+    ver: String,
+    key: String,
+    write: Write,
+) -> ManagerResult<String> {
+    info!("version: {} api: write {} {}", ver, key, write);
+    // the other resources are read before the key is updated, so checked writes are serialized
+    let _guard = CHECKED_WRITE.lock().await;
+    let keys = integrity::keys();
+    let checked = keys.iter().position(|k| *k == key);
+    let mut stored = match checked {
+        Some(_) => get_resource_strings(&keys).await?,
+        None => Vec::new(),
+    };
+    update_resource_string(&key, |current| {
+        let updated = apply::<T>(current.as_deref(), &write)?;
+        if let Some(i) = checked {
+            stored[i] = Some(updated.clone());
+            integrity::check(&stored)?;
+        }
+        Ok(updated)
+    })
+    .await?;
+    Ok("OK".to_string())
+}
+
+/// Held from reading the resources to committing a write checked against them. Writes of other
+/// manager replicas are not serialized with it, so run only one replica that writes.
+static CHECKED_WRITE: Mutex<()> = Mutex::const_new(());
+
+pub async fn create_policy(
+    Path((ver, policy_model)): Path<(String, String)>,
+    body: String,
+) -> ManagerResult<String> {
+    write_policy(ver, policy_model, Write::Create { body }).await
+}
+
+pub async fn update_policy(
+    Path((ver, policy_model, id)): Path<(String, String, String)>,
+    body: String,
+) -> ManagerResult<String> {
+    write_policy(ver, policy_model, Write::Update { id, body }).await
+}
+
+pub async fn delete_policy(
+    Path((ver, policy_model, id)): Path<(String, String, String)>,
+) -> ManagerResult<String> {
+    write_policy(ver, policy_model, Write::Delete { id }).await
+}
+
+/// Only policies of the models in [`integrity::POLICY_MODELS`] can be written, fully validated
+async fn write_policy(ver: String, policy_model: String, write: Write) -> ManagerResult<String> {
+    let key = integrity::policies_key(&policy_model);
+    match policy_model.as_str() {
+        CONDITION => write_resource::<Policy<ConditionPolicy>>(ver, key, write).await,
+        OBJECT_STORAGE => write_resource::<Policy<ObjectStoragePolicy>>(ver, key, write).await,
+        _ => Err(ManagerError::BadRequest(format!(
+            "policy model '{}' is not registered, expected one of {:?}",
+            policy_model,
+            integrity::POLICY_MODELS
+        ))),
+    }
+}
+
+pub async fn put_extended_config(
+    Path((ver, config_type)): Path<(String, String)>,
+    body: String,
+) -> ManagerResult<String> {
+    info!("version: {} api: put_extended_config: {}", ver, config_type);
+    serde_yaml::from_str::<Value>(&body)
+        .map_err(|e| ManagerError::BadRequest(format!("invalid payload: {}", e)))?;
+    let key = format!("extended_config:{}", config_type);
+    update_resource_string(&key, |_| Ok(body.clone())).await?;
+    Ok("OK".to_string())
+}
+
+pub async fn delete_extended_config(
+    Path((ver, config_type)): Path<(String, String)>,
+) -> ManagerResult<String> {
+    info!(
+        "version: {} api: delete_extended_config: {}",
+        ver, config_type
+    );
+    delete_resource_string(&format!("extended_config:{}", config_type)).await?;
+    Ok("OK".to_string())
+}
+
+fn parse_stored(key: &str, value: &str) -> ManagerResult<Value> {
+    serde_yaml::from_str(value)
+        .map_err(|e| ManagerError::Internal(format!("invalid YAML stored at {}: {}", key, e)))
+}
+
 fn wrap(value: String) -> ManagerResult<String> {
     // manually encrypt for HTTP
     Ok(encrypt(value))
@@ -71,11 +311,16 @@ fn wrap(value: String) -> ManagerResult<String> {
 
 impl IntoResponse for ManagerError {
     fn into_response(self) -> Response {
-        let body = match self {
-            ManagerError::BadRequest(e) => e,
-            ManagerError::Internal(e) => e,
+        let (status, body) = match self {
+            ManagerError::BadRequest(e) => (StatusCode::BAD_REQUEST, e),
+            ManagerError::Unauthorized(e) => (StatusCode::UNAUTHORIZED, e),
+            ManagerError::Forbidden(e) => (StatusCode::FORBIDDEN, e),
+            ManagerError::NotFound(e) => (StatusCode::NOT_FOUND, e),
+            ManagerError::Conflict(e) => (StatusCode::CONFLICT, e),
+            ManagerError::Internal(e) => (StatusCode::INTERNAL_SERVER_ERROR, e),
+            ManagerError::Unavailable(e) => (StatusCode::SERVICE_UNAVAILABLE, e),
         };
         error!("ManagerError: {}", body);
-        (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+        (status, body).into_response()
     }
 }
@@ -0,0 +1,278 @@
+//! Referential integrity of the stored config.
+//!
+//! A write is checked against the config it would produce, with the same lints proxies run on
+//! load (see `CoreConfig::lint` of `piam-proxy`), so that nothing a proxy would reject as a
+//! whole is ever committed.
+
+use std::collections::HashSet;
+
+use piam_core::{
+    account::aws::AwsAccount,
+    endpoint::Endpoint,
+    group::Group,
+    lint::{
+        lint_account_codes, lint_duplicate_ids, lint_endpoints, lint_group_parent_relationships,
+        lint_policy_relationships, lint_user_group_relationships, lint_user_role_relationships,
+        KnownIds, Lint, LintKind,
+    },
+    manager_api_constant::{
+        policies_path, ACCOUNTS, CONDITION, ENDPOINTS, GROUPS, GROUP_PARENT_RELATIONSHIPS,
+        POLICY_RELATIONSHIPS, ROLES, USERS, USER_GROUP_RELATIONSHIPS, USER_ROLE_RELATIONSHIPS,
+    },
+    policy::{condition::ConditionPolicy, Policy},
+    principal::{Role, User},
+    relation_model::{
+        GroupParentRelationship, PolicyRelationship, UserGroupRelationship, UserRoleRelationship,
+    },
+    IamIdentity,
+};
+use piam_object_storage::{config::POLICY_MODEL as OBJECT_STORAGE, policy::ObjectStoragePolicy};
+use serde::de::DeserializeOwned;
+
+use crate::error::{ManagerError, ManagerResult};
+
+/// Policy models the manager validates, policies and relationships of other models are rejected
+pub const POLICY_MODELS: [&str; 2] = [CONDITION, OBJECT_STORAGE];
+
+/// Keys of the resources taking part in the check, in the order [`StoredConfig::parse`] reads
+pub fn keys() -> Vec<String> {
+    vec![
+        ACCOUNTS.to_string(),
+        USERS.to_string(),
+        GROUPS.to_string(),
+        ROLES.to_string(),
+        policies_key(CONDITION),
+        policies_key(OBJECT_STORAGE),
+        USER_GROUP_RELATIONSHIPS.to_string(),
+        GROUP_PARENT_RELATIONSHIPS.to_string(),
+        USER_ROLE_RELATIONSHIPS.to_string(),
+        POLICY_RELATIONSHIPS.to_string(),
+        ENDPOINTS.to_string(),
+    ]
+}
+
+pub fn policies_key(policy_model: &str) -> String {
+    format!("policies:{}", policy_model)
+}
+
+/// Checks the config stored under [`keys`] (values in the same order),
+/// [`ManagerError::Conflict`] with the lints a proxy would reject it for
+pub fn check(values: &[Option<String>]) -> ManagerResult<()> {
+    let config = StoredConfig::parse(&keys(), values)?;
+    let invalid: Vec<String> = config
+        .lint()
+        .iter()
+        .filter(|lint| rejected(&lint.kind))
+        .map(ToString::to_string)
+        .collect();
+    if !invalid.is_empty() {
+        return Err(ManagerError::Conflict(format!(
+            "config would be invalid: {}",
+            invalid.join("; ")
+        )));
+    }
+    Ok(())
+}
+
+/// Rejected by proxies as a whole, and relationships to policy models the manager does not know
+fn rejected(kind: &LintKind) -> bool {
+    matches!(
+        kind,
+        LintKind::DanglingRelationship
+            | LintKind::GroupCycle
+            | LintKind::ConflictingRule
+            | LintKind::UnknownPolicyModel
+    )
+}
+
+#[derive(Debug, Default)]
+struct StoredConfig {
+    accounts: Vec<AwsAccount>,
+    users: Vec<User>,
+    groups: Vec<Group>,
+    roles: Vec<Role>,
+    condition_policies: Vec<Policy<ConditionPolicy>>,
+    object_storage_policies: Vec<Policy<ObjectStoragePolicy>>,
+    user_group_relationships: Vec<UserGroupRelationship>,
+    group_parent_relationships: Vec<GroupParentRelationship>,
+    user_role_relationships: Vec<UserRoleRelationship>,
+    policy_relationships: Vec<PolicyRelationship>,
+    endpoints: Vec<Endpoint>,
+}
+
+impl StoredConfig {
+    fn parse(keys: &[String], values: &[Option<String>]) -> ManagerResult<Self> {
+        let mut stored = keys.iter().zip(values);
+        let mut next = || {
+            stored
+                .next()
+                .map(|(key, value)| (key.as_str(), value.as_deref()))
+        };
+        Ok(Self {
+            accounts: parse_list(next())?,
+            users: parse_list(next())?,
+            groups: parse_list(next())?,
+            roles: parse_list(next())?,
+            condition_policies: parse_list(next())?,
+            object_storage_policies: parse_list(next())?,
+            user_group_relationships: parse_list(next())?,
+            group_parent_relationships: parse_list(next())?,
+            user_role_relationships: parse_list(next())?,
+            policy_relationships: parse_list(next())?,
+            endpoints: parse_list(next())?,
+        })
+    }
+
+    fn lint(&self) -> Vec<Lint> {
+        let condition_path = policies_path(CONDITION);
+        let object_storage_path = policies_path(OBJECT_STORAGE);
+
+        let mut lints = lint_duplicate_ids(&self.accounts, ACCOUNTS);
+        lints.extend(lint_account_codes(&self.accounts, ACCOUNTS));
+        lints.extend(lint_duplicate_ids(&self.users, USERS));
+        lints.extend(lint_duplicate_ids(&self.groups, GROUPS));
+        lints.extend(lint_duplicate_ids(&self.roles, ROLES));
+        lints.extend(Policy::lint_all(&self.condition_policies, &condition_path));
+        lints.extend(Policy::lint_all(
+            &self.object_storage_policies,
+            &object_storage_path,
+        ));
+
+        let known = KnownIds {
+            accounts: ids(&self.accounts),
+            users: ids(&self.users),
+            groups: ids(&self.groups),
+            roles: ids(&self.roles),
+            policies: [
+                (CONDITION, ids(&self.condition_policies)),
+                (OBJECT_STORAGE, ids(&self.object_storage_policies)),
+            ]
+            .into(),
+        };
+        lints.extend(lint_user_group_relationships(
+            &self.user_group_relationships,
+            &known,
+            USER_GROUP_RELATIONSHIPS,
+        ));
+        lints.extend(lint_group_parent_relationships(
+            &self.group_parent_relationships,
+            &known,
+            GROUP_PARENT_RELATIONSHIPS,
+        ));
+        lints.extend(lint_user_role_relationships(
+            &self.user_role_relationships,
+            &known,
+            USER_ROLE_RELATIONSHIPS,
+        ));
+        lints.extend(lint_policy_relationships(
+            &self.policy_relationships,
+            &known,
+            POLICY_RELATIONSHIPS,
+        ));
+        lints.extend(lint_endpoints(&self.endpoints, &known, ENDPOINTS));
+        lints
+    }
+}
+
+fn parse_list<T: DeserializeOwned>(stored: Option<(&str, Option<&str>)>) -> ManagerResult<Vec<T>> {
+    match stored {
+        Some((key, Some(value))) => serde_yaml::from_str(value)
+            .map_err(|e| ManagerError::Internal(format!("invalid YAML stored at {}: {}", key, e))),
+        _ => Ok(Vec::new()),
+    }
+}
+
+fn ids<T: IamIdentity>(items: &[T]) -> HashSet<&str> {
+    items.iter().map(|item| item.id_str()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use piam_core::manager_api_constant::{ACCOUNTS, ENDPOINTS, POLICY_RELATIONSHIPS, USERS};
+
+    use crate::{
+        error::ManagerError,
+        integrity::{check, keys, policies_key},
+    };
+
+    fn stored(values: &[(&str, &str)]) -> Vec<Option<String>> {
+        keys()
+            .iter()
+            .map(|key| {
+                values
+                    .iter()
+                    .find(|(k, _)| k == key)
+                    .map(|(_, v)| v.to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reject_dangling_references() {
+        let account = (
+            ACCOUNTS,
+            "[{id: account1, code: '1000', access_key: '', secret_key: '', comment: dev}]",
+        );
+        let user = (
+            USERS,
+            "[{id: user1, name: alice, base_access_key: AKPSPERSALICE, secret: '', kind: Person}]",
+        );
+        let policies = (
+            policies_key("Condition"),
+            "[{kind: Condition, version: 1, id: policy1, name: allow, modeled_policy: []}]",
+        );
+        let relationship = |policy_model: &str, policy_id: &str| {
+            format!(
+                "[{{id: rel1, policy_model: {}, user_id: user1, group_id: null, role_id: null, account_id: account1, region: us-east-1, policy_id: {}}}]",
+                policy_model, policy_id
+            )
+        };
+
+        assert!(check(&stored(&[account, user])).is_ok());
+        let valid = relationship("Condition", "policy1");
+        let config = [
+            account,
+            user,
+            (policies.0.as_str(), policies.1),
+            (POLICY_RELATIONSHIPS, &valid),
+        ];
+        assert!(check(&stored(&config)).is_ok());
+
+        // the user of the relationship deleted
+        let config = [
+            account,
+            (policies.0.as_str(), policies.1),
+            (POLICY_RELATIONSHIPS, &valid),
+        ];
+        assert!(matches!(
+            check(&stored(&config)),
+            Err(ManagerError::Conflict(_))
+        ));
+
+        // the policy does not exist
+        let missing = relationship("Condition", "policy2");
+        let config = [account, user, (POLICY_RELATIONSHIPS, &missing)];
+        assert!(matches!(
+            check(&stored(&config)),
+            Err(ManagerError::Conflict(_))
+        ));
+
+        // the policy model is not registered
+        let unknown = relationship("Unknown", "policy1");
+        let config = [account, user, (POLICY_RELATIONSHIPS, &unknown)];
+        assert!(matches!(
+            check(&stored(&config)),
+            Err(ManagerError::Conflict(_))
+        ));
+
+        // the endpoint points at an unknown account
+        let endpoint = (
+            ENDPOINTS,
+            "[{id: endpoint1, account_id: account2, region: us-east-1, host: s3.example.com}]",
+        );
+        assert!(matches!(
+            check(&stored(&[account, endpoint])),
+            Err(ManagerError::Conflict(_))
+        ));
+    }
+}
@@ -2,17 +2,44 @@
 
 // #![allow(unused)]
 
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 
-use axum::{routing::get, Router};
+use axum::{
+    extract::Path,
+    middleware,
+    routing::{get, post, put},
+    Router,
+};
 use busylib::{logger::init_logger, prelude::EnhancedUnwrap};
-use log::info;
-use piam_core::manager_api_constant::*;
+use log::{error, info, warn};
+use piam_core::{
+    account::aws::AwsAccount,
+    endpoint::Endpoint,
+    group::Group,
+    manager_api_constant::*,
+    principal::{Role, User},
+    relation_model::{
+        GroupParentRelationship, PolicyRelationship, UserGroupRelationship, UserRoleRelationship,
+    },
+};
 
+use crate::{
+    auth::{Authorizer, Identity, Token},
+    config::MANAGER_AUTH_KEY,
+    resource::{Resource, Write},
+};
+
+mod auth;
 mod config;
 mod error;
+mod fs_store;
 mod handler;
+mod integrity;
 mod persist;
+mod redis_store;
+mod resource;
+#[cfg(feature = "sqlite")]
+mod sqlite_store;
 
 #[tokio::main]
 async fn main() {
@@ -20,28 +47,85 @@ async fn main() {
     let enable_logging = &["busylib", "piam-core"];
     let (_guard, _log_handle) = init_logger(&bin_name, enable_logging, true);
 
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("issue-token") {
+        issue_token(&args[2..]);
+        return;
+    }
+    if let Err(e) = persist::init_from_env() {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = persist::check_health().await {
+        // not fatal, the store is retried on each request and reported by /health
+        warn!("config store is not ready: {}", e);
+    }
+    let authorizer = match Authorizer::from_env() {
+        Ok(authorizer) => authorizer,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
     let routes = Router::new()
-        // .route("/_piam_manage_api", put(handler::manage))
-        .route("/health", get(handler::health))
+        .route(&gen_path(REVISION), get(handler::revision))
+        .route(&gen_path(SNAPSHOT), get(handler::snapshot))
         .route(&gen_path(ACCOUNTS), get(handler::get_accounts))
         .route(&gen_path(USERS), get(handler::get_users))
         .route(&gen_path(GROUPS), get(handler::get_groups))
+        .route(&gen_path(ROLES), get(handler::get_roles))
         .route(
             &gen_path_with_param(POLICIES, "policy_model_placeholder"),
-            get(handler::get_policies),
+            get(handler::get_policies).post(handler::create_policy),
+        )
+        .route(
+            &format!(
+                "{}/:id",
+                gen_path_with_param(POLICIES, "policy_model_placeholder")
+            ),
+            put(handler::update_policy).delete(handler::delete_policy),
         )
         .route(
             &gen_path(USER_GROUP_RELATIONSHIPS),
             get(handler::get_user_group_relationships),
         )
+        .route(
+            &gen_path(GROUP_PARENT_RELATIONSHIPS),
+            get(handler::get_group_parent_relationships),
+        )
+        .route(
+            &gen_path(USER_ROLE_RELATIONSHIPS),
+            get(handler::get_user_role_relationships),
+        )
         .route(
             &gen_path(POLICY_RELATIONSHIPS),
             get(handler::get_policy_relationships),
         )
+        .route(&gen_path(ENDPOINTS), get(handler::get_endpoints))
         .route(
             &gen_path_with_param(EXTENDED_CONFIG, "config_type_placeholder"),
-            get(handler::extended_config),
+            get(handler::extended_config)
+                .put(handler::put_extended_config)
+                .delete(handler::delete_extended_config),
         );
+    let routes = with_writes::<AwsAccount>(routes, ACCOUNTS);
+    let routes = with_writes::<User>(routes, USERS);
+    let routes = with_writes::<Group>(routes, GROUPS);
+    let routes = with_writes::<Role>(routes, ROLES);
+    let routes = with_writes::<UserGroupRelationship>(routes, USER_GROUP_RELATIONSHIPS);
+    let routes = with_writes::<GroupParentRelationship>(routes, GROUP_PARENT_RELATIONSHIPS);
+    let routes = with_writes::<UserRoleRelationship>(routes, USER_ROLE_RELATIONSHIPS);
+    let routes = with_writes::<PolicyRelationship>(routes, POLICY_RELATIONSHIPS);
+    let routes = with_writes::<Endpoint>(routes, ENDPOINTS);
+    let routes = match authorizer {
+        Some(authorizer) => routes.route_layer(middleware::from_fn_with_state(
+            Arc::new(authorizer),
+            auth::auth,
+        )),
+        None => routes.route_layer(middleware::from_fn(auth::read_only)),
+    };
+    let routes = routes.route("/health", get(handler::health));
 
     let addr = SocketAddr::from(([0, 0, 0, 0], config::port()));
     info!("piam-manager listening on {}", addr);
@@ -51,6 +135,61 @@ async fn main() {
         .unwp();
 }
 
+/// `piam-manager issue-token <proxy|operator> <id> <ttl_seconds>` prints a token signed by
+/// `MANAGER_AUTH_KEY`, and its id on stderr
+fn issue_token(args: &[String]) {
+    let [kind, id, ttl] = args else {
+        eprintln!("usage: piam-manager issue-token <proxy|operator> <id> <ttl_seconds>");
+        std::process::exit(2);
+    };
+    let (kind, ttl): (_, u64) = match (kind.parse(), ttl.parse()) {
+        (Ok(kind), Ok(ttl)) => (kind, ttl),
+        _ => {
+            eprintln!("invalid identity kind or ttl: {} {}", kind, ttl);
+            std::process::exit(2);
+        }
+    };
+    let key = MANAGER_AUTH_KEY.load();
+    if key.is_empty() {
+        eprintln!("MANAGER_AUTH_KEY is required");
+        std::process::exit(2);
+    }
+    let token = Token::new(
+        Identity {
+            kind,
+            id: id.to_string(),
+        },
+        auth::now() + ttl,
+    );
+    // stdout only gets the token, the id revokes it in MANAGER_REVOKED_TOKENS
+    eprintln!("token id: {}", token.token_id);
+    println!("{}", token.sign(&key));
+}
+
+/// `POST /:v/{resource}` creates an item, `PUT` and `DELETE /:v/{resource}/:id` update and
+/// delete one, the redis key of the resource list is its name
+fn with_writes<T: Resource + 'static>(routes: Router, resource: &'static str) -> Router {
+    let key = || resource.to_string();
+    routes
+        .route(
+            &gen_path(resource),
+            post(move |Path(ver): Path<String>, body: String| {
+                handler::write_resource::<T>(ver, key(), Write::Create { body })
+            }),
+        )
+        .route(
+            &gen_path_with_param(resource, "id"),
+            put(
+                move |Path((ver, id)): Path<(String, String)>, body: String| {
+                    handler::write_resource::<T>(ver, key(), Write::Update { id, body })
+                },
+            )
+            .delete(move |Path((ver, id)): Path<(String, String)>| {
+                handler::write_resource::<T>(ver, key(), Write::Delete { id })
+            }),
+        )
+}
+
 fn gen_path(value: &str) -> String {
     format!("/:v/{}", value)
 }
@@ -1,20 +1,272 @@
-use piam_core::manager_api_constant::VERSION;
-use redis::Commands;
+//! Storage of the resources, each resource is a YAML string under a key like `users` or
+//! `policies:Condition`. The backend is chosen by `CONFIG_STORE`, see [`ConfigStore`].
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use busylib::prelude::EnhancedExpect;
+use log::info;
+use once_cell::sync::{Lazy, OnceCell};
+use piam_core::manager_api_constant::REVISION;
+use tokio::{sync::watch, time::Instant};
 
 use crate::{
-    config::REDIS_ADDRESS,
+    config::{CONFIG_STORE, CONFIG_STORE_PATH, REDIS_ADDRESS},
     error::{ManagerError, ManagerResult},
+    fs_store::FsStore,
+    redis_store::RedisStore,
 };
 
+pub type Update<'a> = dyn FnMut(Option<String>) -> ManagerResult<String> + Send + 'a;
+
+#[async_trait]
+pub trait ConfigStore: Send + Sync {
+    /// [`None`] if the key is not set
+    async fn get(&self, key: &str) -> ManagerResult<Option<String>>;
+
+    /// Values of the keys in order, read atomically so no write is seen halfway
+    async fn get_many(&self, keys: &[String]) -> ManagerResult<Vec<Option<String>>>;
+
+    /// Read-modify-write of the key, `update` gets the current value ([`None`] if not set yet)
+    /// and may be called again if the key is modified concurrently. Nothing is written if it
+    /// returns an error.
+    async fn update(&self, key: &str, update: &mut Update<'_>) -> ManagerResult<()>;
+
+    /// [`ManagerError::NotFound`] if the key is not set
+    async fn delete(&self, key: &str) -> ManagerResult<()>;
+
+    /// [`ManagerError::Unavailable`] if the backend can not be used now
+    async fn health(&self) -> ManagerResult<()>;
+}
+
+/// Runs blocking I/O of a store on the blocking thread pool, off the async workers
+pub async fn spawn_blocking<R, F>(f: F) -> ManagerResult<R>
+where
+    R: Send + 'static,
+    F: FnOnce() -> ManagerResult<R> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| ManagerError::Internal(format!("blocking task failed: {}", e)))?
+}
+
+static STORE: OnceCell<Box<dyn ConfigStore>> = OnceCell::new();
+/// Revisions written by this process, to wake up the watchers at once
+static REVISION_WRITTEN: Lazy<watch::Sender<u64>> = Lazy::new(|| watch::channel(0).0);
+
+/// How often watchers check the revision written by other manager replicas or by hand
+const REVISION_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `redis` (default), `fs` for a directory of YAML files (which can be a git work tree), or
+/// `sqlite` for a database file, the latter two at `CONFIG_STORE_PATH`
+pub fn init_from_env() -> ManagerResult<()> {
+    let kind = CONFIG_STORE.load();
+    let path = CONFIG_STORE_PATH.load();
+    let store: Box<dyn ConfigStore> = match kind.as_str() {
+        "redis" => Box::new(RedisStore::new(&REDIS_ADDRESS.load())?),
+        "fs" => Box::new(FsStore::new(path.as_str())?),
+        #[cfg(feature = "sqlite")]
+        "sqlite" => Box::new(crate::sqlite_store::SqliteStore::open(path.as_str())?),
+        _ => {
+            return Err(ManagerError::Internal(format!(
+                "unsupported CONFIG_STORE: {}",
+                kind
+            )))
+        }
+    };
+    info!("CONFIG_STORE: {} {}", kind, path);
+    init(store);
+    Ok(())
+}
+
+pub fn init(store: Box<dyn ConfigStore>) {
+    if STORE.set(store).is_err() {
+        panic!("config store should only be initialized once");
+    }
+}
+
 pub async fn get_resource_string(key: &str) -> ManagerResult<String> {
-    let client = redis::Client::open(REDIS_ADDRESS.load().as_str())
-        .map_err(|e| ManagerError::Internal(format!("failed to create redis client: {}", e)))?;
-    let mut con = client
-        .get_connection()
-        .map_err(|e| ManagerError::Internal(format!("failed to get redis connection: {}", e)))?;
-    let key = format!("piam:{}:{}", VERSION, key);
-    let string = con.get(&key).map_err(|e| {
-        ManagerError::Internal(format!("failed to get redis key: {} error: {}", key, e))
-    })?;
-    Ok(string)
+    store()
+        .get(key)
+        .await?
+        .ok_or_else(|| ManagerError::NotFound(format!("{} not found", key)))
+}
+
+/// See [`ConfigStore::get_many`]
+pub async fn get_resource_strings(keys: &[String]) -> ManagerResult<Vec<Option<String>>> {
+    store().get_many(keys).await
+}
+
+pub async fn update_resource_string<F>(key: &str, mut update: F) -> ManagerResult<()>
+where
+    F: FnMut(Option<String>) -> ManagerResult<String> + Send,
+{
+    store().update(key, &mut update).await?;
+    bump_revision().await
+}
+
+pub async fn delete_resource_string(key: &str) -> ManagerResult<()> {
+    store().delete(key).await?;
+    bump_revision().await
+}
+
+/// 0 if nothing has been written yet
+pub async fn get_revision() -> ManagerResult<u64> {
+    store()
+        .get(REVISION)
+        .await?
+        .map_or(Ok(0), |revision| parse_revision(&revision))
+}
+
+/// Returns once the revision is not `known` anymore, or the current one after `timeout`
+pub async fn wait_revision(known: u64, timeout: Duration) -> ManagerResult<u64> {
+    // subscribe before reading, so no write in between is missed
+    let mut written = REVISION_WRITTEN.subscribe();
+    let deadline = Instant::now() + timeout;
+    loop {
+        let revision = get_revision().await?;
+        let left = deadline.saturating_duration_since(Instant::now());
+        if revision != known || left.is_zero() {
+            return Ok(revision);
+        }
+        // either way the revision is read again
+        let _ = tokio::time::timeout(left.min(REVISION_POLL_INTERVAL), written.changed()).await;
+    }
+}
+
+/// Bumped after the write, so a proxy seeing the new revision always gets the new config
+async fn bump_revision() -> ManagerResult<()> {
+    let mut bumped = 0;
+    store()
+        .update(REVISION, &mut |current| {
+            let revision = current.map_or(Ok(0), |revision| parse_revision(&revision))?;
+            bumped = revision + 1;
+            Ok(bumped.to_string())
+        })
+        .await?;
+    REVISION_WRITTEN.send_replace(bumped);
+    Ok(())
+}
+
+fn parse_revision(revision: &str) -> ManagerResult<u64> {
+    revision
+        .trim()
+        .parse()
+        .map_err(|e| ManagerError::Internal(format!("invalid revision {}: {}", revision, e)))
+}
+
+pub async fn check_health() -> ManagerResult<()> {
+    store().health().await
+}
+
+fn store() -> &'static dyn ConfigStore {
+    STORE
+        .get()
+        .ex("config store should be initialized before use")
+        .as_ref()
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::time::Duration;
+
+    use uuid::Uuid;
+
+    use crate::{
+        error::{ManagerError, ManagerResult},
+        fs_store::FsStore,
+        persist::{
+            delete_resource_string, get_revision, init, update_resource_string, wait_revision,
+            ConfigStore,
+        },
+    };
+
+    #[tokio::test]
+    async fn bump_revision_on_write() {
+        let root = std::env::temp_dir().join(format!("piam-revision-{}", Uuid::new_v4()));
+        init(Box::new(FsStore::new(&root).unwrap()));
+        assert_eq!(get_revision().await.unwrap(), 0);
+        update_resource_string("users", |_| Ok("[]".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(get_revision().await.unwrap(), 1);
+        // failed writes keep the revision
+        assert!(
+            update_resource_string("users", |_| Err(ManagerError::Conflict(
+                "conflict".to_string()
+            )))
+            .await
+            .is_err()
+        );
+        assert!(delete_resource_string("groups").await.is_err());
+        assert_eq!(get_revision().await.unwrap(), 1);
+        delete_resource_string("users").await.unwrap();
+        assert_eq!(get_revision().await.unwrap(), 2);
+
+        // times out with the known revision, or wakes up on a write
+        let wait = Duration::from_millis(50);
+        assert_eq!(wait_revision(2, wait).await.unwrap(), 2);
+        assert_eq!(wait_revision(1, wait).await.unwrap(), 2);
+        let waiting = tokio::spawn(wait_revision(2, Duration::from_secs(10)));
+        tokio::time::sleep(wait).await;
+        update_resource_string("users", |_| Ok("[]".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(waiting.await.unwrap().unwrap(), 3);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    /// The contract every store should fulfill
+    pub async fn check_store(store: &dyn ConfigStore) {
+        assert_eq!(store.get("users").await.unwrap(), None);
+        assert!(matches!(
+            store.delete("users").await,
+            Err(ManagerError::NotFound(_))
+        ));
+
+        store
+            .update("users", &mut |current| {
+                assert_eq!(current, None);
+                Ok("- id: user1".to_string())
+            })
+            .await
+            .unwrap();
+        store
+            .update("policies:Condition", &mut |_| Ok("[]".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get("users").await.unwrap().as_deref(),
+            Some("- id: user1")
+        );
+
+        let failed: ManagerResult<()> = store
+            .update("users", &mut |_| {
+                Err(ManagerError::Conflict("user1 exists".to_string()))
+            })
+            .await;
+        assert!(matches!(failed, Err(ManagerError::Conflict(_))));
+        assert_eq!(
+            store.get("users").await.unwrap().as_deref(),
+            Some("- id: user1")
+        );
+
+        let keys = ["policies:Condition", "groups", "users"].map(String::from);
+        assert_eq!(
+            store.get_many(&keys).await.unwrap(),
+            vec![
+                Some("[]".to_string()),
+                None,
+                Some("- id: user1".to_string())
+            ]
+        );
+
+        store.delete("users").await.unwrap();
+        assert_eq!(store.get("users").await.unwrap(), None);
+        store.health().await.unwrap();
+        assert_eq!(
+            store.get("policies:Condition").await.unwrap().as_deref(),
+            Some("[]")
+        );
+    }
 }
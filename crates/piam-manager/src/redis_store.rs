@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::warn;
+use piam_core::manager_api_constant::VERSION;
+use redis::{aio::ConnectionManager, AsyncCommands, Client, Script};
+use tokio::sync::Mutex;
+
+use crate::{
+    error::{ManagerError, ManagerResult},
+    persist::{ConfigStore, Update},
+};
+
+const MAX_ATTEMPTS: u32 = 4;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Sets `KEYS[1]` to `ARGV[3]` only if it is still the value read before, which is `ARGV[2]`
+/// if `ARGV[1]` is `1`, or unset if `ARGV[1]` is `0`
+const COMPARE_AND_SET: &str = r"
+local current = redis.call('GET', KEYS[1])
+if (ARGV[1] == '0' and not current) or (ARGV[1] == '1' and current == ARGV[2]) then
+    redis.call('SET', KEYS[1], ARGV[3])
+    return 1
+end
+return 0
+";
+
+/// Keys are stored as `piam:{VERSION}:{key}`. All requests share one multiplexed connection,
+/// which is established on first use and re-established by itself once it breaks.
+pub struct RedisStore {
+    client: Client,
+    connection: Mutex<Option<ConnectionManager>>,
+}
+
+impl RedisStore {
+    /// No connection is made until the first use
+    pub fn new(address: &str) -> ManagerResult<Self> {
+        let client = Client::open(address)
+            .map_err(|e| ManagerError::Internal(format!("failed to create redis client: {}", e)))?;
+        Ok(Self {
+            client,
+            connection: Mutex::new(None),
+        })
+    }
+
+    /// Retries with exponential backoff while redis is unreachable, each attempt taking at most
+    /// `CONNECT_TIMEOUT`. The lock is only held during an attempt, not while backing off.
+    async fn connection(&self) -> ManagerResult<ConnectionManager> {
+        let mut attempt = 1;
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let error = {
+                let mut connection = self.connection.lock().await;
+                // connected by another request meanwhile
+                if let Some(con) = connection.as_ref() {
+                    return Ok(con.clone());
+                }
+                let connect = ConnectionManager::new(self.client.clone());
+                match tokio::time::timeout(CONNECT_TIMEOUT, connect).await {
+                    Ok(Ok(con)) => {
+                        *connection = Some(con.clone());
+                        return Ok(con);
+                    }
+                    Ok(Err(e)) => e.to_string(),
+                    Err(_) => format!("timed out after {:?}", CONNECT_TIMEOUT),
+                }
+            };
+            if attempt >= MAX_ATTEMPTS {
+                return Err(ManagerError::Unavailable(format!(
+                    "failed to connect to redis: {}",
+                    error
+                )));
+            }
+            warn!(
+                "failed to connect to redis (attempt {}), retry in {:?}: {}",
+                attempt, backoff, error
+            );
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+            backoff *= 2;
+        }
+    }
+}
+
+#[async_trait]
+impl ConfigStore for RedisStore {
+    async fn get(&self, key: &str) -> ManagerResult<Option<String>> {
+        let mut con = self.connection().await?;
+        let key = redis_key(key);
+        con.get(&key).await.map_err(|e| redis_error("get", &key, e))
+    }
+
+    async fn get_many(&self, keys: &[String]) -> ManagerResult<Vec<Option<String>>> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut con = self.connection().await?;
+        let keys: Vec<String> = keys.iter().map(|key| redis_key(key)).collect();
+        redis::cmd("MGET")
+            .arg(&keys)
+            .query_async(&mut con)
+            .await
+            .map_err(|e| redis_error("get", &keys.join(" "), e))
+    }
+
+    async fn update(&self, key: &str, update: &mut Update<'_>) -> ManagerResult<()> {
+        // a multiplexed connection can not WATCH, so compare and set by a script instead
+        let mut con = self.connection().await?;
+        let key = redis_key(key);
+        let err = |e| redis_error("set", &key, e);
+        let script = Script::new(COMPARE_AND_SET);
+        loop {
+            let current: Option<String> = con.get(&key).await.map_err(err)?;
+            let is_set = if current.is_some() { "1" } else { "0" };
+            // nothing is written if update fails
+            let new = update(current.clone())?;
+            let set: i32 = script
+                .key(&key)
+                .arg(is_set)
+                .arg(current.unwrap_or_default())
+                .arg(new)
+                .invoke_async(&mut con)
+                .await
+                .map_err(err)?;
+            if set == 1 {
+                return Ok(());
+            }
+            // modified concurrently, read it again
+        }
+    }
+
+    async fn delete(&self, key: &str) -> ManagerResult<()> {
+        let mut con = self.connection().await?;
+        let key = redis_key(key);
+        let deleted: i32 = con
+            .del(&key)
+            .await
+            .map_err(|e| redis_error("delete", &key, e))?;
+        match deleted {
+            0 => Err(ManagerError::NotFound(format!("{} not found", key))),
+            _ => Ok(()),
+        }
+    }
+
+    async fn health(&self) -> ManagerResult<()> {
+        let mut con = self.connection().await?;
+        redis::cmd("PING")
+            .query_async::<_, ()>(&mut con)
+            .await
+            .map_err(|e| ManagerError::Unavailable(format!("redis PING failed: {}", e)))
+    }
+}
+
+fn redis_key(key: &str) -> String {
+    format!("piam:{}:{}", VERSION, key)
+}
+
+fn redis_error(op: &str, key: &str, e: redis::RedisError) -> ManagerError {
+    ManagerError::Internal(format!("failed to {} redis key: {} error: {}", op, key, e))
+}
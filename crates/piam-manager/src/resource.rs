@@ -0,0 +1,129 @@
+//! Writes to the lists of IAM entities stored as YAML.
+//!
+//! Payloads are YAML (or JSON, which is YAML too) of a single item, validated by deserializing
+//! them into `piam-core` types before anything is persisted, and the config they produce is
+//! checked as a whole, see `integrity`.
+
+use std::fmt;
+
+use piam_core::IamIdentity;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::{ManagerError, ManagerResult};
+
+pub trait Resource: IamIdentity + Serialize + DeserializeOwned {}
+
+impl<T: IamIdentity + Serialize + DeserializeOwned> Resource for T {}
+
+#[derive(Debug)]
+pub enum Write {
+    Create { body: String },
+    Update { id: String, body: String },
+    Delete { id: String },
+}
+
+impl fmt::Display for Write {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Write::Create { .. } => write!(f, "create"),
+            Write::Update { id, .. } => write!(f, "update {}", id),
+            Write::Delete { id } => write!(f, "delete {}", id),
+        }
+    }
+}
+
+pub fn parse<T: Resource>(body: &str) -> ManagerResult<T> {
+    let item: T = serde_yaml::from_str(body)
+        .map_err(|e| ManagerError::BadRequest(format!("invalid payload: {}", e)))?;
+    if item.id_str().is_empty() {
+        return Err(ManagerError::BadRequest("id must not be empty".to_string()));
+    }
+    Ok(item)
+}
+
+/// Applies the write to the current YAML list, returning the new list
+pub fn apply<T: Resource>(current: Option<&str>, write: &Write) -> ManagerResult<String> {
+    let mut items: Vec<T> = match current {
+        None => Vec::new(),
+        Some(s) => serde_yaml::from_str(s)
+            .map_err(|e| ManagerError::Internal(format!("stored resource is not valid: {}", e)))?,
+    };
+    let position = |items: &[T], id: &str| items.iter().position(|item| item.id_str() == id);
+    match write {
+        Write::Create { body } => {
+            let item = parse::<T>(body)?;
+            if position(&items, item.id_str()).is_some() {
+                return Err(ManagerError::Conflict(format!(
+                    "id '{}' already exists",
+                    item.id_str()
+                )));
+            }
+            items.push(item);
+        }
+        Write::Update { id, body } => {
+            let item = parse::<T>(body)?;
+            if item.id_str() != id {
+                return Err(ManagerError::BadRequest(format!(
+                    "id '{}' in payload does not match '{}'",
+                    item.id_str(),
+                    id
+                )));
+            }
+            let i = position(&items, id).ok_or_else(|| not_found(id))?;
+            items[i] = item;
+        }
+        Write::Delete { id } => {
+            let i = position(&items, id).ok_or_else(|| not_found(id))?;
+            items.remove(i);
+        }
+    }
+    serde_yaml::to_string(&items)
+        .map_err(|e| ManagerError::Internal(format!("failed to serialize resource: {}", e)))
+}
+
+fn not_found(id: &str) -> ManagerError {
+    ManagerError::NotFound(format!("id '{}' not found", id))
+}
+
+#[cfg(test)]
+mod test {
+    use piam_core::principal::User;
+
+    use crate::{
+        error::ManagerError,
+        resource::{apply, Write},
+    };
+
+    #[test]
+    fn apply_writes() {
+        let create = |body: &str| Write::Create {
+            body: body.to_string(),
+        };
+        let users = apply::<User>(None, &create("{id: user1, name: alice, base_access_key: AKPSPERSALICE, secret: '', kind: Person}")).unwrap();
+        assert!(matches!(
+            apply::<User>(Some(&users), &create("{id: user1, name: bob, base_access_key: AKPSPERSBOB, secret: '', kind: Person}")),
+            Err(ManagerError::Conflict(_))
+        ));
+        assert!(matches!(
+            apply::<User>(Some(&users), &create("{id: user2, name: bob}")),
+            Err(ManagerError::BadRequest(_))
+        ));
+
+        let update = Write::Update {
+            id: "user1".to_string(),
+            body: "{id: user1, name: alice, base_access_key: AKPSPERSALICE, secret: '', kind: Service}"
+                .to_string(),
+        };
+        let users = apply::<User>(Some(&users), &update).unwrap();
+        let parsed: Vec<User> = serde_yaml::from_str(&users).unwrap();
+        assert_eq!(parsed[0].kind, piam_core::principal::UserKind::Service);
+
+        let delete = |id: &str| Write::Delete { id: id.to_string() };
+        assert!(matches!(
+            apply::<User>(Some(&users), &delete("user2")),
+            Err(ManagerError::NotFound(_))
+        ));
+        let users = apply::<User>(Some(&users), &delete("user1")).unwrap();
+        assert_eq!(users.trim(), "[]");
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use busylib::prelude::EnhancedExpect;
+use piam_core::manager_api_constant::VERSION;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{
+    error::{ManagerError, ManagerResult},
+    persist::{spawn_blocking, ConfigStore, Update},
+};
+
+/// A single table of `(key, value)`, keys are prefixed by `VERSION` like `v3:users`
+pub struct SqliteStore {
+    /// Only locked on the blocking thread pool, see [`SqliteStore::run`]
+    con: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    /// `:memory:` opens an in-memory database
+    pub fn open(path: &str) -> ManagerResult<Self> {
+        let con = match path {
+            ":memory:" => Connection::open_in_memory(),
+            _ => Connection::open(path),
+        }
+        .map_err(|e| internal(&format!("open {}", path), e))?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS piam_resource (key TEXT PRIMARY KEY, value TEXT NOT NULL)",
+            [],
+        )
+        .map_err(|e| internal("create table", e))?;
+        Ok(Self {
+            con: Arc::new(Mutex::new(con)),
+        })
+    }
+
+    /// Runs `f` with the connection on the blocking thread pool
+    async fn run<R, F>(&self, f: F) -> ManagerResult<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Connection) -> ManagerResult<R> + Send + 'static,
+    {
+        let con = self.con.clone();
+        spawn_blocking(move || {
+            let mut con = con.lock().ex("sqlite store lock should not be poisoned");
+            f(&mut con)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl ConfigStore for SqliteStore {
+    async fn get(&self, key: &str) -> ManagerResult<Option<String>> {
+        let key = key.to_string();
+        self.run(move |con| get(con, &key)).await
+    }
+
+    async fn get_many(&self, keys: &[String]) -> ManagerResult<Vec<Option<String>>> {
+        let keys = keys.to_vec();
+        // writes are excluded by the lock of the connection
+        self.run(move |con| keys.iter().map(|key| get(con, key)).collect())
+            .await
+    }
+
+    async fn update(&self, key: &str, update: &mut Update<'_>) -> ManagerResult<()> {
+        // update runs off the blocking thread pool, so compare and set like the redis store
+        loop {
+            let current = self.get(key).await?;
+            // nothing is written if update fails
+            let new = update(current.clone())?;
+            let key = key.to_string();
+            let set = self
+                .run(move |con| compare_and_set(con, &key, current.as_deref(), &new))
+                .await?;
+            if set {
+                return Ok(());
+            }
+            // modified concurrently, read it again
+        }
+    }
+
+    async fn delete(&self, key: &str) -> ManagerResult<()> {
+        let key = key.to_string();
+        self.run(move |con| {
+            let deleted = con
+                .execute(
+                    "DELETE FROM piam_resource WHERE key = ?1",
+                    params![sqlite_key(&key)],
+                )
+                .map_err(|e| internal(&format!("delete {}", key), e))?;
+            match deleted {
+                0 => Err(ManagerError::NotFound(format!("{} not found", key))),
+                _ => Ok(()),
+            }
+        })
+        .await
+    }
+
+    async fn health(&self) -> ManagerResult<()> {
+        self.run(|con| {
+            con.query_row("SELECT 1", [], |_| Ok(()))
+                .map_err(|e| ManagerError::Unavailable(format!("sqlite is not usable: {}", e)))
+        })
+        .await
+    }
+}
+
+fn get(con: &Connection, key: &str) -> ManagerResult<Option<String>> {
+    con.prepare_cached("SELECT value FROM piam_resource WHERE key = ?1")
+        .map_err(|e| internal("prepare", e))?
+        .query_row(params![sqlite_key(key)], |row| row.get(0))
+        .optional()
+        .map_err(|e| internal(&format!("get {}", key), e))
+}
+
+/// Sets the key to `new` only if it is still `current` ([`None`] for not set), false if not
+fn compare_and_set(
+    con: &Connection,
+    key: &str,
+    current: Option<&str>,
+    new: &str,
+) -> ManagerResult<bool> {
+    let changed = match current {
+        None => con.execute(
+            "INSERT INTO piam_resource (key, value) VALUES (?1, ?2) ON CONFLICT(key) DO NOTHING",
+            params![sqlite_key(key), new],
+        ),
+        Some(current) => con.execute(
+            "UPDATE piam_resource SET value = ?3 WHERE key = ?1 AND value = ?2",
+            params![sqlite_key(key), current, new],
+        ),
+    }
+    .map_err(|e| internal(&format!("set {}", key), e))?;
+    Ok(changed == 1)
+}
+
+fn sqlite_key(key: &str) -> String {
+    format!("{}:{}", VERSION, key)
+}
+
+fn internal(op: &str, e: rusqlite::Error) -> ManagerError {
+    ManagerError::Internal(format!("sqlite failed to {}: {}", op, e))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{persist::test::check_store, sqlite_store::SqliteStore};
+
+    #[tokio::test]
+    async fn sqlite_store() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        check_store(&store).await;
+    }
+}
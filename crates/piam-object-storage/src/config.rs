@@ -2,6 +2,9 @@ use serde::{Deserialize, Serialize};
 
 use crate::error::{ParserError, ParserResult};
 
+/// `kind` of object storage policies and their `policy_model` in relationships
+pub const POLICY_MODEL: &str = "ObjectStorage";
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct HostDomains {
     pub domains: Vec<String>,
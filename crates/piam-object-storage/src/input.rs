@@ -1,7 +1,8 @@
 use serde::{Deserialize, Serialize};
-use strum_macros::Display;
+use strum_macros::{Display, EnumString};
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq, Display, Serialize, Deserialize)]
+/// `EnumString` parses an action name into the variant with empty fields
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Display, EnumString, Serialize, Deserialize)]
 pub enum ObjectStorageInput {
     ListBuckets,
     CreateBucket {
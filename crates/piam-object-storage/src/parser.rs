@@ -2,15 +2,35 @@ use piam_core::{
     input::{Input, InputAndRequest},
     type_alias::HttpRequest,
 };
+use tracing::{instrument, Span};
 
-use crate::{config::HostDomains, error::ParserResult, input::ObjectStorageInput};
+use crate::{
+    config::HostDomains,
+    error::ParserResult,
+    input::{ActionKind, ObjectStorageInput},
+};
 
 impl Input for ObjectStorageInput {}
 
 impl ObjectStorageInput {
+    #[instrument(name = "ObjectStorageInput::parse", skip_all, fields(action, bucket))]
     pub async fn parse(
         req: HttpRequest,
         config: &HostDomains,
+    ) -> ParserResult<InputAndRequest<ObjectStorageInput>> {
+        let parsed = Self::parse_by_sdk(req, config).await?;
+        let input = parsed.input();
+        let span = Span::current();
+        span.record("action", input.action().as_str());
+        if input.action_kind() != ActionKind::ListBuckets {
+            span.record("bucket", input.bucket());
+        }
+        Ok(parsed)
+    }
+
+    async fn parse_by_sdk(
+        req: HttpRequest,
+        config: &HostDomains,
     ) -> ParserResult<InputAndRequest<ObjectStorageInput>> {
         #[cfg(feature = "cos-parser")]
         {
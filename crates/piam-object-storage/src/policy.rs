@@ -1,6 +1,9 @@
+use std::str::FromStr;
+
 use piam_core::{
     effect::Effect,
     error::PiamResult,
+    lint::{Lint, LintKind},
     policy::{Modeled, StringMatcher},
 };
 use serde::{Deserialize, Serialize};
@@ -89,6 +92,11 @@ impl Modeled for ObjectStoragePolicy {
             ActionKind::Object => input_policy.find_object_effect(input),
         }
     }
+
+    fn lint(&self, location: &str) -> Vec<Lint> {
+        self.input_policy
+            .lint(&format!("{}.input_policy", location))
+    }
 }
 
 /// Modeling for ObjectStoragePolicy
@@ -134,15 +142,162 @@ impl ObjectStorageMatches for ObjectStorageInputPolicy {
 }
 
 impl ObjectStorageInputPolicy {
+    pub fn lint(&self, location: &str) -> Vec<Lint> {
+        let mut lints = Vec::new();
+        let mut object_actions = true;
+        if let Some(actions) = &self.actions {
+            let location = format!("{}.actions", location);
+            if actions.is_empty() {
+                lints.push(Lint::new(
+                    LintKind::UnreachableRule,
+                    &location,
+                    "empty actions never match",
+                ));
+            }
+            let mut kinds = Vec::new();
+            for (i, action) in actions.iter().enumerate() {
+                if action == "Any" {
+                    continue;
+                }
+                match ObjectStorageInput::from_str(action) {
+                    Ok(input) => kinds.push(input.action_kind()),
+                    Err(_) => lints.push(Lint::new(
+                        LintKind::UnknownAction,
+                        &format!("{}[{}]", location, i),
+                        format!("unknown action '{}'", action),
+                    )),
+                }
+            }
+            object_actions =
+                actions.contains(&"Any".to_string()) || kinds.contains(&ActionKind::Object);
+        }
+
+        if let Some(name) = &self.bucket.name {
+            lints.extend(name.lint(&format!("{}.bucket.name", location)));
+        }
+
+        if let Some(keys) = &self.keys {
+            let location = format!("{}.keys", location);
+            if !object_actions && !keys.is_empty() {
+                lints.push(Lint::new(
+                    LintKind::UnreachableRule,
+                    &location,
+                    "keys are never matched since there is no object action in actions",
+                ));
+            }
+            lints.extend(Self::lint_keys(keys, self.bucket.name.as_ref(), &location));
+        }
+        lints
+    }
+
+    /// Keys are matched in order by `find_keys_effect`, so a path covered by an earlier one
+    /// is unreachable. Keys rejected by `StringMatcher::check_conflict`, several without path or
+    /// sharing a value, make every request evaluating them fail.
+    fn lint_keys(keys: &[Key], bucket_name: Option<&StringMatcher>, location: &str) -> Vec<Lint> {
+        let mut lints = Vec::new();
+        let defaults: Vec<usize> = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, key)| key.path.is_none().then_some(i))
+            .collect();
+        if let Some((first, conflicting)) = defaults.split_first() {
+            for i in conflicting {
+                lints.push(Lint::new(
+                    LintKind::ConflictingRule,
+                    &format!("{}[{}]", location, i),
+                    format!("key without path conflicts with {}[{}]", location, first),
+                ));
+            }
+        }
+
+        for (i, key) in keys.iter().enumerate() {
+            let path = match &key.path {
+                None => continue,
+                Some(path) => path,
+            };
+            let path_location = format!("{}[{}].path", location, i);
+            if path.is_empty() {
+                lints.extend(path.lint(&path_location));
+                continue;
+            }
+            // conflicts are still reported once the path is known to be unreachable
+            let mut covered = false;
+            for (j, earlier) in keys[..i].iter().enumerate() {
+                let earlier = match &earlier.path {
+                    None => continue,
+                    Some(earlier) => earlier,
+                };
+                if let Some(value) = earlier.conflict_with(path) {
+                    lints.push(Lint::new(
+                        LintKind::ConflictingRule,
+                        &path_location,
+                        format!("'{}' conflicts with {}[{}].path", value, location, j),
+                    ));
+                    continue;
+                }
+                if covered {
+                    continue;
+                }
+                if earlier.covers(path) {
+                    lints.push(Lint::new(
+                        LintKind::UnreachableRule,
+                        &path_location,
+                        format!("always matched first by {}[{}].path", location, j),
+                    ));
+                    covered = true;
+                    continue;
+                }
+                if let Some(value) = earlier.overlap_with(path) {
+                    lints.push(Lint::new(
+                        LintKind::OverlappingPath,
+                        &path_location,
+                        format!("'{}' is also matched by {}[{}].path", value, location, j),
+                    ));
+                }
+            }
+            if let Some(bucket_name) = bucket_name {
+                if !bucket_name.is_empty() && !Self::reachable_from_bucket(path, bucket_name) {
+                    lints.push(Lint::new(
+                        LintKind::UnreachableRule,
+                        &path_location,
+                        "path never starts with a bucket matched by bucket.name",
+                    ));
+                }
+            }
+        }
+        lints
+    }
+
+    /// Whether any full path (`bucket/key`) matched by `path` can be in a bucket matched by
+    /// `bucket_name`
+    fn reachable_from_bucket(path: &StringMatcher, bucket_name: &StringMatcher) -> bool {
+        let prefixes: Vec<String> = bucket_name
+            .eq
+            .iter()
+            .flatten()
+            .map(|bucket| format!("{}/", bucket))
+            .chain(bucket_name.start_with.iter().flatten().cloned())
+            .collect();
+        let eq_reachable = path
+            .eq
+            .iter()
+            .flatten()
+            .any(|v| prefixes.iter().any(|p| v.starts_with(p.as_str())));
+        let start_with_reachable = path.start_with.iter().flatten().any(|v| {
+            prefixes
+                .iter()
+                .any(|p| v.starts_with(p.as_str()) || p.starts_with(v.as_str()))
+        });
+        eq_reachable || start_with_reachable
+    }
+
     /// find the first key policy that matches the input, return the effect
-    /// assume that key policy in keys are not conflicting
+    /// assume that key policy in keys are not conflicting, see `lint`
     fn find_keys_effect<'a>(
         &'a self,
         input: &ObjectStorageInput,
         policies: &'a [Key],
     ) -> PiamResult<Option<&Effect>> {
-        // TODO: static analysis to make sure that key policy in keys are not conflicting
-
         let path_matchers = policies
             .iter()
             .map(|policy| policy.path.as_ref())
@@ -181,6 +336,7 @@ impl ObjectStorageInputPolicy {
 mod test {
     use piam_core::{
         effect::{Effect, Modify},
+        lint::LintKind,
         policy::StringMatcher,
     };
 
@@ -189,6 +345,94 @@ mod test {
         policy::{Key, ObjectStorageInputPolicy, ObjectStorageMatches},
     };
 
+    #[test]
+    fn lint() {
+        let start_with = |v: &str| StringMatcher {
+            eq: None,
+            start_with: Some(vec![v.to_string()]),
+        };
+        let mut policy = ObjectStorageInputPolicy {
+            actions: Some(vec!["GetObject".to_string(), "GetObjects".to_string()]),
+            ..Default::default()
+        };
+        policy.bucket.name = Some(StringMatcher {
+            eq: Some(vec!["bucket1".to_string()]),
+            start_with: None,
+        });
+        policy.keys = Some(vec![
+            Key {
+                path: Some(start_with("bucket1/a")),
+                ..Default::default()
+            },
+            Key {
+                path: Some(start_with("bucket1/a/b")),
+                ..Default::default()
+            },
+            Key {
+                path: Some(start_with("bucket1/")),
+                ..Default::default()
+            },
+            Key {
+                path: Some(start_with("bucket2/")),
+                ..Default::default()
+            },
+            Key {
+                path: Some(StringMatcher::default()),
+                ..Default::default()
+            },
+            Key {
+                path: Some(StringMatcher {
+                    eq: Some(vec!["bucket1/c".to_string()]),
+                    start_with: None,
+                }),
+                ..Default::default()
+            },
+            Key {
+                path: Some(StringMatcher {
+                    eq: Some(vec!["bucket1/c".to_string()]),
+                    start_with: None,
+                }),
+                ..Default::default()
+            },
+            Key::default(),
+            Key::default(),
+        ]);
+
+        let lints: Vec<(LintKind, String)> = policy
+            .lint("p")
+            .into_iter()
+            .map(|l| (l.kind, l.location))
+            .collect();
+        assert_eq!(
+            lints,
+            vec![
+                (LintKind::UnknownAction, "p.actions[1]".to_string()),
+                (LintKind::ConflictingRule, "p.keys[8]".to_string()),
+                (LintKind::UnreachableRule, "p.keys[1].path".to_string()),
+                (LintKind::OverlappingPath, "p.keys[2].path".to_string()),
+                (LintKind::OverlappingPath, "p.keys[2].path".to_string()),
+                (LintKind::UnreachableRule, "p.keys[3].path".to_string()),
+                (LintKind::EmptyMatcher, "p.keys[4].path".to_string()),
+                (LintKind::UnreachableRule, "p.keys[5].path".to_string()),
+                (LintKind::UnreachableRule, "p.keys[6].path".to_string()),
+                (LintKind::ConflictingRule, "p.keys[6].path".to_string()),
+            ]
+        );
+        // the keys of each conflicting lint are rejected at runtime
+        let keys = policy.keys.as_ref().unwrap();
+        for (a, b) in [(5, 6), (7, 8)] {
+            let matchers = [keys[a].path.as_ref(), keys[b].path.as_ref()];
+            assert!(StringMatcher::check_conflict(&matchers).is_err());
+        }
+
+        policy.actions = Some(vec!["ListObjects".to_string()]);
+        policy.keys = Some(vec![Key::default()]);
+        let lints = policy.lint("p");
+        assert_eq!(lints.len(), 1);
+        assert_eq!(lints[0].kind, LintKind::UnreachableRule);
+        assert_eq!(lints[0].location, "p.keys");
+    }
+
     #[test]
     fn match_action() {
         let policy = ObjectStorageInputPolicy {
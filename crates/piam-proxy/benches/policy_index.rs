@@ -0,0 +1,60 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use piam_core::{account::aws::AwsAccount, group::Group, relation_model::PolicyRelationship};
+use piam_proxy::{
+    container::PolicyFilterParams,
+    policy_index::{scan, PolicyRelationshipIndex},
+};
+
+const ACCOUNTS: usize = 20;
+const REGIONS: usize = 5;
+const GROUPS: usize = 500;
+
+fn relationships() -> Vec<PolicyRelationship> {
+    let mut relationships = Vec::new();
+    for account in 0..ACCOUNTS {
+        for region in 0..REGIONS {
+            for group in 0..GROUPS {
+                relationships.push(PolicyRelationship {
+                    id: relationships.len().to_string(),
+                    policy_model: "ObjectStorage".to_string(),
+                    group_id: Some(format!("group{group}")),
+                    account_id: format!("account{account}"),
+                    region: format!("region{region}"),
+                    policy_id: format!("policy{group}"),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    relationships
+}
+
+fn find_policy_relationships(c: &mut Criterion) {
+    let index = PolicyRelationshipIndex::new(relationships());
+    let account = AwsAccount {
+        id: "account7".to_string(),
+        ..Default::default()
+    };
+    let groups: Vec<Group> = ["group3", "group42", "group420"]
+        .into_iter()
+        .map(|id| Group {
+            id: id.to_string(),
+            ..Default::default()
+        })
+        .collect();
+    let groups: Vec<&Group> = groups.iter().collect();
+    let params = PolicyFilterParams::new_with(&account, "region2").groups(&groups);
+
+    let mut group = c.benchmark_group(format!(
+        "find {} policy relationships",
+        index.relationships().len()
+    ));
+    group.bench_function("scan", |b| {
+        b.iter(|| scan(black_box(index.relationships()), black_box(&params)))
+    });
+    group.bench_function("index", |b| b.iter(|| index.find(black_box(&params))));
+    group.finish();
+}
+
+criterion_group!(benches, find_policy_relationships);
+criterion_main!(benches);
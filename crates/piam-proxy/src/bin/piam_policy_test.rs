@@ -0,0 +1,59 @@
+//! Runs policy test cases against a config, for a policy repository to check its policies in CI.
+//!
+//! ```sh
+//! piam-policy-test <cases.yaml> <config.yaml>
+//! ```
+//!
+//! `config.yaml` is the core config as served by piam manager in a snapshot, with object storage
+//! policies. Exits with 1 if any case mismatches and with 2 if the inputs can not be loaded.
+
+use std::{process::ExitCode, sync::Arc};
+
+use piam_object_storage::{
+    config::POLICY_MODEL as OBJECT_STORAGE, input::ObjectStorageInput, policy::ObjectStoragePolicy,
+};
+use piam_proxy::{
+    config::{CoreConfig, POLICY_MODEL},
+    container::IamContainer,
+    error::{ProxyError, ProxyResult},
+    policy_case::{load_cases, run_cases, PolicyCaseReport},
+    state::CoreState,
+};
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    let [_, cases_path, config_path] = args.as_slice() else {
+        eprintln!("usage: piam-policy-test <cases.yaml> <config.yaml>");
+        return ExitCode::from(2);
+    };
+    match run(cases_path, config_path) {
+        Ok(report) => {
+            print!("{report}");
+            match report.mismatches.is_empty() {
+                true => ExitCode::SUCCESS,
+                false => ExitCode::FAILURE,
+            }
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn run(cases_path: &str, config_path: &str) -> ProxyResult<PolicyCaseReport> {
+    POLICY_MODEL.store(Arc::new(OBJECT_STORAGE));
+    let config: CoreConfig<ObjectStoragePolicy> = serde_yaml::from_str(&read(config_path)?)
+        .map_err(|e| ProxyError::Deserialize(format!("invalid config '{config_path}': {e}")))?;
+    for lint in config.lint() {
+        eprintln!("{lint}");
+    }
+    let container = IamContainer::new_from(config)?;
+    let cases = load_cases::<ObjectStorageInput>(&read(cases_path)?)?;
+    Ok(run_cases(&container, &cases))
+}
+
+fn read(path: &str) -> ProxyResult<String> {
+    std::fs::read_to_string(path)
+        .map_err(|e| ProxyError::OtherInternal(format!("failed to read '{path}': {e}")))
+}
@@ -0,0 +1,439 @@
+//! Address of the client that sent the request.
+//!
+//! Requests coming through a gateway all have the gateway as their peer. If the peer is a trusted
+//! proxy, the client address is taken from `TRUSTED_FORWARDED_HEADER` instead, which must be the
+//! header the gateway appends to, the other one is passed through as sent by the client. Headers
+//! must be read before `sign_with_aws_sigv4_params`, which removes `x-forwarded-for`.
+//!
+//! Layer 4 load balancers send no headers, with `TRUSTED_PROXY_PROTOCOL` the address is taken from
+//! the PROXY protocol (v1 or v2) header they send at the start of each connection instead, see
+//! [`TrustedProxies::accept`].
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use busylib::prelude::EnhancedExpect;
+use cidr::AnyIpCidr;
+use http::HeaderMap;
+use once_cell::sync::Lazy;
+use piam_core::{condition::input::Condition, policy::condition::canonical_ip};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{
+    config::{TRUSTED_FORWARDED_HEADER, TRUSTED_PROXIES, TRUSTED_PROXY_PROTOCOL},
+    error::{ProxyError, ProxyResult},
+};
+
+/// Starts a PROXY protocol v2 header
+const PROXY_PROTOCOL_V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest PROXY protocol v1 header line, including `\r\n`
+const PROXY_PROTOCOL_V1_MAX_LEN: usize = 107;
+
+/// Parsed once from the env, which is validated by `set_constants`
+pub static TRUSTED: Lazy<TrustedProxies> = Lazy::new(|| {
+    TrustedProxies::from_env().ex("TRUSTED_PROXIES should be validated by set_constants")
+});
+
+/// The header trusted proxies append the address of their peer to
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ForwardedHeader {
+    #[default]
+    XForwardedFor,
+    /// RFC 7239
+    Forwarded,
+}
+
+impl ForwardedHeader {
+    /// `x-forwarded-for` or `forwarded`
+    pub fn parse(s: &str) -> ProxyResult<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "x-forwarded-for" => Ok(Self::XForwardedFor),
+            "forwarded" => Ok(Self::Forwarded),
+            _ => Err(ProxyError::InvalidConfig(format!(
+                "invalid TRUSTED_FORWARDED_HEADER '{s}': expect x-forwarded-for or forwarded"
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies {
+    cidrs: Vec<AnyIpCidr>,
+    header: ForwardedHeader,
+    proxy_protocol: bool,
+}
+
+impl TrustedProxies {
+    /// Comma separated cidrs, e.g. `10.0.0.0/8, fd00::/8`
+    pub fn parse(s: &str) -> ProxyResult<Self> {
+        let cidrs = s
+            .split(',')
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .map(|c| {
+                c.parse::<AnyIpCidr>().map_err(|e| {
+                    ProxyError::InvalidConfig(format!("invalid trusted proxy cidr '{c}': {e}"))
+                })
+            })
+            .collect::<ProxyResult<_>>()?;
+        Ok(Self {
+            cidrs,
+            ..Default::default()
+        })
+    }
+
+    pub fn header(self, header: ForwardedHeader) -> Self {
+        Self { header, ..self }
+    }
+
+    /// Whether trusted peers send a PROXY protocol header first, see [`TrustedProxies::accept`]
+    pub fn proxy_protocol(self, proxy_protocol: bool) -> Self {
+        Self {
+            proxy_protocol,
+            ..self
+        }
+    }
+
+    /// Parses the env on every call, use [`TRUSTED`] instead
+    pub fn from_env() -> ProxyResult<Self> {
+        let header = ForwardedHeader::parse(&TRUSTED_FORWARDED_HEADER.load())?;
+        let proxy_protocol = match TRUSTED_PROXY_PROTOCOL.load().trim() {
+            "true" => true,
+            "false" => false,
+            other => {
+                return Err(ProxyError::InvalidConfig(format!(
+                    "invalid TRUSTED_PROXY_PROTOCOL '{other}': expect true or false"
+                )))
+            }
+        };
+        Ok(Self::parse(&TRUSTED_PROXIES.load())?
+            .header(header)
+            .proxy_protocol(proxy_protocol))
+    }
+
+    /// To be called by listeners on each accepted connection before reading anything else from
+    /// it. With PROXY protocol enabled, the header sent by a trusted peer is consumed and the
+    /// source address in it returned, the peer itself for `UNKNOWN` or `LOCAL`. Untrusted peers
+    /// are never read from, so that they can not claim any address.
+    pub async fn accept<S: AsyncRead + Unpin>(
+        &self,
+        peer: SocketAddr,
+        stream: &mut S,
+    ) -> ProxyResult<SocketAddr> {
+        if !self.proxy_protocol || !self.is_trusted(peer.ip()) {
+            return Ok(peer);
+        }
+        Ok(read_proxy_protocol(stream).await?.unwrap_or(peer))
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        let ip = canonical_ip(ip);
+        self.cidrs.iter().any(|cidr| cidr.contains(&ip))
+    }
+
+    /// Walks the forwarded chain from the nearest hop, the first address that is not a trusted
+    /// proxy is the client. Headers from an untrusted peer are ignored since they can be forged.
+    /// Forwarded addresses without port get port 0.
+    pub fn client_addr(&self, peer: SocketAddr, headers: &HeaderMap) -> SocketAddr {
+        if !self.is_trusted(peer.ip()) {
+            return peer;
+        }
+        let mut client = peer;
+        for hop in forwarded_chain(headers, self.header).into_iter().rev() {
+            match hop {
+                // unknown or obfuscated, the chain can not be followed any further
+                None => break,
+                Some(addr) => {
+                    client = addr;
+                    if !self.is_trusted(addr.ip()) {
+                        break;
+                    }
+                }
+            }
+        }
+        client
+    }
+
+    /// `ConditionCtx.from` of the request
+    pub fn condition_from(&self, peer: SocketAddr, headers: &HeaderMap) -> Condition {
+        Condition::new_with_addr(self.client_addr(peer, headers))
+    }
+}
+
+/// Addresses in `header` from the farthest hop to the nearest one, the other header is never read
+/// since it is not appended to by the trusted proxies
+fn forwarded_chain(headers: &HeaderMap, header: ForwardedHeader) -> Vec<Option<SocketAddr>> {
+    let values = |name: &str| -> Vec<String> {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|v| v.trim().to_string())
+            .collect()
+    };
+    match header {
+        ForwardedHeader::Forwarded => values("forwarded")
+            .iter()
+            .map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    key.eq_ignore_ascii_case("for").then(|| parse_node(value))
+                })?
+            })
+            .collect(),
+        ForwardedHeader::XForwardedFor => values("x-forwarded-for")
+            .iter()
+            .map(|node| parse_node(node))
+            .collect(),
+    }
+}
+
+/// Reads exactly the PROXY protocol v1 or v2 header from the start of `stream`, see
+/// [`parse_proxy_protocol_v1`] and [`parse_proxy_protocol_v2`]
+pub async fn read_proxy_protocol<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> ProxyResult<Option<SocketAddr>> {
+    let read_failed =
+        |e: std::io::Error| ProxyError::MalformedProtocol(format!("reading PROXY header: {e}"));
+    // shorter than any header of either version
+    let mut header = vec![0; PROXY_PROTOCOL_V2_SIGNATURE.len()];
+    stream.read_exact(&mut header).await.map_err(read_failed)?;
+    if header == PROXY_PROTOCOL_V2_SIGNATURE {
+        let mut fixed = [0; 4];
+        stream.read_exact(&mut fixed).await.map_err(read_failed)?;
+        let len = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+        header.extend(fixed);
+        let start = header.len();
+        header.resize(start + len, 0);
+        stream
+            .read_exact(&mut header[start..])
+            .await
+            .map_err(read_failed)?;
+        return parse_proxy_protocol_v2(&header);
+    }
+    if !header.starts_with(b"PROXY ") {
+        return Err(ProxyError::MalformedProtocol(
+            "PROXY header expected from trusted proxy".to_string(),
+        ));
+    }
+    // byte by byte, so that nothing after the line is consumed
+    while !header.ends_with(b"\r\n") {
+        if header.len() >= PROXY_PROTOCOL_V1_MAX_LEN {
+            return Err(ProxyError::MalformedProtocol(
+                "PROXY header line too long".to_string(),
+            ));
+        }
+        header.push(stream.read_u8().await.map_err(read_failed)?);
+    }
+    let line = String::from_utf8(header)
+        .map_err(|_| ProxyError::MalformedProtocol("PROXY header is not ASCII".to_string()))?;
+    parse_proxy_protocol_v1(&line)
+}
+
+/// Parses a PROXY protocol v1 header line sent by layer 4 load balancers.
+///
+/// e.g. `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`, the returned source address should
+/// be used as the peer address. [`None`] is returned for `PROXY UNKNOWN`.
+pub fn parse_proxy_protocol_v1(line: &str) -> ProxyResult<Option<SocketAddr>> {
+    let malformed = || ProxyError::MalformedProtocol(format!("invalid PROXY header: {line:?}"));
+    let line = line.strip_suffix("\r\n").ok_or_else(malformed)?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse().map_err(|_| malformed())?;
+            let port: u16 = src_port.parse().map_err(|_| malformed())?;
+            if ip.is_ipv4() != (protocol == "TCP4") {
+                return Err(malformed());
+            }
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(malformed()),
+    }
+}
+
+/// Parses a whole PROXY protocol v2 header, TLVs after the addresses are ignored.
+///
+/// [`None`] is returned for the `LOCAL` command, e.g. health checks of the load balancer, and for
+/// address families other than IPv4 and IPv6.
+pub fn parse_proxy_protocol_v2(header: &[u8]) -> ProxyResult<Option<SocketAddr>> {
+    let malformed = || ProxyError::MalformedProtocol("invalid PROXY v2 header".to_string());
+    if header.len() < 16 || header[..12] != PROXY_PROTOCOL_V2_SIGNATURE {
+        return Err(malformed());
+    }
+    let (version_command, family) = (header[12], header[13]);
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+    let addresses = header.get(16..16 + len).ok_or_else(malformed)?;
+    if version_command >> 4 != 2 {
+        return Err(malformed());
+    }
+    match version_command & 0x0f {
+        // LOCAL
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        _ => return Err(malformed()),
+    }
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    match family >> 4 {
+        // AF_INET: source, destination, source port, destination port
+        1 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into().map_err(|_| malformed())?;
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(8))))
+        }
+        // AF_INET6
+        2 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().map_err(|_| malformed())?;
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32))))
+        }
+        1 | 2 => Err(malformed()),
+        // AF_UNSPEC or AF_UNIX
+        _ => Ok(None),
+    }
+}
+
+/// `192.0.2.1`, `192.0.2.1:4711`, `2001:db8::1`, `"[2001:db8::1]:4711"`
+fn parse_node(node: &str) -> Option<SocketAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    let ip = node.trim_start_matches('[').trim_end_matches(']');
+    ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use http::HeaderMap;
+
+    use crate::client_addr::{
+        parse_proxy_protocol_v1, parse_proxy_protocol_v2, ForwardedHeader, TrustedProxies,
+        PROXY_PROTOCOL_V2_SIGNATURE,
+    };
+
+    #[test]
+    fn client_addr_behind_trusted_proxies() {
+        let trusted = TrustedProxies::parse("10.0.0.0/8, fd00::/8").unwrap();
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.append(*name, value.parse().unwrap());
+            }
+            headers
+        };
+        let gateway = addr("10.0.0.2:443");
+
+        let xff = headers(&[("x-forwarded-for", "1.1.1.1, 2.2.2.2, 10.0.0.3")]);
+        assert_eq!(trusted.client_addr(gateway, &xff), addr("2.2.2.2:0"));
+        // not behind a trusted proxy, the header may be forged
+        assert_eq!(
+            trusted.client_addr(addr("3.3.3.3:80"), &xff),
+            addr("3.3.3.3:80")
+        );
+        // dual-stack socket
+        assert_eq!(
+            trusted.client_addr(addr("[::ffff:10.0.0.2]:443"), &xff),
+            addr("2.2.2.2:0")
+        );
+
+        let forwarded = headers(&[
+            ("forwarded", r#"for="[2001:db8::1]:4711";proto=https"#),
+            ("forwarded", "for=[fd00::1]"),
+            ("x-forwarded-for", "1.1.1.1"),
+        ]);
+        // sent by the client, not appended by the gateway
+        assert_eq!(trusted.client_addr(gateway, &forwarded), addr("1.1.1.1:0"));
+        let spoofed = headers(&[("forwarded", "for=1.1.1.1")]);
+        assert_eq!(trusted.client_addr(gateway, &spoofed), gateway);
+
+        let trusted = trusted.header(ForwardedHeader::Forwarded);
+        assert_eq!(
+            trusted.client_addr(gateway, &forwarded),
+            addr("[2001:db8::1]:4711")
+        );
+        assert_eq!(trusted.client_addr(gateway, &xff), gateway);
+
+        let unknown = headers(&[("forwarded", "for=1.1.1.1, for=unknown, for=10.0.0.3")]);
+        assert_eq!(trusted.client_addr(gateway, &unknown), addr("10.0.0.3:0"));
+
+        assert_eq!(
+            ForwardedHeader::parse("X-Forwarded-For").unwrap(),
+            ForwardedHeader::XForwardedFor
+        );
+        assert!(ForwardedHeader::parse("x-real-ip").is_err());
+    }
+
+    #[test]
+    fn parse_proxy_protocol() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        assert_eq!(
+            parse_proxy_protocol_v1("PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").unwrap(),
+            Some(addr("192.0.2.1:56324"))
+        );
+        assert_eq!(parse_proxy_protocol_v1("PROXY UNKNOWN\r\n").unwrap(), None);
+        assert!(parse_proxy_protocol_v1("PROXY TCP6 192.0.2.1 ::1 1 2\r\n").is_err());
+
+        let v2 = |command: u8, family: u8, addresses: &[u8]| {
+            let mut header = PROXY_PROTOCOL_V2_SIGNATURE.to_vec();
+            header.extend([0x20 | command, family]);
+            header.extend((addresses.len() as u16).to_be_bytes());
+            header.extend(addresses);
+            header
+        };
+        let tcp4 = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        assert_eq!(
+            parse_proxy_protocol_v2(&v2(1, 0x11, &tcp4)).unwrap(),
+            Some(addr("192.0.2.1:56324"))
+        );
+        let mut tcp6 = [0; 36];
+        tcp6[..16].copy_from_slice(
+            &"2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        tcp6[32..34].copy_from_slice(&4711u16.to_be_bytes());
+        assert_eq!(
+            parse_proxy_protocol_v2(&v2(1, 0x21, &tcp6)).unwrap(),
+            Some(addr("[2001:db8::1]:4711"))
+        );
+        // health check of the load balancer
+        assert_eq!(parse_proxy_protocol_v2(&v2(0, 0x00, &[])).unwrap(), None);
+        assert!(parse_proxy_protocol_v2(&v2(1, 0x11, &tcp4[..8])).is_err());
+    }
+
+    #[tokio::test]
+    async fn accept_proxy_protocol_from_trusted_peers() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        let trusted = TrustedProxies::parse("10.0.0.0/8")
+            .unwrap()
+            .proxy_protocol(true);
+        let balancer = addr("10.0.0.2:443");
+
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 10.0.0.2 56324 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(
+            trusted.accept(balancer, &mut stream).await.unwrap(),
+            addr("192.0.2.1:56324")
+        );
+        // the rest of the connection is left to be served
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+
+        // an untrusted peer is never read from
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 10.0.0.2 56324 443\r\n";
+        let peer = addr("3.3.3.3:80");
+        assert_eq!(trusted.accept(peer, &mut stream).await.unwrap(), peer);
+        assert_eq!(stream.len(), 41);
+
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\n";
+        assert!(trusted.accept(balancer, &mut stream).await.is_err());
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\n";
+        let disabled = trusted.proxy_protocol(false);
+        assert_eq!(
+            disabled.accept(balancer, &mut stream).await.unwrap(),
+            balancer
+        );
+    }
+}
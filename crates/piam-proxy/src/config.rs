@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 
 use arc_swap::ArcSwap;
 use busylib::config::{dev_mode, env_var_with_default, GlobalStaticStr, GlobalString};
@@ -6,10 +6,28 @@ use log::{error, info};
 use once_cell::sync::Lazy;
 use piam_core::{
     account::aws::AwsAccount,
+    endpoint::Endpoint,
     group::Group,
+    lint::{
+        lint_account_codes, lint_duplicate_ids, lint_endpoints, lint_group_parent_relationships,
+        lint_policy_relationships, lint_user_group_relationships, lint_user_role_relationships,
+        KnownIds, Lint,
+    },
+    manager_api_constant::*,
     policy::{condition::ConditionPolicy, Modeled, Policy},
-    principal::User,
-    relation_model::{PolicyRelationship, UserGroupRelationship},
+    principal::{Role, User},
+    relation_model::{
+        GroupParentRelationship, PolicyRelationship, UserGroupRelationship, UserRoleRelationship,
+    },
+    IamIdentity,
+};
+use serde::Deserialize;
+
+use crate::{
+    client_addr::TrustedProxies,
+    health::staleness_threshold,
+    tls::{tls_port, CertResolver},
+    upstream::default_roots,
 };
 
 pub static PROXY_TYPE: GlobalStaticStr = Lazy::new(|| ArcSwap::from_pointee(UNSET));
@@ -19,19 +37,132 @@ pub static PROXY_REGION: GlobalString = GlobalString::new(|| env_var_with_defaul
 pub static PROXY_ENV: GlobalString = GlobalString::new(|| env_var_with_default("ENV", UNSET));
 pub static PIAM_MANAGER_ADDRESS: GlobalString =
     GlobalString::new(|| env_var_with_default("PIAM_MANAGER_ADDRESS", "http://localhost:8080"));
+/// Bearer token of this proxy for the manager API, issued by `piam-manager issue-token`
+pub static PIAM_MANAGER_TOKEN: GlobalString =
+    GlobalString::new(|| env_var_with_default("PIAM_MANAGER_TOKEN", ""));
+/// File caching the last config loaded from the manager, empty to disable, see `SnapshotCache`
+pub static SNAPSHOT_CACHE_PATH: GlobalString =
+    GlobalString::new(|| env_var_with_default("SNAPSHOT_CACHE_PATH", ""));
+/// Seconds after which a config not confirmed up to date with the manager makes the proxy unready
+pub static STATE_STALENESS_THRESHOLD: GlobalString =
+    GlobalString::new(|| env_var_with_default("STATE_STALENESS_THRESHOLD", "300"));
+/// Port serving the Prometheus metrics, see `metrics::serve`
+pub static METRICS_PORT: GlobalString =
+    GlobalString::new(|| env_var_with_default("METRICS_PORT", "9090"));
+/// Base url of the OTLP/HTTP collector to export traces to, e.g. `http://localhost:4318`, empty to
+/// disable, see `Telemetry`
+pub static OTLP_ENDPOINT: GlobalString =
+    GlobalString::new(|| env_var_with_default("OTLP_ENDPOINT", ""));
+/// PEM of CA certificates trusted for upstream besides the system ones, see `UpstreamClients`
+pub static UPSTREAM_CA_PATH: GlobalString =
+    GlobalString::new(|| env_var_with_default("UPSTREAM_CA_PATH", ""));
+/// Comma separated `cert.pem:key.pem` pairs to terminate TLS with, empty to serve plain HTTP only,
+/// see `CertResolver`
+pub static TLS_CERTS: GlobalString = GlobalString::new(|| env_var_with_default("TLS_CERTS", ""));
+/// Port serving TLS if `TLS_CERTS` is set, see `tls::serve`
+pub static TLS_PORT: GlobalString = GlobalString::new(|| env_var_with_default("TLS_PORT", "443"));
+/// Comma separated cidrs of gateways whose forwarded headers are trusted, see `TrustedProxies`
+pub static TRUSTED_PROXIES: GlobalString =
+    GlobalString::new(|| env_var_with_default("TRUSTED_PROXIES", ""));
+/// `x-forwarded-for` or `forwarded`, the one header the trusted proxies append to
+pub static TRUSTED_FORWARDED_HEADER: GlobalString =
+    GlobalString::new(|| env_var_with_default("TRUSTED_FORWARDED_HEADER", "x-forwarded-for"));
+/// `true` if the trusted proxies are layer 4 load balancers sending a PROXY protocol header on
+/// each connection, see `TrustedProxies::accept`
+pub static TRUSTED_PROXY_PROTOCOL: GlobalString =
+    GlobalString::new(|| env_var_with_default("TRUSTED_PROXY_PROTOCOL", "false"));
+/// Key signing the session tokens of `AssumeRole`, shared by the proxies accepting them.
+/// Sessions are not supported if empty, see `sts`
+pub static STS_SIGNING_KEY: GlobalString =
+    GlobalString::new(|| env_var_with_default("STS_SIGNING_KEY", ""));
 
 pub const UNSET: &str = "Unset";
 pub const STATE_UPDATE_INTERVAL: u64 = 10;
+/// Seconds of a long poll watching the config revision, see `StateManager::run`
+pub const STATE_WATCH_WAIT: u64 = 50;
+/// Seconds between checks of the TLS certificate files, see `CertResolver::watch`
+pub const TLS_RELOAD_INTERVAL: u64 = 30;
+/// Seconds a TLS client may take to finish the handshake, see `tls::serve_on`
+pub const TLS_HANDSHAKE_TIMEOUT: u64 = 10;
+/// Seconds a TLS client may take to send the headers of a request, see `tls::serve_on`
+pub const TLS_HEADER_READ_TIMEOUT: u64 = 30;
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Deserialize)]
 pub struct CoreConfig<P: Modeled> {
     pub accounts: Vec<AwsAccount>,
     pub users: Vec<User>,
     pub groups: Vec<Group>,
+    #[serde(default)]
+    pub roles: Vec<Role>,
     pub user_input_policies: Vec<Policy<P>>,
     pub condition_policies: Vec<Policy<ConditionPolicy>>,
     pub user_group_relationships: Vec<UserGroupRelationship>,
+    #[serde(default)]
+    pub group_parent_relationships: Vec<GroupParentRelationship>,
+    #[serde(default)]
+    pub user_role_relationships: Vec<UserRoleRelationship>,
     pub policy_relationships: Vec<PolicyRelationship>,
+    /// Replace or add to the built-in ones, see `EndpointRegistry`
+    #[serde(default)]
+    pub endpoints: Vec<Endpoint>,
+}
+
+impl<P: Modeled> CoreConfig<P> {
+    /// Static analysis of the whole config, locations are named after manager api paths
+    pub fn lint(&self) -> Vec<Lint> {
+        let policy_model: &str = &POLICY_MODEL.load();
+        let user_input_path = policies_path(policy_model);
+        let condition_path = policies_path(CONDITION);
+
+        let mut lints = lint_duplicate_ids(&self.accounts, ACCOUNTS);
+        lints.extend(lint_account_codes(&self.accounts, ACCOUNTS));
+        lints.extend(lint_duplicate_ids(&self.users, USERS));
+        lints.extend(lint_duplicate_ids(&self.groups, GROUPS));
+        lints.extend(lint_duplicate_ids(&self.roles, ROLES));
+        lints.extend(Policy::lint_all(
+            &self.user_input_policies,
+            &user_input_path,
+        ));
+        lints.extend(Policy::lint_all(&self.condition_policies, &condition_path));
+
+        let known = KnownIds {
+            accounts: ids(&self.accounts),
+            users: ids(&self.users),
+            groups: ids(&self.groups),
+            roles: ids(&self.roles),
+            policies: [
+                (policy_model, ids(&self.user_input_policies)),
+                (CONDITION, ids(&self.condition_policies)),
+            ]
+            .into(),
+        };
+        lints.extend(lint_user_group_relationships(
+            &self.user_group_relationships,
+            &known,
+            USER_GROUP_RELATIONSHIPS,
+        ));
+        lints.extend(lint_group_parent_relationships(
+            &self.group_parent_relationships,
+            &known,
+            GROUP_PARENT_RELATIONSHIPS,
+        ));
+        lints.extend(lint_user_role_relationships(
+            &self.user_role_relationships,
+            &known,
+            USER_ROLE_RELATIONSHIPS,
+        ));
+        lints.extend(lint_policy_relationships(
+            &self.policy_relationships,
+            &known,
+            POLICY_RELATIONSHIPS,
+        ));
+        lints.extend(lint_endpoints(&self.endpoints, &known, ENDPOINTS));
+        lints
+    }
+}
+
+fn ids<T: IamIdentity>(items: &[T]) -> HashSet<&str> {
+    items.iter().map(|item| item.id_str()).collect()
 }
 
 pub fn set_constants(
@@ -64,6 +195,44 @@ pub fn set_constants(
     info!("PROXY_REGION: {}", PROXY_REGION.load());
     info!("PROXY_ENV: {}", PROXY_ENV.load());
     info!("PIAM_MANAGER_ADDRESS: {}", PIAM_MANAGER_ADDRESS.load());
+
+    if let Err(e) = TrustedProxies::from_env() {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    info!("TRUSTED_PROXIES: {}", TRUSTED_PROXIES.load());
+    info!(
+        "TRUSTED_FORWARDED_HEADER: {}",
+        TRUSTED_FORWARDED_HEADER.load()
+    );
+    info!("TRUSTED_PROXY_PROTOCOL: {}", TRUSTED_PROXY_PROTOCOL.load());
+
+    if let Err(e) = staleness_threshold() {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    info!(
+        "STATE_STALENESS_THRESHOLD: {}",
+        STATE_STALENESS_THRESHOLD.load()
+    );
+
+    if let Err(e) = default_roots() {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = CertResolver::from_env().and_then(|_| tls_port()) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    info!(
+        "STS_SIGNING_KEY: {}",
+        match STS_SIGNING_KEY.load().is_empty() {
+            true => "unset",
+            false => "set",
+        }
+    );
+    info!("TLS_CERTS: {}", TLS_CERTS.load());
+    info!("TLS_PORT: {}", TLS_PORT.load());
 }
 
 #[inline]
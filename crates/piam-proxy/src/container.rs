@@ -6,22 +6,32 @@ use std::{
     fmt::{Debug, Display, Formatter},
 };
 
-use busylib::{prelude::EnhancedUnwrap, ANY};
+use busylib::ANY;
+use log::{debug, warn};
 use piam_core::{
     account::aws::AwsAccount,
+    condition::input::ConditionCtx,
+    effect::Effect,
+    endpoint::Endpoint,
     group::{Group, GroupId},
+    lint::LintKind,
     manager_api_constant::CONDITION,
     policy::{condition::ConditionPolicy, Modeled, Policy, PolicyId},
-    principal::{Role, User, UserId},
+    principal::{Role, RoleId, User, UserId, UserKind},
     relation_model::PolicyRelationship,
     IamIdentity,
 };
 use serde::de::DeserializeOwned;
+use tracing::{instrument, Span};
 
 use crate::{
     config::{CoreConfig, POLICY_MODEL},
+    endpoint::EndpointRegistry,
     error::{ProxyError, ProxyResult},
+    policy_index::PolicyRelationshipIndex,
+    resolution_cache::{ResolutionCache, ResolutionKey, ResolvedPolicyIds},
     state::CoreState,
+    sts::SessionClaims,
 };
 
 /// IamContainer store entities.
@@ -33,36 +43,44 @@ pub struct IamContainer<P: Modeled> {
     users: HashMap<UserId, User>,
     /// All groups, each one is unique
     groups: HashMap<GroupId, Group>,
+    /// All roles, each one is unique
+    roles: HashMap<RoleId, Role>,
     /// Policies for condition, each one is unique
     condition_policies: HashMap<PolicyId, Policy<ConditionPolicy>>,
     /// Policies for user input, each one is unique
     user_input_policies: HashMap<PolicyId, Policy<P>>,
     /// In-memory index built from all `AccessKeyUserRelationship`s
     base_access_key_to_user_id: HashMap<String, UserId>,
-    /// In-memory index built from all `UserGroupRelationship`s
+    /// In-memory index built from all `UserGroupRelationship`s and `GroupParentRelationship`s,
+    /// direct groups first and then their ancestors
     user_id_to_group_ids: HashMap<UserId, Vec<GroupId>>,
-    /// Relationships of policies and other items
-    policy_relationships: Vec<PolicyRelationship>,
-    // TODO: Use index from all `PolicyRelatedItems`s to speed up policy querying
-    // join IamEntityIds and stuff as hash key of policy relation id
+    /// In-memory index built from all `UserRoleRelationship`s
+    user_id_to_role_ids: HashMap<UserId, Vec<RoleId>>,
+    /// In-memory index built from all `PolicyRelationship`s
+    policy_relationships: PolicyRelationshipIndex,
+    /// Policies found for principals, only valid for the entities above
+    resolution_cache: ResolutionCache,
+    /// Upstream endpoints by account and region
+    endpoints: EndpointRegistry,
 }
 
 /// Struct to use when querying policies from the container.
 #[derive(Debug)]
 pub struct PolicyFilterParams<'a> {
-    roles: Option<&'a Vec<&'a Role>>,
-    user: Option<&'a User>,
-    groups: Option<&'a Vec<&'a Group>>,
-    account: &'a AwsAccount,
-    target_region: &'a str,
+    pub(crate) roles: Option<&'a Vec<&'a Role>>,
+    pub(crate) user: Option<&'a User>,
+    pub(crate) groups: Option<&'a Vec<&'a Group>>,
+    pub(crate) user_kind: Option<&'a UserKind>,
+    pub(crate) account: &'a AwsAccount,
+    pub(crate) target_region: &'a str,
 }
 
 impl<'a> Display for PolicyFilterParams<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "PolicyFilterParams account: {:?} target_region: {:?} group: {:?}",
-            self.account, self.target_region, self.groups
+            "PolicyFilterParams account: {:?} target_region: {:?} group: {:?} user_kind: {:?}",
+            self.account, self.target_region, self.groups, self.user_kind
         )
     }
 }
@@ -75,6 +93,7 @@ impl<'a> PolicyFilterParams<'a> {
             roles: None,
             user: None,
             groups: None,
+            user_kind: None,
         }
     }
 
@@ -93,6 +112,11 @@ impl<'a> PolicyFilterParams<'a> {
         self
     }
 
+    pub const fn user_kind(mut self, user_kind: &'a UserKind) -> Self {
+        self.user_kind = Some(user_kind);
+        self
+    }
+
     pub const fn account(mut self, account: &'a AwsAccount) -> Self {
         self.account = account;
         self
@@ -102,16 +126,106 @@ impl<'a> PolicyFilterParams<'a> {
         self.target_region = target_region;
         self
     }
+
+    /// Every principal set on the relationship must be one of the filter, e.g. a relationship of
+    /// both a user and a group only matches a filter of that user and of groups including that
+    /// group. Relationships bound to no user, group or role only match a filter of a user kind
+    /// only. A filter without any principal matches relationships of every principal.
+    pub fn matches(&self, r: &PolicyRelationship) -> bool {
+        let account_matched = filter_one(Some(&self.account.id), Some(&r.account_id))
+            && filter_one(Some(self.target_region), Some(&r.region));
+        if !account_matched {
+            return false;
+        }
+        if self.user.is_none()
+            && self.groups.is_none()
+            && self.roles.is_none()
+            && self.user_kind.is_none()
+        {
+            return true;
+        }
+
+        let user_id = self.user.map(|u| u.id_str());
+        let group_ids = self.groups.map(|v| v.iter().map(|g| g.id_str()));
+        let role_ids = self.roles.map(|v| v.iter().map(|r| r.id_str()));
+        let principals_matched = filter_principal(user_id.into_iter(), r.user_id.as_deref())
+            && filter_principal(group_ids.into_iter().flatten(), r.group_id.as_deref())
+            && filter_principal(role_ids.into_iter().flatten(), r.role_id.as_deref())
+            && filter_user_kind(r.user_kind.as_ref(), self.user_kind);
+
+        let bound = r.user_id.is_some() || r.group_id.is_some() || r.role_id.is_some();
+        let principal_filtered =
+            self.user.is_some() || self.groups.is_some() || self.roles.is_some();
+        principals_matched && bound == principal_filtered
+    }
+
+    /// Order of the relationships matched by the filter: the ones of the user, then the ones of
+    /// groups or roles, see [`FoundPolicies`]
+    const fn precedence(&self, r: &PolicyRelationship) -> u8 {
+        match (&r.user_id, &r.group_id, &r.role_id) {
+            (Some(_), _, _) => 0,
+            (None, None, None) => 2,
+            _ => 1,
+        }
+    }
 }
 
+/// Policies ordered by the precedence of the principal they are bound to.
+///
+/// The user comes first, then its groups (direct ones before inherited ones) and then its kind.
+/// A session of an assumed role only gets the policies of the role.
+///
+/// Precedence does not affect the decision, any deny effect denies the request and otherwise any
+/// allow effect allows it. It only decides which one applies when effects set something only one
+/// of them can, such as `rate_limit`: the first one found wins, see
+/// [`piam_core::effect::RateLimit::find_or_default`].
 #[derive(Debug)]
 pub struct FoundPolicies<'a, P: Modeled> {
     pub condition: Vec<&'a Policy<ConditionPolicy>>,
     pub user_input: Vec<&'a Policy<P>>,
 }
 
+impl<'a, P: Modeled> FoundPolicies<'a, P> {
+    /// Effects of the condition policies found for `condition_ctx`, in the order of precedence
+    pub fn condition_effects(&self, condition_ctx: &ConditionCtx) -> ProxyResult<Vec<&'a Effect>> {
+        let mut effects: Vec<&Effect> = Vec::new();
+        for policy in &self.condition {
+            for effect in policy.find_effects(condition_ctx)? {
+                if !effects.contains(&effect) {
+                    effects.push(effect);
+                }
+            }
+        }
+        Ok(effects)
+    }
+}
+
 impl<P: Modeled + DeserializeOwned + Send> CoreState<CoreConfig<P>> for IamContainer<P> {
     fn new_from(config: CoreConfig<P>) -> ProxyResult<Self> {
+        let lints = config.lint();
+        for lint in &lints {
+            warn!("policy lint: {}", lint);
+        }
+        // reject the whole config, so that the state in use is kept
+        let invalid: Vec<String> = lints
+            .iter()
+            .filter(|lint| {
+                matches!(
+                    lint.kind,
+                    LintKind::DanglingRelationship
+                        | LintKind::GroupCycle
+                        | LintKind::ConflictingRule
+                )
+            })
+            .map(ToString::to_string)
+            .collect();
+        if !invalid.is_empty() {
+            return Err(ProxyError::InvalidConfig(format!(
+                "invalid config found: {}",
+                invalid.join("; ")
+            )));
+        }
+
         let accounts = config
             .accounts
             .into_iter()
@@ -128,6 +242,11 @@ impl<P: Modeled + DeserializeOwned + Send> CoreState<CoreConfig<P>> for IamConta
             .into_iter()
             .map(|group| (group.id.clone(), group))
             .collect();
+        let roles = config
+            .roles
+            .into_iter()
+            .map(|role| (role.id.clone(), role))
+            .collect();
         let user_input_policies = config
             .user_input_policies
             .into_iter()
@@ -180,15 +299,58 @@ impl<P: Modeled + DeserializeOwned + Send> CoreState<CoreConfig<P>> for IamConta
             }
         }
 
+        // members of a group are members of all of its ancestors
+        let mut group_id_to_parent_ids: HashMap<GroupId, Vec<GroupId>> = HashMap::default();
+        for rel in config.group_parent_relationships {
+            group_id_to_parent_ids
+                .entry(rel.group_id)
+                .or_default()
+                .push(rel.parent_group_id);
+        }
+        for group_ids in user_id_to_group_ids.values_mut() {
+            let mut i = 0;
+            while i < group_ids.len() {
+                let parents = group_id_to_parent_ids.get(&group_ids[i]);
+                for parent in parents.into_iter().flatten() {
+                    // ancestors may be dropped by prefilter
+                    if groups.contains_key(parent) && !group_ids.contains(parent) {
+                        group_ids.push(parent.clone());
+                    }
+                }
+                i += 1;
+            }
+        }
+
+        let mut user_id_to_role_ids: HashMap<UserId, Vec<RoleId>> = HashMap::default();
+        for rel in config.user_role_relationships {
+            user_id_to_role_ids
+                .entry(rel.user_id)
+                .or_default()
+                .push(rel.role_id);
+        }
+
+        let endpoints = EndpointRegistry::new(config.endpoints);
+        let regions = policy_relationships
+            .iter()
+            .map(|rel| rel.region.as_str())
+            .filter(|region| *region != ANY)
+            .chain(endpoints.regions())
+            .map(String::from)
+            .collect();
+
         Ok(Self {
             accounts,
             users,
             groups,
+            roles,
             condition_policies,
             user_input_policies,
             base_access_key_to_user_id,
             user_id_to_group_ids,
-            policy_relationships,
+            user_id_to_role_ids,
+            policy_relationships: PolicyRelationshipIndex::new(policy_relationships),
+            resolution_cache: ResolutionCache::new(regions),
+            endpoints,
         })
     }
 }
@@ -286,6 +448,35 @@ pub mod prefilter {
 }
 
 impl<P: Modeled> IamContainer<P> {
+    /// Number of entities by kind, relationships of users are counted after resolving the
+    /// ancestors of their groups
+    pub fn sizes(&self) -> [(&'static str, usize); 10] {
+        let count = |index: &HashMap<UserId, Vec<String>>| index.values().map(Vec::len).sum();
+        [
+            ("accounts", self.accounts.len()),
+            ("users", self.users.len()),
+            ("groups", self.groups.len()),
+            ("roles", self.roles.len()),
+            ("user_input_policies", self.user_input_policies.len()),
+            ("condition_policies", self.condition_policies.len()),
+            (
+                "user_group_relationships",
+                count(&self.user_id_to_group_ids),
+            ),
+            ("user_role_relationships", count(&self.user_id_to_role_ids)),
+            (
+                "policy_relationships",
+                self.policy_relationships.relationships().len(),
+            ),
+            ("endpoints", self.endpoints.len()),
+        ]
+    }
+
+    /// See [`EndpointRegistry::find`]
+    pub fn find_endpoint(&self, account: &AwsAccount, region: &str) -> ProxyResult<&Endpoint> {
+        self.endpoints.find(account, region)
+    }
+
     pub fn find_account_by_code(&self, code: &str) -> ProxyResult<&AwsAccount> {
         self.accounts.get(code).ok_or_else(|| {
             ProxyError::InvalidAccessKey(format!(
@@ -294,6 +485,7 @@ impl<P: Modeled> IamContainer<P> {
         })
     }
 
+    #[instrument(skip_all, fields(user_id))]
     pub fn find_user_by_base_access_key(&self, base_access_key: &str) -> ProxyResult<&User> {
         let user_id = self
             .base_access_key_to_user_id
@@ -303,75 +495,112 @@ impl<P: Modeled> IamContainer<P> {
                     "User not found for base access key id: '{base_access_key}'"
                 ))
             })?;
+        Span::current().record("user_id", user_id.as_str());
         self.users
             .get(user_id)
             .ok_or_else(|| ProxyError::UserNotFound(format!("User not found by id: {user_id}")))
     }
 
+    /// Group membership is optional, a user without any group gets an empty list
     pub fn find_groups_by_user(&self, user: &User) -> ProxyResult<Vec<&Group>> {
-        let group_ids = self.user_id_to_group_ids.get(&user.id).ok_or_else(|| {
-            ProxyError::GroupNotFound(format!(
-                "Groups not found for user id: {}, check proxy_region_env",
-                user.id
-            ))
-        })?;
+        let Some(group_ids) = self.user_id_to_group_ids.get(&user.id) else {
+            return Ok(Vec::new());
+        };
 
         group_ids
             .iter()
             .map(|group_id| {
                 self.groups.get(group_id).ok_or_else(|| {
-                    ProxyError::AssertFail(format!("Group not found by id: {group_id}"))
+                    ProxyError::InvalidConfig(format!("Group not found by id: {group_id}"))
                 })
             })
             .collect()
     }
 
+    /// The role is only found if the user is trusted to assume it
+    pub fn find_role_by_user(&self, user: &User, role_id: &str) -> ProxyResult<&Role> {
+        let trusted = matches!(
+            self.user_id_to_role_ids.get(&user.id),
+            Some(role_ids) if role_ids.iter().any(|id| id == role_id)
+        );
+        if !trusted {
+            return Err(ProxyError::InvalidAccessKey(format!(
+                "User {} is not allowed to assume role: {role_id}",
+                user.id
+            )));
+        }
+        self.roles
+            .get(role_id)
+            .ok_or_else(|| ProxyError::InvalidConfig(format!("Role not found by id: {role_id}")))
+    }
+
+    pub fn find_user_by_id(&self, user_id: &str) -> ProxyResult<&User> {
+        self.users
+            .get(user_id)
+            .ok_or_else(|| ProxyError::UserNotFound(format!("User not found by id: {user_id}")))
+    }
+
+    #[instrument(
+        skip_all,
+        fields(
+            user_id = f.user.map(|u| u.id_str()),
+            account_code = %f.account.code,
+            region = f.target_region,
+        )
+    )]
     pub fn find_policies(&self, f: &PolicyFilterParams) -> ProxyResult<FoundPolicies<P>> {
-        let relations: Vec<&PolicyRelationship> = self
-            .policy_relationships
-            .iter()
-            .filter(|r| filter_one(Some(&f.account.id), Some(&r.account_id)))
-            .filter(|r| filter_one(Some(f.target_region), Some(&r.region)))
-            .filter(|r| {
-                let user_id = f.user.map(|u| u.id_str());
-                filter_one(user_id, r.user_id.as_deref())
-            })
-            .filter(|r| {
-                let group_ids = f.groups.map(|v| v.iter().map(|g| g.id_str()));
-                filter_many(group_ids, r.group_id.as_deref())
-            })
-            .filter(|r| {
-                let role_ids = f.roles.map(|v| v.iter().map(|r| r.id_str()));
-                filter_many(role_ids, r.role_id.as_deref())
-            })
-            .collect();
+        self.find_policies_any(std::slice::from_ref(f))
+    }
+
+    /// Policies of relationships matched by any of the filters, in the order of the filters and
+    /// then of the precedence of the principals of each filter
+    pub fn find_policies_any(
+        &self,
+        filters: &[PolicyFilterParams],
+    ) -> ProxyResult<FoundPolicies<'_, P>> {
+        let mut relations: Vec<&PolicyRelationship> = Vec::new();
+        for f in filters {
+            let mut found = self.policy_relationships.find(f);
+            found.sort_by_key(|r| f.precedence(r));
+            for relation in found {
+                if !relations.iter().any(|r| std::ptr::eq(*r, relation)) {
+                    relations.push(relation);
+                }
+            }
+        }
 
         if relations.is_empty() {
+            let filters: Vec<String> = filters.iter().map(ToString::to_string).collect();
             return Err(ProxyError::MissingPolicy(format!(
                 "access denied by missing policy, PolicyFilterParams: {}",
-                f
+                filters.join(", ")
             )));
         }
 
         let mut condition: Vec<&Policy<ConditionPolicy>> = Vec::new();
         let mut user_input: Vec<&Policy<P>> = Vec::new();
         for relation in relations {
+            // a policy reached through several relationships keeps its highest precedence
             match relation.policy_model.as_str() {
                 CONDITION => {
-                    // TODO: return error instead, to indicate that the policy configuration is incorrect
-                    let p = self.condition_policies.get(&relation.policy_id).unwp();
-                    condition.push(p);
+                    let p = self.condition_policies.get(&relation.policy_id);
+                    let p = p.ok_or_else(|| missing_policy(relation))?;
+                    if !condition.iter().any(|found| std::ptr::eq(*found, p)) {
+                        condition.push(p);
+                    }
                 }
                 user_input_model if user_input_model == POLICY_MODEL.load().to_string() => {
-                    let p = self.user_input_policies.get(&relation.policy_id).unwp();
-                    user_input.push(p);
-                }
-                other => {
-                    return Err(ProxyError::AssertFail(format!(
-                        "unknown policy model found: {}",
-                        other
-                    )));
+                    let p = self.user_input_policies.get(&relation.policy_id);
+                    let p = p.ok_or_else(|| missing_policy(relation))?;
+                    if !user_input.iter().any(|found| std::ptr::eq(*found, p)) {
+                        user_input.push(p);
+                    }
                 }
+                // of another kind of proxy sharing the config, see `LintKind::UnknownPolicyModel`
+                other => debug!(
+                    "relationship of policy model {} ignored: {}",
+                    other, relation.id
+                ),
             };
         }
 
@@ -380,6 +609,96 @@ impl<P: Modeled> IamContainer<P> {
             user_input,
         })
     }
+
+    /// Same as `find_user_by_base_access_key` and `find_policies_by_user` in a row, memoized
+    /// until the container is replaced by a state update.
+    pub fn find_policies_by_access_key(
+        &self,
+        base_access_key: &str,
+        account: &AwsAccount,
+        target_region: &str,
+    ) -> ProxyResult<FoundPolicies<'_, P>> {
+        let key = ResolutionKey::new(base_access_key, &account.id, target_region);
+        if let Some(resolved) = self.resolution_cache.get(&key) {
+            return self.policies_by_ids(&resolved);
+        }
+
+        let user = self.find_user_by_base_access_key(base_access_key)?;
+        let found = self.find_policies_by_user(user, account, target_region)?;
+        let resolved = ResolvedPolicyIds {
+            condition: found.condition.iter().map(|p| p.id.clone()).collect(),
+            user_input: found.user_input.iter().map(|p| p.id.clone()).collect(),
+        };
+        self.resolution_cache.insert(key, resolved);
+        Ok(found)
+    }
+
+    /// Union of the policies bound to the user, to each of its groups and to its kind, in that
+    /// order of precedence, see [`FoundPolicies`]
+    pub fn find_policies_by_user(
+        &self,
+        user: &User,
+        account: &AwsAccount,
+        target_region: &str,
+    ) -> ProxyResult<FoundPolicies<'_, P>> {
+        let groups = self.find_groups_by_user(user)?;
+        let filter = || PolicyFilterParams::new_with(account, target_region).user_kind(&user.kind);
+        // every principal of a relationship is matched against all the ones of the user at once
+        let filters = [filter().user(user).groups(&groups), filter()];
+        self.find_policies_any(&filters)
+    }
+
+    /// Policies of the role assumed by the session. The trust relationship is checked against the
+    /// current config, so that removing it also revokes the sessions already issued.
+    pub fn find_policies_by_session(
+        &self,
+        session: &SessionClaims,
+        account: &AwsAccount,
+        target_region: &str,
+    ) -> ProxyResult<FoundPolicies<'_, P>> {
+        let user = self
+            .find_user_by_id(&session.user_id)
+            .map_err(|e| ProxyError::InvalidAccessKey(format!("Invalid session: {e}")))?;
+        let roles = vec![self.find_role_by_user(user, &session.role_id)?];
+        let filter = PolicyFilterParams::new_with(account, target_region)
+            .roles(&roles)
+            .user_kind(&user.kind);
+        self.find_policies(&filter)
+    }
+
+    pub const fn resolution_cache(&self) -> &ResolutionCache {
+        &self.resolution_cache
+    }
+
+    fn policies_by_ids(&self, resolved: &ResolvedPolicyIds) -> ProxyResult<FoundPolicies<'_, P>> {
+        let not_found =
+            |id: &PolicyId| ProxyError::InvalidConfig(format!("cached policy {id} not found"));
+        let condition = resolved
+            .condition
+            .iter()
+            .map(|id| self.condition_policies.get(id).ok_or_else(|| not_found(id)))
+            .collect::<ProxyResult<_>>()?;
+        let user_input = resolved
+            .user_input
+            .iter()
+            .map(|id| {
+                self.user_input_policies
+                    .get(id)
+                    .ok_or_else(|| not_found(id))
+            })
+            .collect::<ProxyResult<_>>()?;
+        Ok(FoundPolicies {
+            condition,
+            user_input,
+        })
+    }
+}
+
+fn missing_policy(relation: &PolicyRelationship) -> ProxyError {
+    ProxyError::InvalidConfig(format!(
+        "policy {} of model {} not found, referenced by policy relationship {}",
+        relation.policy_id, relation.policy_model, relation.id
+    ))
 }
 
 #[inline]
@@ -397,6 +716,22 @@ fn filter_many<'a>(
     })
 }
 
+/// A principal set on the relationship must be one of the filter, which may have none
+#[inline]
+fn filter_principal<'a>(query_params: impl Iterator<Item = &'a str>, record: Option<&str>) -> bool {
+    record.is_none() || filter_many(Some(query_params), record)
+}
+
+/// A relationship of a user kind only matches users of that kind
+#[inline]
+fn filter_user_kind(record: Option<&UserKind>, query_param: Option<&UserKind>) -> bool {
+    match (record, query_param) {
+        (None, _) => true,
+        (Some(r), Some(q)) => r == q,
+        (Some(_), None) => false,
+    }
+}
+
 #[inline]
 fn equals_or_any(query_param: &str, record: &str) -> bool {
     record == query_param || record == ANY
@@ -404,9 +739,448 @@ fn equals_or_any(query_param: &str, record: &str) -> bool {
 
 #[cfg(test)]
 mod test {
+    use std::time::Duration;
+
     use busylib::ANY;
+    use piam_core::{
+        account::aws::AwsAccount,
+        condition::input::ConditionCtx,
+        effect::{Effect, RateLimit},
+        group::Group,
+        policy::{condition::ConditionPolicy, Policy},
+        principal::{User, UserKind},
+        relation_model::{GroupParentRelationship, PolicyRelationship, UserGroupRelationship},
+    };
+
+    use crate::{
+        config::CoreConfig,
+        container::{filter_many, filter_one, IamContainer},
+        error::ProxyError,
+        state::CoreState,
+    };
 
-    use crate::container::{filter_many, filter_one};
+    #[test]
+    fn reject_dangling_relationships() {
+        let mut config = CoreConfig::<ConditionPolicy>::default();
+        config.policy_relationships.push(PolicyRelationship {
+            id: "rel1".to_string(),
+            policy_model: "Condition".to_string(),
+            account_id: ANY.to_string(),
+            region: ANY.to_string(),
+            policy_id: "missing".to_string(),
+            ..Default::default()
+        });
+        assert!(matches!(
+            IamContainer::new_from(config),
+            Err(ProxyError::InvalidConfig(_))
+        ));
+        assert!(IamContainer::new_from(CoreConfig::<ConditionPolicy>::default()).is_ok());
+    }
+
+    #[test]
+    fn cache_policy_resolution() {
+        let mut config = CoreConfig::<ConditionPolicy>::default();
+        config.accounts.push(AwsAccount {
+            id: "account1".to_string(),
+            code: "0001".to_string(),
+            ..Default::default()
+        });
+        config.users.push(User {
+            id: "user1".to_string(),
+            base_access_key: "AKPSPERSALICE".to_string(),
+            ..Default::default()
+        });
+        config.groups.push(Group {
+            id: "group1".to_string(),
+            ..Default::default()
+        });
+        config.user_group_relationships.push(UserGroupRelationship {
+            id: "rel1".to_string(),
+            user_id: "user1".to_string(),
+            group_id: "group1".to_string(),
+        });
+        config.condition_policies.push(Policy {
+            id: "policy1".to_string(),
+            modeled_policy: vec![ConditionPolicy::default()],
+            ..Default::default()
+        });
+        config.policy_relationships.push(PolicyRelationship {
+            id: "rel2".to_string(),
+            policy_model: "Condition".to_string(),
+            group_id: Some("group1".to_string()),
+            account_id: "account1".to_string(),
+            region: ANY.to_string(),
+            policy_id: "policy1".to_string(),
+            ..Default::default()
+        });
+        let container = IamContainer::new_from(config).unwrap();
+        let account = container.find_account_by_code("0001").unwrap();
+
+        for _ in 0..2 {
+            let found = container
+                .find_policies_by_access_key("AKPSPERSALICE", account, "us-east-1")
+                .unwrap();
+            assert_eq!(found.condition.len(), 1);
+            assert_eq!(found.condition[0].id, "policy1");
+            assert_eq!(container.resolution_cache().len(), 1);
+        }
+
+        assert!(container
+            .find_policies_by_access_key("AKPSPERSBOB", account, "us-east-1")
+            .is_err());
+        assert_eq!(container.resolution_cache().len(), 1);
+
+        // resolved by the relationship of any region, but made up by the client
+        for i in 0..3 {
+            let region = format!("made-up-{i}");
+            assert!(container
+                .find_policies_by_access_key("AKPSPERSALICE", account, &region)
+                .is_ok());
+        }
+        assert_eq!(container.resolution_cache().len(), 1);
+    }
+
+    #[test]
+    fn find_policies_by_user_kind() {
+        let mut config = CoreConfig::<ConditionPolicy>::default();
+        config.accounts.push(AwsAccount {
+            id: "account1".to_string(),
+            code: "0001".to_string(),
+            ..Default::default()
+        });
+        for (id, kind) in [
+            ("user1", UserKind::Customer),
+            ("user2", UserKind::Service),
+            ("user3", UserKind::Customer),
+        ] {
+            config.users.push(User {
+                id: id.to_string(),
+                base_access_key: id.to_string(),
+                kind,
+                ..Default::default()
+            });
+            if id != "user3" {
+                config.user_group_relationships.push(UserGroupRelationship {
+                    id: format!("{id}_group1"),
+                    user_id: id.to_string(),
+                    group_id: "group1".to_string(),
+                });
+            }
+        }
+        config.groups.push(Group {
+            id: "group1".to_string(),
+            ..Default::default()
+        });
+        for (id, group_id, user_kind) in [
+            ("policy1", Some("group1".to_string()), None),
+            ("policy2", None, Some(UserKind::Customer)),
+            // customers of group1 only
+            (
+                "policy3",
+                Some("group1".to_string()),
+                Some(UserKind::Customer),
+            ),
+        ] {
+            config.condition_policies.push(Policy {
+                id: id.to_string(),
+                modeled_policy: vec![ConditionPolicy::default()],
+                ..Default::default()
+            });
+            config.policy_relationships.push(PolicyRelationship {
+                id: format!("{id}_rel"),
+                policy_model: "Condition".to_string(),
+                group_id,
+                user_kind,
+                account_id: ANY.to_string(),
+                region: ANY.to_string(),
+                policy_id: id.to_string(),
+                ..Default::default()
+            });
+        }
+        let container = IamContainer::new_from(config).unwrap();
+        let account = container.find_account_by_code("0001").unwrap();
+
+        let policy_ids = |base_access_key: &str| {
+            let found = container
+                .find_policies_by_access_key(base_access_key, account, "us-east-1")
+                .unwrap();
+            found
+                .condition
+                .iter()
+                .map(|p| p.id.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(policy_ids("user1"), vec!["policy1", "policy3", "policy2"]);
+        assert_eq!(policy_ids("user2"), vec!["policy1"]);
+        // a customer, but not a member of group1
+        assert_eq!(policy_ids("user3"), vec!["policy2"]);
+    }
+
+    #[test]
+    fn rate_limit_by_precedence() {
+        let mut config = CoreConfig::<ConditionPolicy>::default();
+        config.accounts.push(AwsAccount {
+            id: "account1".to_string(),
+            code: "0001".to_string(),
+            ..Default::default()
+        });
+        config.users.push(User {
+            id: "user1".to_string(),
+            base_access_key: "user1".to_string(),
+            ..Default::default()
+        });
+        config.groups.push(Group {
+            id: "group1".to_string(),
+            ..Default::default()
+        });
+        config.user_group_relationships.push(UserGroupRelationship {
+            id: "user1_group1".to_string(),
+            user_id: "user1".to_string(),
+            group_id: "group1".to_string(),
+        });
+        let limited = |count: u32| ConditionPolicy {
+            effect: Effect::Allow {
+                emit_event: None,
+                rate_limit: Some(RateLimit {
+                    duration: Duration::from_secs(1),
+                    count,
+                }),
+                modify: None,
+            },
+            ..Default::default()
+        };
+        // related in the reverse order of precedence
+        for (id, user_id, group_id, modeled_policy) in [
+            ("policy1", None, Some("group1"), vec![limited(30)]),
+            (
+                "policy2",
+                Some("user1"),
+                None,
+                vec![limited(10), limited(20)],
+            ),
+        ] {
+            config.condition_policies.push(Policy {
+                id: id.to_string(),
+                modeled_policy,
+                ..Default::default()
+            });
+            config.policy_relationships.push(PolicyRelationship {
+                id: format!("{id}_rel"),
+                policy_model: "Condition".to_string(),
+                user_id: user_id.map(str::to_string),
+                group_id: group_id.map(str::to_string),
+                account_id: ANY.to_string(),
+                region: ANY.to_string(),
+                policy_id: id.to_string(),
+                ..Default::default()
+            });
+        }
+        let container = IamContainer::new_from(config).unwrap();
+        let account = container.find_account_by_code("0001").unwrap();
+        let found = container
+            .find_policies_by_access_key("user1", account, "us-east-1")
+            .unwrap();
+        let effects = found.condition_effects(&ConditionCtx::default()).unwrap();
+        let counts: Vec<u32> = effects
+            .iter()
+            .map(|e| RateLimit::find_or_default([*e], UserKind::Person).count)
+            .collect();
+        assert_eq!(counts, vec![10, 20, 30]);
+        assert_eq!(
+            RateLimit::find_or_default(effects, UserKind::Person).count,
+            10
+        );
+    }
+
+    #[test]
+    fn inherit_groups() {
+        let mut config = CoreConfig::<ConditionPolicy>::default();
+        config.users.push(User {
+            id: "user1".to_string(),
+            ..Default::default()
+        });
+        for id in ["company", "department", "team"] {
+            config.groups.push(Group {
+                id: id.to_string(),
+                ..Default::default()
+            });
+        }
+        config.user_group_relationships.push(UserGroupRelationship {
+            id: "rel1".to_string(),
+            user_id: "user1".to_string(),
+            group_id: "team".to_string(),
+        });
+        for (group_id, parent_group_id) in [("team", "department"), ("department", "company")] {
+            config
+                .group_parent_relationships
+                .push(GroupParentRelationship {
+                    id: format!("{group_id}_{parent_group_id}"),
+                    group_id: group_id.to_string(),
+                    parent_group_id: parent_group_id.to_string(),
+                });
+        }
+        let container = IamContainer::new_from(config).unwrap();
+        let user = container.find_user_by_id("user1").unwrap();
+        let group_ids: Vec<&str> = container
+            .find_groups_by_user(user)
+            .unwrap()
+            .iter()
+            .map(|g| g.id.as_str())
+            .collect();
+        assert_eq!(group_ids, vec!["team", "department", "company"]);
+
+        let mut config = CoreConfig::<ConditionPolicy>::default();
+        for id in ["group1", "group2"] {
+            config.groups.push(Group {
+                id: id.to_string(),
+                ..Default::default()
+            });
+        }
+        for (group_id, parent_group_id) in [("group1", "group2"), ("group2", "group1")] {
+            config
+                .group_parent_relationships
+                .push(GroupParentRelationship {
+                    id: group_id.to_string(),
+                    group_id: group_id.to_string(),
+                    parent_group_id: parent_group_id.to_string(),
+                });
+        }
+        assert!(matches!(
+            IamContainer::new_from(config),
+            Err(ProxyError::InvalidConfig(msg)) if msg.contains("group1 -> group2 -> group1")
+        ));
+    }
+
+    #[test]
+    fn find_policies_of_group_less_user() {
+        let mut config = CoreConfig::<ConditionPolicy>::default();
+        config.accounts.push(AwsAccount {
+            id: "account1".to_string(),
+            code: "0001".to_string(),
+            ..Default::default()
+        });
+        for id in ["user1", "user2"] {
+            config.users.push(User {
+                id: id.to_string(),
+                base_access_key: id.to_string(),
+                ..Default::default()
+            });
+        }
+        config.groups.push(Group {
+            id: "group1".to_string(),
+            ..Default::default()
+        });
+        config.user_group_relationships.push(UserGroupRelationship {
+            id: "rel1".to_string(),
+            user_id: "user1".to_string(),
+            group_id: "group1".to_string(),
+        });
+        for (id, user_id, group_id) in [
+            ("policy1", None, Some("group1")),
+            ("policy2", Some("user1"), None),
+            ("policy3", Some("user2"), None),
+        ] {
+            config.condition_policies.push(Policy {
+                id: id.to_string(),
+                modeled_policy: vec![ConditionPolicy::default()],
+                ..Default::default()
+            });
+            config.policy_relationships.push(PolicyRelationship {
+                id: format!("{id}_rel"),
+                policy_model: "Condition".to_string(),
+                user_id: user_id.map(ToString::to_string),
+                group_id: group_id.map(ToString::to_string),
+                account_id: ANY.to_string(),
+                region: ANY.to_string(),
+                policy_id: id.to_string(),
+                ..Default::default()
+            });
+        }
+        let container = IamContainer::new_from(config).unwrap();
+        let account = container.find_account_by_code("0001").unwrap();
+
+        let policy_ids = |user_id: &str| {
+            let user = container.find_user_by_id(user_id).unwrap();
+            let found = container
+                .find_policies_by_user(user, account, "us-east-1")
+                .unwrap();
+            found
+                .condition
+                .iter()
+                .map(|p| p.id.as_str())
+                .collect::<Vec<_>>()
+        };
+        // user policies take precedence over group policies
+        assert_eq!(policy_ids("user1"), vec!["policy2", "policy1"]);
+        assert_eq!(policy_ids("user2"), vec!["policy3"]);
+    }
+
+    #[test]
+    fn match_every_principal_of_relationship() {
+        let mut config = CoreConfig::<ConditionPolicy>::default();
+        config.accounts.push(AwsAccount {
+            id: "account1".to_string(),
+            code: "0001".to_string(),
+            ..Default::default()
+        });
+        for id in ["alice", "bob", "carol"] {
+            config.users.push(User {
+                id: id.to_string(),
+                base_access_key: id.to_string(),
+                ..Default::default()
+            });
+        }
+        config.groups.push(Group {
+            id: "eng".to_string(),
+            ..Default::default()
+        });
+        for user_id in ["alice", "carol"] {
+            config.user_group_relationships.push(UserGroupRelationship {
+                id: format!("{user_id}_eng"),
+                user_id: user_id.to_string(),
+                group_id: "eng".to_string(),
+            });
+        }
+        for (id, user_id) in [
+            ("everyone", None),
+            ("bob_in_eng", Some("bob")),
+            ("carol_in_eng", Some("carol")),
+        ] {
+            config.condition_policies.push(Policy {
+                id: id.to_string(),
+                modeled_policy: vec![ConditionPolicy::default()],
+                ..Default::default()
+            });
+            config.policy_relationships.push(PolicyRelationship {
+                id: format!("{id}_rel"),
+                policy_model: "Condition".to_string(),
+                user_id: user_id.map(ToString::to_string),
+                group_id: user_id.map(|_| "eng".to_string()),
+                account_id: ANY.to_string(),
+                region: ANY.to_string(),
+                policy_id: id.to_string(),
+                ..Default::default()
+            });
+        }
+        let container = IamContainer::new_from(config).unwrap();
+        let account = container.find_account_by_code("0001").unwrap();
+
+        let policy_ids = |user_id: &str| {
+            let user = container.find_user_by_id(user_id).unwrap();
+            let found = container
+                .find_policies_by_user(user, account, "us-east-1")
+                .unwrap();
+            found
+                .condition
+                .iter()
+                .map(|p| p.id.as_str())
+                .collect::<Vec<_>>()
+        };
+        // in eng but not bob, and bob but not in eng
+        assert_eq!(policy_ids("alice"), vec!["everyone"]);
+        assert_eq!(policy_ids("bob"), vec!["everyone"]);
+        assert_eq!(policy_ids("carol"), vec!["carol_in_eng", "everyone"]);
+    }
 
     #[clippy::cognitive_complexity = "100"]
     #[test]
@@ -0,0 +1,164 @@
+//! Upstream endpoints by account and region, from the `endpoints` of the config on top of the
+//! built-in ones of AWS and Tencent Cloud, so that adding one does not take a rebuild.
+
+use std::collections::HashMap;
+
+use busylib::ANY;
+use piam_core::{
+    account::{aws::AwsAccount, AccountId},
+    endpoint::{Endpoint, Scheme},
+};
+
+use crate::error::{ProxyError, ProxyResult};
+
+#[derive(Debug, Default)]
+pub struct EndpointRegistry {
+    /// Endpoints of an account by region, which may be `ANY`
+    by_account: HashMap<AccountId, HashMap<String, Endpoint>>,
+    /// Endpoints for all accounts by region
+    by_region: HashMap<String, Endpoint>,
+}
+
+impl EndpointRegistry {
+    /// The first one wins if several serve the same account and region, see `lint_endpoints`
+    pub fn new(endpoints: Vec<Endpoint>) -> Self {
+        let mut registry = Self::default();
+        for endpoint in endpoints.into_iter().chain(builtin()) {
+            let by_region = match &endpoint.account_id {
+                Some(account_id) => registry.by_account.entry(account_id.clone()).or_default(),
+                None => &mut registry.by_region,
+            };
+            by_region.entry(endpoint.region.clone()).or_insert(endpoint);
+        }
+        registry
+    }
+
+    /// The endpoint of the account in the region, of the account in any region, of the region, or
+    /// of any account in any region as the last resort
+    pub fn find(&self, account: &AwsAccount, region: &str) -> ProxyResult<&Endpoint> {
+        let of_account = self.by_account.get(&account.id);
+        of_account
+            .and_then(|by_region| by_region.get(region).or_else(|| by_region.get(ANY)))
+            .or_else(|| self.by_region.get(region))
+            .or_else(|| self.by_region.get(ANY))
+            .ok_or_else(|| {
+                ProxyError::InvalidRegion(format!(
+                    "no endpoint of region: {region} for account: {}",
+                    account.code
+                ))
+            })
+    }
+
+    /// Regions served, other than `ANY`
+    pub fn regions(&self) -> impl Iterator<Item = &str> {
+        self.by_account
+            .values()
+            .flat_map(HashMap::keys)
+            .chain(self.by_region.keys())
+            .map(String::as_str)
+            .filter(|region| *region != ANY)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_region.len() + self.by_account.values().map(HashMap::len).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Hosts by region known before endpoints were configurable
+const BUILTIN_HOSTS: [(&str, &str); 6] = [
+    // aws
+    ("cn-northwest-1", "s3.cn-northwest-1.amazonaws.com.cn"),
+    ("us-east-1", "s3.us-east-1.amazonaws.com"),
+    ("us-east-2", "s3.us-east-2.amazonaws.com"),
+    ("eu-central-1", "s3.eu-central-1.amazonaws.com"),
+    // tencent
+    ("ap-shanghai", "cos.ap-shanghai.myqcloud.com"),
+    ("na-ashburn", "cos.na-ashburn.myqcloud.com"),
+];
+
+/// The built-in host of the region, regardless of the configured endpoints
+pub fn builtin_host(region: &str) -> Option<&'static str> {
+    BUILTIN_HOSTS
+        .iter()
+        .find(|(builtin_region, _)| *builtin_region == region)
+        .map(|(_, host)| *host)
+}
+
+fn builtin() -> Vec<Endpoint> {
+    BUILTIN_HOSTS
+        .into_iter()
+        .map(|(region, host)| {
+            Endpoint::new(&format!("builtin-{region}"), region, Scheme::Https, host)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use busylib::ANY;
+    use piam_core::{
+        account::aws::AwsAccount,
+        endpoint::{Endpoint, Scheme},
+    };
+
+    use crate::{endpoint::EndpointRegistry, error::ProxyError};
+
+    #[test]
+    fn find_endpoint() {
+        let account = |id: &str| AwsAccount {
+            id: id.to_string(),
+            ..Default::default()
+        };
+        let registry = EndpointRegistry::new(vec![
+            Endpoint::new(
+                "vpc",
+                "us-east-1",
+                Scheme::Https,
+                "bucket.vpce.amazonaws.com",
+            ),
+            Endpoint {
+                account_id: Some("minio".to_string()),
+                path_style: true,
+                ..Endpoint::new("minio", ANY, Scheme::Http, "minio:9000")
+            },
+            Endpoint {
+                account_id: Some("account1".to_string()),
+                ..Endpoint::new("account1", "eu-central-1", Scheme::Https, "s3.internal")
+            },
+        ]);
+
+        let found = |account_id: &str, region: &str| {
+            registry
+                .find(&account(account_id), region)
+                .map(|endpoint| endpoint.id.as_str())
+        };
+        // configured ones replace the built-in ones
+        assert_eq!(found("account0", "us-east-1").unwrap(), "vpc");
+        assert_eq!(found("account0", "us-east-2").unwrap(), "builtin-us-east-2");
+        assert_eq!(found("minio", "anywhere").unwrap(), "minio");
+        assert_eq!(found("account1", "eu-central-1").unwrap(), "account1");
+        assert_eq!(found("account1", "us-east-1").unwrap(), "vpc");
+        assert!(matches!(
+            found("account1", "mars-1"),
+            Err(ProxyError::InvalidRegion(_))
+        ));
+
+        let registry = EndpointRegistry::new(vec![Endpoint::new(
+            "gateway",
+            ANY,
+            Scheme::Https,
+            "gateway.internal",
+        )]);
+        let found = |account_id: &str, region: &str| {
+            registry
+                .find(&account(account_id), region)
+                .map(|endpoint| endpoint.id.as_str())
+        };
+        assert_eq!(found("account0", "us-east-1").unwrap(), "builtin-us-east-1");
+        assert_eq!(found("account0", "mars-1").unwrap(), "gateway");
+    }
+}
@@ -22,6 +22,7 @@ pub enum ProxyError {
     EffectNotFound(String),
     ManagerApi(String),
     Deserialize(String),
+    InvalidConfig(String),
     OtherInternal(String),
     FatalError(String),
     AssertFail(String),
@@ -45,6 +46,7 @@ impl ProxyError {
             Self::EffectNotFound(_) => "EffectNotFound",
             Self::ManagerApi(_) => "ManagerApi",
             Self::Deserialize(_) => "Deserialize",
+            Self::InvalidConfig(_) => "InvalidConfig",
             Self::OtherInternal(_) => "OtherInternal",
             Self::FatalError(_) => "FatalError",
             Self::AssertFail(_) => "AssertFail",
@@ -72,6 +74,7 @@ impl Display for ProxyError {
             Self::EffectNotFound(msg) => write!(f, "EffectNotFound: {msg}"),
             Self::ManagerApi(msg) => write!(f, "ManagerApi: {msg}"),
             Self::Deserialize(msg) => write!(f, "Deserialize: {msg}"),
+            Self::InvalidConfig(msg) => write!(f, "InvalidConfig: {msg}"),
             Self::OtherInternal(msg) => write!(f, "OtherInternal: {msg}"),
             Self::FatalError(msg) => write!(f, "FatalError: {msg}"),
             Self::AssertFail(msg) => write!(f, "AssertFail: {msg}"),
@@ -88,7 +91,7 @@ impl From<ReqwestError> for ProxyError {
 impl From<PiamError> for ProxyError {
     fn from(err: PiamError) -> Self {
         match err {
-            PiamError::Conflict(msg) => Self::AssertFail(msg),
+            PiamError::Conflict(msg) => Self::InvalidConfig(msg),
         }
     }
 }
@@ -0,0 +1,135 @@
+//! Liveness and readiness of the proxy, for the load balancer to drain proxies whose config is
+//! stale, e.g. when the manager has been unreachable for long.
+//!
+//! The endpoints are under `/_piam`, which is never a valid bucket name, so they can be mounted
+//! next to the proxied routes.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{extract::State, routing::get, Router};
+use busylib::prelude::EnhancedExpect;
+use http::StatusCode;
+
+use crate::{
+    config::STATE_STALENESS_THRESHOLD,
+    error::{ProxyError, ProxyResult},
+    state::Health,
+};
+
+pub const LIVENESS_PATH: &str = "/_piam/live";
+pub const READINESS_PATH: &str = "/_piam/ready";
+
+/// Health of the state kept up to date by `StateManager`
+pub type SharedHealth = Arc<Mutex<Health>>;
+
+#[derive(Clone, Debug)]
+pub struct HealthCheck {
+    health: SharedHealth,
+    /// Unready once the config is older than this
+    staleness_threshold: Duration,
+}
+
+impl HealthCheck {
+    pub const fn new(health: SharedHealth, staleness_threshold: Duration) -> Self {
+        Self {
+            health,
+            staleness_threshold,
+        }
+    }
+
+    /// Ready while the config is not older than the threshold, the message tells why either way
+    pub fn readiness(&self) -> Result<String, String> {
+        let health = self.health.lock().ex("health lock should not be poisoned");
+        let failed = health.state_update_failed_times;
+        match health.staleness() {
+            None => Err("config has never been loaded".to_string()),
+            Some(staleness) if staleness > self.staleness_threshold => Err(format!(
+                "config is stale, updated {}s ago exceeding {}s, failed {} times since",
+                staleness.as_secs(),
+                self.staleness_threshold.as_secs(),
+                failed
+            )),
+            Some(staleness) => Ok(format!(
+                "config updated {}s ago, failed {} times since",
+                staleness.as_secs(),
+                failed
+            )),
+        }
+    }
+
+    /// Routes of [`LIVENESS_PATH`] and [`READINESS_PATH`]
+    pub fn routes<S: Clone + Send + Sync + 'static>(self) -> Router<S> {
+        Router::new()
+            .route(LIVENESS_PATH, get(liveness))
+            .route(READINESS_PATH, get(readiness))
+            .with_state(self)
+    }
+}
+
+/// Seconds in `STATE_STALENESS_THRESHOLD`
+pub fn staleness_threshold() -> ProxyResult<Duration> {
+    let threshold = STATE_STALENESS_THRESHOLD.load();
+    threshold
+        .trim()
+        .parse()
+        .map(Duration::from_secs)
+        .map_err(|e| {
+            ProxyError::InvalidConfig(format!(
+                "invalid STATE_STALENESS_THRESHOLD '{threshold}': {e}"
+            ))
+        })
+}
+
+/// Alive as long as requests are served, a stale config is up to readiness
+pub async fn liveness() -> StatusCode {
+    StatusCode::OK
+}
+
+pub async fn readiness(State(check): State<HealthCheck>) -> (StatusCode, String) {
+    match check.readiness() {
+        Ok(msg) => (StatusCode::OK, msg),
+        Err(msg) => (StatusCode::SERVICE_UNAVAILABLE, msg),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant, SystemTime},
+    };
+
+    use crate::{health::HealthCheck, state::Health};
+
+    #[test]
+    fn readiness() {
+        let health = Arc::new(Mutex::new(Health::default()));
+        let check = HealthCheck::new(health.clone(), Duration::from_secs(60));
+        assert!(check.readiness().is_err());
+
+        // booted from the cache
+        health.lock().unwrap().config_cached_at =
+            Some(SystemTime::now() - Duration::from_secs(120));
+        assert!(check.readiness().is_err());
+        health.lock().unwrap().config_cached_at = Some(SystemTime::now() - Duration::from_secs(30));
+        assert!(check.readiness().is_ok());
+
+        health.lock().unwrap().record_success();
+        assert!(check.readiness().is_ok());
+        {
+            let mut health = health.lock().unwrap();
+            health.record_failure();
+            health.record_failure();
+            assert_eq!(health.state_update_failed_times, 2);
+            health.state_last_successful_update_at = Some(Instant::now() - Duration::from_secs(61));
+        }
+        assert!(check.readiness().unwrap_err().contains("failed 2 times"));
+
+        health.lock().unwrap().record_success();
+        assert_eq!(health.lock().unwrap().state_update_failed_times, 0);
+        assert!(check.readiness().is_ok());
+    }
+}
@@ -4,13 +4,25 @@
 // #![feature(custom_inner_attributes)]
 // #![clippy::cognitive_complexity = "10"]
 
+pub mod client_addr;
 pub mod config;
 pub mod container;
+pub mod endpoint;
 pub mod error;
+pub mod health;
 pub mod manager_api;
+pub mod metrics;
 pub mod policy;
+pub mod policy_case;
+pub mod policy_index;
 pub mod request;
+pub mod resolution_cache;
 pub mod response;
 pub mod signature;
+pub mod snapshot_cache;
 pub mod state;
+pub mod sts;
+pub mod telemetry;
+pub mod tls;
 pub mod type_alias;
+pub mod upstream;
@@ -1,22 +1,55 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, time::Duration};
 
 use busylib::http::ReqwestClient;
+use http::{header::IF_NONE_MATCH, StatusCode};
 use piam_core::{
     account::aws::AwsAccount,
     crypto::decrypt,
+    endpoint::Endpoint,
     group::Group,
     manager_api_constant::*,
     policy::{Modeled, Policy},
-    principal::User,
-    relation_model::{PolicyRelationship, UserGroupRelationship},
+    principal::{Role, User},
+    relation_model::{
+        GroupParentRelationship, PolicyRelationship, UserGroupRelationship, UserRoleRelationship,
+    },
 };
-use serde::de::DeserializeOwned;
+use serde::{de::DeserializeOwned, Deserialize};
 
 use crate::{
-    config::{CoreConfig, PIAM_MANAGER_ADDRESS, POLICY_MODEL},
+    config::{
+        CoreConfig, EXTENDED_CONFIG_TYPE, PIAM_MANAGER_ADDRESS, PIAM_MANAGER_TOKEN, POLICY_MODEL,
+    },
     error::{deserialize, ProxyError, ProxyResult},
 };
 
+/// Added to the wait of a long poll for the request timeout
+const WATCH_TIMEOUT_MARGIN: Duration = Duration::from_secs(10);
+
+/// See [`ManagerClient::get_revision`]
+#[derive(Debug, Eq, PartialEq)]
+pub enum Revision {
+    NotModified,
+    Current(String),
+    /// The manager does not serve revisions, the config should always be fetched
+    Unsupported,
+}
+
+/// The whole config read atomically by the manager, see [`ManagerClient::get_snapshot`]
+#[derive(Debug, Deserialize)]
+pub struct Snapshot<P: Modeled, C> {
+    /// The revision the config is at or newer than
+    pub revision: String,
+    pub core_config: CoreConfig<P>,
+    pub extended_config: C,
+}
+
+impl<P: Modeled + DeserializeOwned, C: DeserializeOwned> Snapshot<P, C> {
+    pub fn from_encrypted(encrypted: String) -> ProxyResult<Self> {
+        decrypt_resource(SNAPSHOT, encrypted)
+    }
+}
+
 #[derive(Debug)]
 pub struct ManagerClient {
     http_client: ReqwestClient,
@@ -31,6 +64,43 @@ impl Default for ManagerClient {
 }
 
 impl ManagerClient {
+    /// The revision of the whole config, [`Revision::NotModified`] if it is still `known`
+    pub async fn get_revision(&self, known: Option<&str>) -> ProxyResult<Revision> {
+        self.revision(known, None).await
+    }
+
+    /// Long poll returning as soon as the revision is not `known` anymore, or
+    /// [`Revision::NotModified`] after `wait`
+    pub async fn watch_revision(&self, known: &str, wait: Duration) -> ProxyResult<Revision> {
+        self.revision(Some(known), Some(wait)).await
+    }
+
+    async fn revision(&self, known: Option<&str>, wait: Option<Duration>) -> ProxyResult<Revision> {
+        let mut request = self.http_client.get(manager_url(REVISION));
+        if let Some(token) = manager_token() {
+            request = request.bearer_auth(token);
+        }
+        if let Some(known) = known {
+            request = request.header(IF_NONE_MATCH, format!("\"{}\"", known));
+        }
+        if let Some(wait) = wait {
+            request = request
+                .query(&[("wait", wait.as_secs())])
+                .timeout(wait + WATCH_TIMEOUT_MARGIN);
+        }
+        let response = request.send().await?;
+        match response.status() {
+            StatusCode::NOT_MODIFIED => Ok(Revision::NotModified),
+            StatusCode::NOT_FOUND => Ok(Revision::Unsupported),
+            _ => {
+                let response = response
+                    .error_for_status()
+                    .map_err(|e| ProxyError::OtherInternal(e.to_string()))?;
+                Ok(Revision::Current(response.text().await?.trim().to_string()))
+            }
+        }
+    }
+
     pub async fn get_accounts(&self) -> ProxyResult<Vec<AwsAccount>> {
         self.get_resource(ACCOUNTS).await
     }
@@ -43,6 +113,10 @@ impl ManagerClient {
         self.get_resource(GROUPS).await
     }
 
+    pub async fn get_roles(&self) -> ProxyResult<Vec<Role>> {
+        self.get_resource(ROLES).await
+    }
+
     pub async fn get_policies_by_model<P: Modeled + DeserializeOwned>(
         &self,
         policy_model: &str,
@@ -54,31 +128,75 @@ impl ManagerClient {
         self.get_resource(USER_GROUP_RELATIONSHIPS).await
     }
 
+    pub async fn get_group_parent_relationships(
+        &self,
+    ) -> ProxyResult<Vec<GroupParentRelationship>> {
+        self.get_resource(GROUP_PARENT_RELATIONSHIPS).await
+    }
+
+    pub async fn get_user_role_relationships(&self) -> ProxyResult<Vec<UserRoleRelationship>> {
+        self.get_resource(USER_ROLE_RELATIONSHIPS).await
+    }
+
     pub async fn get_policy_relationships(&self) -> ProxyResult<Vec<PolicyRelationship>> {
         self.get_resource(POLICY_RELATIONSHIPS).await
     }
 
+    pub async fn get_endpoints(&self) -> ProxyResult<Vec<Endpoint>> {
+        self.get_resource(ENDPOINTS).await
+    }
+
+    /// The core and extended config in one consistent document, unlike [`get_core_config`] and
+    /// [`get_extended_config`] which may see a concurrent write halfway
+    ///
+    /// [`get_core_config`]: ManagerClient::get_core_config
+    /// [`get_extended_config`]: ManagerClient::get_extended_config
+    pub async fn get_snapshot<P: Modeled + DeserializeOwned, C: DeserializeOwned>(
+        &self,
+    ) -> ProxyResult<Snapshot<P, C>> {
+        Snapshot::from_encrypted(self.get_snapshot_string().await?)
+    }
+
+    /// The snapshot still encrypted, as it is cached on disk
+    pub async fn get_snapshot_string(&self) -> ProxyResult<String> {
+        let query = serde_urlencoded::to_string([
+            ("policy_model", *POLICY_MODEL.load_full()),
+            ("extended_config_type", *EXTENDED_CONFIG_TYPE.load_full()),
+        ])
+        .map_err(|e| ProxyError::OtherInternal(e.to_string()))?;
+        self.get_resource_string(&format!("{}?{}", SNAPSHOT, query))
+            .await
+    }
+
     pub async fn get_core_config<P: Modeled + DeserializeOwned>(
         &self,
     ) -> ProxyResult<CoreConfig<P>> {
         let accounts = self.get_accounts().await?;
         let users = self.get_users().await?;
         let groups = self.get_groups().await?;
+        let roles = self.get_roles().await?;
         let user_input_policies: Vec<Policy<P>> =
             self.get_policies_by_model(&POLICY_MODEL.load()).await?;
         let condition_policies = self.get_policies_by_model(CONDITION).await?;
 
         let user_group_relationships = self.get_user_group_relationships().await?;
+        let group_parent_relationships = self.get_group_parent_relationships().await?;
+        let user_role_relationships = self.get_user_role_relationships().await?;
         let policy_relationships = self.get_policy_relationships().await?;
+        let endpoints = self.get_endpoints().await?;
 
         Ok(CoreConfig {
             accounts,
             users,
             groups,
+            roles,
             user_input_policies,
             condition_policies,
             user_group_relationships,
+            group_parent_relationships,
+            user_role_relationships,
             policy_relationships,
+            endpoints,
         })
     }
 
@@ -90,26 +208,23 @@ impl ManagerClient {
     }
 
     async fn get_resource<T: DeserializeOwned>(&self, path: &str) -> ProxyResult<T> {
-        // manually decrypt for HTTP
-        let resource_string = decrypt(self.get_resource_string(path).await?);
-        let resource = serde_yaml::from_str(&resource_string)
-            .map_err(|e| deserialize(path, resource_string, e))?;
-        Ok(resource)
+        decrypt_resource(path, self.get_resource_string(path).await?)
     }
 
     async fn get_resource_string(&self, path: &str) -> ProxyResult<String> {
-        let url = format!("{}/{}/{}", PIAM_MANAGER_ADDRESS.load(), VERSION, path);
         // A native-tls/rust-tls related issue:
         // default-features = false, features = ["rustls-tls"] for reqwest should be set in Cargo.toml,
         // otherwise Segmentation fault (core dumped) may occur when creating a new reqwest client.
-        let client = &self.http_client;
+        let mut request = self.http_client.get(manager_url(path));
+        if let Some(token) = manager_token() {
+            request = request.bearer_auth(token);
+        }
         // response is not Error, but the response body may be an error message without encrypted,
         // and this message will be decrypted later by get_resource function,
         // that will cause an unwrapped error in get_resource function,
         // which in turn leads to a panic in configuration fetching loop, and the loop will exit.
         // So we need to check the response status here.
-        let response = client
-            .get(&url)
+        let response = request
             .send()
             .await?
             .error_for_status()
@@ -117,3 +232,20 @@ impl ManagerClient {
         Ok(response.text().await?)
     }
 }
+
+fn decrypt_resource<T: DeserializeOwned>(path: &str, encrypted: String) -> ProxyResult<T> {
+    // manually decrypt for HTTP
+    let resource_string = decrypt(encrypted);
+    let resource = serde_yaml::from_str(&resource_string)
+        .map_err(|e| deserialize(path, resource_string, e))?;
+    Ok(resource)
+}
+
+fn manager_url(path: &str) -> String {
+    format!("{}/{}/{}", PIAM_MANAGER_ADDRESS.load(), VERSION, path)
+}
+
+fn manager_token() -> Option<String> {
+    let token = PIAM_MANAGER_TOKEN.load();
+    (!token.is_empty()).then(|| token.to_string())
+}
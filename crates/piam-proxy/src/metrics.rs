@@ -0,0 +1,241 @@
+//! Prometheus metrics of the proxy, served in the text format at [`METRICS_PATH`].
+//!
+//! `/metrics` is also a valid path-style bucket, so it is served by [`serve`] on its own port
+//! `METRICS_PORT` rather than next to the proxied routes.
+
+use std::{net::SocketAddr, time::Duration};
+
+use axum::{routing::get, Router};
+use busylib::prelude::EnhancedExpect;
+use http::{header::CONTENT_TYPE, HeaderMap, StatusCode};
+use log::{error, info};
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::{
+    config::METRICS_PORT,
+    error::{ProxyError, ProxyResult},
+};
+
+pub const METRICS_PATH: &str = "/metrics";
+
+static REGISTRY: Lazy<Registry> = Lazy::new(|| {
+    Registry::new_custom(Some("piam_proxy".to_string()), None)
+        .ex("metrics registry should be created")
+});
+
+static REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("requests_total", "Requests handled"),
+        &["action", "decision", "error"],
+    ))
+});
+static REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register(HistogramVec::new(
+        HistogramOpts::new("request_duration_seconds", "Time to handle a request"),
+        &["action", "decision", "error"],
+    ))
+});
+static UPSTREAM_RESPONSES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new(
+            "upstream_responses_total",
+            "Responses of forwarded requests by status code, `error` if none is received",
+        ),
+        &["status"],
+    ))
+});
+static RECEIVED_BYTES: Lazy<IntCounter> = Lazy::new(|| {
+    register(IntCounter::new(
+        "received_bytes_total",
+        "Content length of the requests forwarded to upstream",
+    ))
+});
+static SENT_BYTES: Lazy<IntCounter> = Lazy::new(|| {
+    register(IntCounter::new(
+        "sent_bytes_total",
+        "Content length of the responses received from upstream",
+    ))
+});
+static CONFIG_REFRESH_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register(Histogram::with_opts(HistogramOpts::new(
+        "config_refresh_duration_seconds",
+        "Time to fetch the config from the manager and build the state from it",
+    )))
+});
+static CONFIG_REFRESH_FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+    register(IntCounter::new(
+        "config_refresh_failures_total",
+        "Failed attempts to refresh the config, including revision checks",
+    ))
+});
+static CONTAINER_SIZE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register(IntGaugeVec::new(
+        Opts::new("container_size", "Entities in the state in use by kind"),
+        &["kind"],
+    ))
+});
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(metric: prometheus::Result<T>) -> T {
+    let metric = metric.ex("metric options should be valid");
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .ex("metric should only be registered once");
+    metric
+}
+
+/// Outcome of a request for the `decision` label
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Decision {
+    Allow,
+    Deny,
+    Error,
+}
+
+impl Decision {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Allow => "allow",
+            Self::Deny => "deny",
+            Self::Error => "error",
+        }
+    }
+
+    /// Requests rejected for lack of an allowing policy are denied, other errors are errors
+    pub const fn from_error(error: &ProxyError) -> Self {
+        match error {
+            ProxyError::EffectNotFound(_) | ProxyError::MissingPolicy(_) => Self::Deny,
+            _ => Self::Error,
+        }
+    }
+}
+
+/// Records a handled request. `action` is the action of the parsed input, e.g.
+/// `ObjectStorageInput::action`, empty if the request could not be parsed.
+pub fn observe_request<T>(action: &str, result: &ProxyResult<T>, elapsed: Duration) {
+    let (decision, error) = match result {
+        Ok(_) => (Decision::Allow, ""),
+        Err(e) => (Decision::from_error(e), e.name()),
+    };
+    let labels = [action, decision.as_str(), error];
+    REQUESTS.with_label_values(&labels).inc();
+    REQUEST_DURATION
+        .with_label_values(&labels)
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Records a request forwarded to upstream and its response, [`None`] if it failed without one.
+/// Bytes are taken from [`content_length`].
+pub fn observe_upstream(status: Option<StatusCode>, received_bytes: u64, sent_bytes: u64) {
+    let status = status.map_or_else(|| "error".to_string(), |s| s.as_u16().to_string());
+    UPSTREAM_RESPONSES.with_label_values(&[&status]).inc();
+    RECEIVED_BYTES.inc_by(received_bytes);
+    SENT_BYTES.inc_by(sent_bytes);
+}
+
+/// Records an attempt to refresh the config
+pub fn observe_config_refresh(succeeded: bool, elapsed: Duration) {
+    CONFIG_REFRESH_DURATION.observe(elapsed.as_secs_f64());
+    if !succeeded {
+        CONFIG_REFRESH_FAILURES.inc();
+    }
+}
+
+pub fn observe_config_refresh_failure() {
+    CONFIG_REFRESH_FAILURES.inc();
+}
+
+/// Sizes of the state in use, see `IamContainer::sizes`
+pub fn set_container_sizes(sizes: &[(&str, usize)]) {
+    for (kind, size) in sizes {
+        CONTAINER_SIZE
+            .with_label_values(&[kind])
+            .set(i64::try_from(*size).unwrap_or(i64::MAX));
+    }
+}
+
+/// 0 if unknown, e.g. chunked
+pub fn content_length(headers: &HeaderMap) -> u64 {
+    headers
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_default()
+}
+
+/// All metrics in the Prometheus text format
+pub fn gather() -> String {
+    let mut buf = Vec::new();
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buf)
+        .ex("metrics should be encoded");
+    String::from_utf8(buf).ex("metrics should be utf-8")
+}
+
+pub async fn metrics() -> (StatusCode, [(http::HeaderName, &'static str); 1], String) {
+    (
+        StatusCode::OK,
+        [(CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        gather(),
+    )
+}
+
+/// Route of [`METRICS_PATH`]
+pub fn routes<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    Router::new().route(METRICS_PATH, get(metrics))
+}
+
+/// Serves [`routes`] on `METRICS_PORT` until the server fails, meant to be spawned
+pub async fn serve() {
+    let port: u16 = match METRICS_PORT.load().trim().parse() {
+        Ok(port) => port,
+        Err(e) => {
+            error!("invalid METRICS_PORT '{}': {}", METRICS_PORT.load(), e);
+            return;
+        }
+    };
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    info!("serving metrics on {}{}", addr, METRICS_PATH);
+    if let Err(e) = axum::Server::bind(&addr)
+        .serve(routes::<()>().into_make_service())
+        .await
+    {
+        error!("metrics server failed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use http::StatusCode;
+
+    use crate::{
+        error::{ProxyError, ProxyResult},
+        metrics::{gather, observe_request, observe_upstream, set_container_sizes},
+    };
+
+    #[test]
+    fn gather_observed() {
+        let ok: ProxyResult<()> = Ok(());
+        let denied: ProxyResult<()> = Err(ProxyError::EffectNotFound("denied".into()));
+        observe_request("GetObject", &ok, Duration::from_millis(5));
+        observe_request("PutObject", &denied, Duration::from_millis(5));
+        observe_upstream(Some(StatusCode::OK), 42, 0);
+        set_container_sizes(&[("users", 3)]);
+
+        let text = gather();
+        assert!(text.contains(
+            r#"piam_proxy_requests_total{action="GetObject",decision="allow",error=""} 1"#
+        ));
+        assert!(text.contains(
+            r#"piam_proxy_requests_total{action="PutObject",decision="deny",error="EffectNotFound"} 1"#
+        ));
+        assert!(text.contains(r#"piam_proxy_upstream_responses_total{status="200"} 1"#));
+        assert!(text.contains("piam_proxy_received_bytes_total 42"));
+        assert!(text.contains(r#"piam_proxy_container_size{kind="users"} 3"#));
+    }
+}
@@ -4,6 +4,7 @@ use piam_core::{
     policy::{Modeled, Policy},
 };
 use serde::de::DeserializeOwned;
+use tracing::instrument;
 
 use crate::error::ProxyResult;
 
@@ -20,6 +21,7 @@ where
     P: Modeled<Input = I> + DeserializeOwned,
     I: Input,
 {
+    #[instrument(skip_all, fields(policies = self.len()))]
     fn find_effects(&self, input: &I) -> ProxyResult<Vec<&Effect>> {
         let mut effects = Vec::new();
         for policy in self {
@@ -0,0 +1,294 @@
+//! Declarative policy test cases.
+//!
+//! Each [`PolicyCase`] describes a principal, an input and the expected decision. Cases are
+//! evaluated against a config snapshot with the same lookups the proxy does for every request,
+//! so a policy repository can catch regressions before pushing to piam manager, e.g. in CI with
+//! the `piam-policy-test` binary of the `policy-test` feature.
+//!
+//! Cases are written in YAML:
+//!
+//! ```yaml
+//! - name: team can not read private reports
+//!   principal:
+//!     base_access_key: AKPSTEAMXXX
+//!     account_code: "0001"
+//!     region: us-east-1
+//!   input: !GetObject
+//!     bucket: reports
+//!     key: private/2023.csv
+//!   expect: deny
+//! ```
+
+use std::fmt::{Display, Formatter};
+
+use piam_core::{condition::input::ConditionCtx, effect::Effect, input::Input, policy::Modeled};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    container::IamContainer,
+    error::{deserialize, ProxyError, ProxyResult},
+    policy::FindEffect,
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    Allow,
+    Deny,
+}
+
+impl Decision {
+    /// Same rules as `HttpRequestExt::apply_effects`: any deny or no allow at all denies.
+    pub fn from_effects(effects: &[&Effect]) -> Self {
+        match effects.iter().any(|e| e.is_deny()) || !effects.iter().any(|e| e.is_allow()) {
+            true => Self::Deny,
+            false => Self::Allow,
+        }
+    }
+}
+
+impl Display for Decision {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Allow => write!(f, "allow"),
+            Self::Deny => write!(f, "deny"),
+        }
+    }
+}
+
+/// The identity that sends the request, as it would be extracted from the signature.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CasePrincipal {
+    pub base_access_key: String,
+    pub account_code: String,
+    pub region: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PolicyCase<I> {
+    pub name: String,
+    pub principal: CasePrincipal,
+    /// Defaults to an empty context, so only condition policies without range match. The user
+    /// kind is always the one of the principal.
+    #[serde(default)]
+    pub condition: ConditionCtx,
+    pub input: I,
+    pub expect: Decision,
+}
+
+/// A case whose actual outcome differs from the expected one.
+#[derive(Debug)]
+pub struct PolicyCaseMismatch {
+    pub name: String,
+    pub expect: Decision,
+    /// [`None`] if the evaluation failed with an error that is not a denial
+    pub actual: Option<Decision>,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct PolicyCaseReport {
+    pub total: usize,
+    pub mismatches: Vec<PolicyCaseMismatch>,
+}
+
+impl Display for PolicyCaseReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "policy cases: {} total, {} mismatched",
+            self.total,
+            self.mismatches.len()
+        )?;
+        for m in &self.mismatches {
+            let actual = m
+                .actual
+                .map_or_else(|| "error".to_string(), |d| d.to_string());
+            writeln!(
+                f,
+                "  '{}': expect: {}, actual: {}, reason: {}",
+                m.name, m.expect, actual, m.reason
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub fn load_cases<I: DeserializeOwned>(yaml: &str) -> ProxyResult<Vec<PolicyCase<I>>> {
+    serde_yaml::from_str(yaml).map_err(|e| deserialize("policy cases", yaml.to_string(), e))
+}
+
+pub fn run_cases<P, I>(container: &IamContainer<P>, cases: &[PolicyCase<I>]) -> PolicyCaseReport
+where
+    P: Modeled<Input = I> + DeserializeOwned,
+    I: Input,
+{
+    let mismatches = cases
+        .iter()
+        .filter_map(|case| {
+            let (actual, reason) = match evaluate(container, case) {
+                Ok((decision, reason)) => (Some(decision), reason),
+                Err(e) if is_denial(&e) => (Some(Decision::Deny), e.to_string()),
+                Err(e) => (None, e.to_string()),
+            };
+            (actual != Some(case.expect)).then(|| PolicyCaseMismatch {
+                name: case.name.clone(),
+                expect: case.expect,
+                actual,
+                reason,
+            })
+        })
+        .collect();
+    PolicyCaseReport {
+        total: cases.len(),
+        mismatches,
+    }
+}
+
+/// Evaluate a case the way the proxy authorizes a request, returning the decision and the
+/// effects that led to it.
+pub fn evaluate<P, I>(
+    container: &IamContainer<P>,
+    case: &PolicyCase<I>,
+) -> ProxyResult<(Decision, String)>
+where
+    P: Modeled<Input = I> + DeserializeOwned,
+    I: Input,
+{
+    let principal = &case.principal;
+    let account = container.find_account_by_code(&principal.account_code)?;
+    let user = container.find_user_by_base_access_key(&principal.base_access_key)?;
+    let policies = container.find_policies_by_user(user, account, &principal.region)?;
+
+    let condition = case.condition.clone().user_kind(user.kind);
+    let mut effects = policies.condition.find_effects(&condition)?;
+    effects.extend(policies.user_input.find_effects(&case.input)?);
+    Ok((
+        Decision::from_effects(&effects),
+        format!("effects: {:?}", effects),
+    ))
+}
+
+/// Errors the proxy responds to with 403
+const fn is_denial(err: &ProxyError) -> bool {
+    matches!(
+        err,
+        ProxyError::InvalidAccessKey(_)
+            | ProxyError::MissingPolicy(_)
+            | ProxyError::EffectNotFound(_)
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use piam_object_storage::{input::ObjectStorageInput, policy::ObjectStoragePolicy};
+
+    use crate::{
+        config::{CoreConfig, POLICY_MODEL},
+        container::IamContainer,
+        policy_case::{load_cases, run_cases, Decision},
+        state::CoreState,
+    };
+
+    const SNAPSHOT: &str = r#"
+accounts:
+  - id: account1
+    code: "0001"
+    access_key: ak
+    secret_key: sk
+    comment: ""
+users:
+  - id: user1
+    name: alice
+    base_access_key: AKPSPERSALICE
+    secret: ""
+    kind: Person
+groups:
+  - id: group1
+    name: team
+user_input_policies:
+  - kind: ObjectStorage
+    version: 1
+    id: policy1
+    name: reports
+    modeled_policy:
+      - version: 1
+        id: modeled1
+        input_policy:
+          actions: [GetObject]
+          bucket:
+            name:
+              eq: [reports]
+            allow: {}
+          keys:
+            - path:
+                start_with: [reports/public/]
+              allow: {}
+            - path:
+                start_with: [reports/private/]
+              deny: ~
+condition_policies: []
+user_group_relationships:
+  - id: rel1
+    user_id: user1
+    group_id: group1
+policy_relationships:
+  - id: rel2
+    policy_model: ObjectStorage
+    group_id: group1
+    account_id: account1
+    region: us-east-1
+    policy_id: policy1
+"#;
+
+    const CASES: &str = r#"
+- name: public reports are readable
+  principal: &alice
+    base_access_key: AKPSPERSALICE
+    account_code: "0001"
+    region: us-east-1
+  input: !GetObject
+    bucket: reports
+    key: public/2023.csv
+  expect: allow
+- name: private reports are not readable
+  principal: *alice
+  input: !GetObject
+    bucket: reports
+    key: private/2023.csv
+  expect: deny
+- name: writing public reports (deliberately wrong expectation)
+  principal: *alice
+  input: !PutObject
+    bucket: reports
+    key: public/2023.csv
+  expect: allow
+- name: unknown user
+  principal:
+    base_access_key: AKPSPERSBOB
+    account_code: "0001"
+    region: us-east-1
+  input: ListBuckets
+  expect: deny
+"#;
+
+    #[test]
+    fn run_policy_cases() {
+        POLICY_MODEL.store(Arc::new("ObjectStorage"));
+        let config: CoreConfig<ObjectStoragePolicy> = serde_yaml::from_str(SNAPSHOT).unwrap();
+        let container = IamContainer::new_from(config).unwrap();
+        let cases = load_cases::<ObjectStorageInput>(CASES).unwrap();
+
+        let report = run_cases(&container, &cases);
+        assert_eq!(report.total, 4);
+        assert_eq!(report.mismatches.len(), 1);
+        let mismatch = &report.mismatches[0];
+        assert_eq!(
+            mismatch.name,
+            "writing public reports (deliberately wrong expectation)"
+        );
+        assert_eq!(mismatch.actual, Some(Decision::Deny));
+    }
+}
@@ -0,0 +1,244 @@
+//! In-memory index of `PolicyRelationship`s by account, region and user/group/role/user kind.
+//!
+//! `ANY` is stored as a key like any other id, and is looked up along with the queried one, so
+//! finding the relationships of a request costs a few hash lookups instead of a full scan.
+
+use std::collections::HashMap;
+
+use busylib::ANY;
+use piam_core::{principal::UserKind, relation_model::PolicyRelationship, IamIdentity};
+
+use crate::container::PolicyFilterParams;
+
+/// Positions of the relationships in one account and region
+#[derive(Debug, Default)]
+struct Scope {
+    all: Vec<usize>,
+    by_user: HashMap<String, Vec<usize>>,
+    by_group: HashMap<String, Vec<usize>>,
+    by_role: HashMap<String, Vec<usize>>,
+    /// Bound to no user, group or role, by their user kind if any
+    unbound: HashMap<Option<UserKind>, Vec<usize>>,
+}
+
+#[derive(Debug, Default)]
+pub struct PolicyRelationshipIndex {
+    relationships: Vec<PolicyRelationship>,
+    /// account id -> region -> scope
+    scopes: HashMap<String, HashMap<String, Scope>>,
+}
+
+impl PolicyRelationshipIndex {
+    pub fn new(relationships: Vec<PolicyRelationship>) -> Self {
+        let mut scopes: HashMap<String, HashMap<String, Scope>> = HashMap::new();
+        for (i, r) in relationships.iter().enumerate() {
+            let scope = scopes
+                .entry(r.account_id.clone())
+                .or_default()
+                .entry(r.region.clone())
+                .or_default();
+            scope.all.push(i);
+            if let Some(user_id) = &r.user_id {
+                scope.by_user.entry(user_id.clone()).or_default().push(i);
+            }
+            if let Some(group_id) = &r.group_id {
+                scope.by_group.entry(group_id.clone()).or_default().push(i);
+            }
+            if let Some(role_id) = &r.role_id {
+                scope.by_role.entry(role_id.clone()).or_default().push(i);
+            }
+            if r.user_id.is_none() && r.group_id.is_none() && r.role_id.is_none() {
+                scope.unbound.entry(r.user_kind).or_default().push(i);
+            }
+        }
+        Self {
+            relationships,
+            scopes,
+        }
+    }
+
+    pub fn relationships(&self) -> &[PolicyRelationship] {
+        &self.relationships
+    }
+
+    /// Same result as [`scan`], in the same order.
+    pub fn find(&self, f: &PolicyFilterParams) -> Vec<&PolicyRelationship> {
+        let mut positions: Vec<usize> = Vec::new();
+        for scope in self.scopes_of(f) {
+            // candidates are the ones bound to any principal of the filter, the rest is checked
+            // by `matches`
+            if let Some(groups) = f.groups {
+                let ids = groups.iter().map(|g| g.id_str());
+                Self::extend_by(&mut positions, &scope.by_group, ids);
+            }
+            if let Some(user) = f.user {
+                let ids = std::iter::once(user.id_str());
+                Self::extend_by(&mut positions, &scope.by_user, ids);
+            }
+            if let Some(roles) = f.roles {
+                let ids = roles.iter().map(|r| r.id_str());
+                Self::extend_by(&mut positions, &scope.by_role, ids);
+            }
+            match (f.groups, f.user, f.roles, f.user_kind) {
+                (None, None, None, Some(user_kind)) => {
+                    for key in [Some(*user_kind), None] {
+                        positions.extend(scope.unbound.get(&key).into_iter().flatten());
+                    }
+                }
+                (None, None, None, None) => positions.extend(&scope.all),
+                _ => {}
+            }
+        }
+        positions.sort_unstable();
+        positions.dedup();
+        positions
+            .into_iter()
+            .map(|i| &self.relationships[i])
+            .filter(|r| f.matches(r))
+            .collect()
+    }
+
+    fn scopes_of<'a>(&'a self, f: &'a PolicyFilterParams) -> impl Iterator<Item = &'a Scope> {
+        [f.account.id.as_str(), ANY]
+            .into_iter()
+            .filter_map(|account_id| self.scopes.get(account_id))
+            .flat_map(move |regions| {
+                [f.target_region, ANY]
+                    .into_iter()
+                    .filter_map(|region| regions.get(region))
+            })
+    }
+
+    fn extend_by<'a>(
+        positions: &mut Vec<usize>,
+        index: &HashMap<String, Vec<usize>>,
+        ids: impl Iterator<Item = &'a str>,
+    ) {
+        for id in ids.chain(std::iter::once(ANY)) {
+            positions.extend(index.get(id).into_iter().flatten());
+        }
+    }
+}
+
+/// Linear scan over all relationships, the reference implementation of
+/// [`PolicyRelationshipIndex::find`].
+pub fn scan<'a>(
+    relationships: &'a [PolicyRelationship],
+    f: &PolicyFilterParams,
+) -> Vec<&'a PolicyRelationship> {
+    relationships.iter().filter(|r| f.matches(r)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use busylib::ANY;
+    use piam_core::{
+        account::aws::AwsAccount,
+        group::Group,
+        principal::{Role, User, UserKind},
+        relation_model::PolicyRelationship,
+    };
+
+    use crate::{
+        container::PolicyFilterParams,
+        policy_index::{scan, PolicyRelationshipIndex},
+    };
+
+    #[test]
+    fn find_same_as_scan() {
+        let ids = |prefix: &str| {
+            vec![
+                format!("{prefix}1"),
+                format!("{prefix}2"),
+                format!("{prefix}3"),
+                ANY.to_string(),
+            ]
+        };
+        let (accounts, regions, principals) = (ids("account"), ids("region"), ids("principal"));
+        let mut relationships = Vec::new();
+        for account_id in &accounts {
+            for region in &regions {
+                for (i, principal) in principals.iter().enumerate() {
+                    let principal = Some(principal.clone());
+                    relationships.push(PolicyRelationship {
+                        id: relationships.len().to_string(),
+                        account_id: account_id.clone(),
+                        region: region.clone(),
+                        user_id: principal.clone().filter(|_| i % 2 == 0),
+                        group_id: principal.clone().filter(|_| i != 1),
+                        role_id: principal.filter(|_| i == 1),
+                        user_kind: (i == 3).then_some(UserKind::Customer),
+                        ..Default::default()
+                    });
+                }
+                for user_kind in [None, Some(UserKind::Customer)] {
+                    relationships.push(PolicyRelationship {
+                        id: relationships.len().to_string(),
+                        account_id: account_id.clone(),
+                        region: region.clone(),
+                        user_kind,
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+        let index = PolicyRelationshipIndex::new(relationships);
+
+        let roles: Vec<Role> = principals
+            .iter()
+            .map(|id| Role {
+                id: id.clone(),
+                ..Default::default()
+            })
+            .collect();
+        let groups: Vec<Group> = principals
+            .iter()
+            .map(|id| Group {
+                id: id.clone(),
+                ..Default::default()
+            })
+            .collect();
+        for account_id in &accounts {
+            let account = AwsAccount {
+                id: account_id.clone(),
+                ..Default::default()
+            };
+            for region in &regions {
+                for (i, group) in groups.iter().enumerate() {
+                    let user = User {
+                        id: group.id.clone(),
+                        ..Default::default()
+                    };
+                    let some_groups = groups[..=i].iter().collect::<Vec<_>>();
+                    let some_roles = roles[..=i].iter().collect::<Vec<_>>();
+                    let params = [
+                        PolicyFilterParams::new_with(&account, region),
+                        PolicyFilterParams::new_with(&account, region).user(&user),
+                        PolicyFilterParams::new_with(&account, region).groups(&some_groups),
+                        PolicyFilterParams::new_with(&account, region)
+                            .user(&user)
+                            .groups(&some_groups),
+                        PolicyFilterParams::new_with(&account, region).user_kind(&user.kind),
+                        PolicyFilterParams::new_with(&account, region)
+                            .user_kind(&UserKind::Customer),
+                        PolicyFilterParams::new_with(&account, region)
+                            .user(&user)
+                            .user_kind(&UserKind::Customer),
+                        PolicyFilterParams::new_with(&account, region)
+                            .groups(&some_groups)
+                            .user_kind(&UserKind::Customer),
+                        PolicyFilterParams::new_with(&account, region).roles(&some_roles),
+                    ];
+                    for f in &params {
+                        let expected = scan(index.relationships(), f);
+                        let actual = index.find(f);
+                        let ids = |v: Vec<&PolicyRelationship>| {
+                            v.into_iter().map(|r| r.id.clone()).collect::<Vec<_>>()
+                        };
+                        assert_eq!(ids(actual), ids(expected), "{}", f);
+                    }
+                }
+            }
+        }
+    }
+}
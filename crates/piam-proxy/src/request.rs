@@ -1,8 +1,11 @@
 use log::debug;
-use piam_core::{account::aws::AwsAccount, effect::Effect};
+use piam_core::{account::aws::AwsAccount, condition::input::RequestAttrs, effect::Effect};
+use tracing::{instrument, Span};
 
 use crate::{
+    endpoint::builtin_host,
     error::{ProxyError, ProxyResult},
+    metrics,
     type_alias::{HttpClient, HttpRequest, HttpResponse},
 };
 
@@ -14,6 +17,10 @@ pub struct AccessTarget {
 
 pub trait HttpRequestExt {
     fn apply_effects(self, effect: Vec<&Effect>) -> ProxyResult<HttpRequest>;
+
+    /// Attributes matched by `RequestRange` of condition policies, `tls` tells whether the
+    /// client connection was TLS
+    fn request_attrs(&self, tls: bool) -> RequestAttrs;
 }
 
 impl HttpRequestExt for HttpRequest {
@@ -52,30 +59,43 @@ impl HttpRequestExt for HttpRequest {
             Ok(self)
         }
     }
+
+    fn request_attrs(&self, tls: bool) -> RequestAttrs {
+        RequestAttrs::new_with(self, tls)
+    }
 }
 
+#[deprecated(note = "use `EndpointRegistry::find`, which also knows the configured endpoints")]
 pub fn from_region_to_endpoint(region: &str) -> ProxyResult<String> {
+    #[allow(deprecated)]
     Ok(format!("http://{}", from_region_to_host(region)?))
 }
 
+#[deprecated(note = "use `EndpointRegistry::find`, which also knows the configured endpoints")]
 pub fn from_region_to_host(region: &str) -> ProxyResult<&'static str> {
-    match region {
-        // aws
-        "cn-northwest-1" => Ok("s3.cn-northwest-1.amazonaws.com.cn"),
-        "us-east-1" => Ok("s3.us-east-1.amazonaws.com"),
-        "us-east-2" => Ok("s3.us-east-2.amazonaws.com"),
-        "eu-central-1" => Ok("s3.eu-central-1.amazonaws.com"),
-        // tencent
-        "ap-shanghai" => Ok("cos.ap-shanghai.myqcloud.com"),
-        "na-ashburn" => Ok("cos.na-ashburn.myqcloud.com"),
-        _ => Err(ProxyError::InvalidRegion(format!(
-            "unsupported region: {region}",
-        ))),
-    }
+    builtin_host(region)
+        .ok_or_else(|| ProxyError::InvalidRegion(format!("unsupported region: {region}",)))
 }
 
+#[instrument(skip_all, fields(host, status))]
 pub async fn forward(new_req: HttpRequest, client: &HttpClient) -> ProxyResult<HttpResponse> {
     debug!("new_req headers {:#?}", new_req.headers());
-    let res = client.request(new_req).await;
-    res.map_err(|e| ProxyError::OtherInternal(format!("proxy forwarding error: {e}")))
+    let received_bytes = metrics::content_length(new_req.headers());
+    if let Some(host) = new_req.uri().host() {
+        Span::current().record("host", host);
+    }
+    match client.request(new_req).await {
+        Ok(res) => {
+            Span::current().record("status", res.status().as_u16());
+            let sent_bytes = metrics::content_length(res.headers());
+            metrics::observe_upstream(Some(res.status()), received_bytes, sent_bytes);
+            Ok(res)
+        }
+        Err(e) => {
+            metrics::observe_upstream(None, received_bytes, 0);
+            Err(ProxyError::OtherInternal(format!(
+                "proxy forwarding error: {e}"
+            )))
+        }
+    }
 }
@@ -0,0 +1,76 @@
+//! Memoized policy resolution of principals.
+//!
+//! The cache lives in `IamContainer`, so each `ProxyState` owns its own one, and replacing the
+//! state on update starts over with an empty cache.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use piam_core::{account::AccountId, policy::PolicyId};
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ResolutionKey {
+    pub base_access_key: String,
+    pub account_id: AccountId,
+    pub region: String,
+}
+
+impl ResolutionKey {
+    pub fn new(base_access_key: &str, account_id: &str, region: &str) -> Self {
+        Self {
+            base_access_key: base_access_key.to_string(),
+            account_id: account_id.to_string(),
+            region: region.to_string(),
+        }
+    }
+}
+
+/// Ids of the policies found for a principal, see `FoundPolicies`
+#[derive(Debug, Default)]
+pub struct ResolvedPolicyIds {
+    pub condition: Vec<PolicyId>,
+    pub user_input: Vec<PolicyId>,
+}
+
+/// Only successful resolutions of known regions are cached, so the size is bounded by the number
+/// of users, accounts and regions in the config.
+#[derive(Debug, Default)]
+pub struct ResolutionCache {
+    /// Regions of endpoints and policy relationships. Regions of requests come from clients, and
+    /// any of them resolves with relationships of `ANY` region, so others are not cached.
+    regions: HashSet<String>,
+    entries: RwLock<HashMap<ResolutionKey, Arc<ResolvedPolicyIds>>>,
+}
+
+impl ResolutionCache {
+    pub fn new(regions: HashSet<String>) -> Self {
+        Self {
+            regions,
+            entries: RwLock::default(),
+        }
+    }
+
+    pub fn get(&self, key: &ResolutionKey) -> Option<Arc<ResolvedPolicyIds>> {
+        let entries = self.entries.read().unwrap_or_else(PoisonError::into_inner);
+        entries.get(key).cloned()
+    }
+
+    pub fn insert(&self, key: ResolutionKey, resolved: ResolvedPolicyIds) {
+        if !self.regions.contains(&key.region) {
+            return;
+        }
+        let mut entries = self.entries.write().unwrap_or_else(PoisonError::into_inner);
+        entries.insert(key, Arc::new(resolved));
+    }
+
+    pub fn len(&self) -> usize {
+        let entries = self.entries.read().unwrap_or_else(PoisonError::into_inner);
+        entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -9,6 +9,7 @@ use uuid::Uuid;
 use crate::{
     config::{proxy_region_env, PROXY_TYPE},
     error::ProxyError,
+    telemetry::{current_request_id, current_trace_id},
     type_alias::HttpResponse,
 };
 
@@ -30,6 +31,12 @@ impl HttpResponseExt for HttpResponse {
             HeaderValue::from_str(&proxy_region_env()).unwp(),
         );
         headers.append("x-patsnap-request-id", HeaderValue::from_str(&id).unwp());
+        if let Some(trace_id) = current_trace_id() {
+            headers.append(
+                "x-patsnap-trace-id",
+                HeaderValue::from_str(&trace_id).unwp(),
+            );
+        }
         self
     }
 
@@ -38,18 +45,31 @@ impl HttpResponseExt for HttpResponse {
     }
 }
 
+/// Id of the request being handled, see `with_request_id`, or a new one outside of any
+pub fn request_id() -> String {
+    current_request_id().unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
 impl IntoResponse for ProxyError {
     fn into_response(self) -> axum::response::Response {
-        let id = Uuid::new_v4().to_string();
+        self.into_response_with_id(request_id())
+    }
+}
+
+impl ProxyError {
+    /// The response of the error to the request of `id`
+    pub fn into_response_with_id(self, id: String) -> axum::response::Response {
+        let trace_id = current_trace_id();
         let response_and_trace = |resp_fn: fn(&str, &str, &str) -> HttpResponse, msg, err_type| {
             let trace_info = format!(
                 "proxy_type: {}, proxy_region_env: {}, \
-                error_type: {}, message: {}, x-patsnap-request-id: {}",
+                error_type: {}, message: {}, x-patsnap-request-id: {}, x-patsnap-trace-id: {}",
                 PROXY_TYPE.load(),
                 proxy_region_env(),
                 err_type,
                 msg,
-                id
+                id,
+                trace_id.as_deref().unwrap_or("none")
             );
             (resp_fn(err_type, &trace_info, &id), trace_info)
         };
@@ -77,30 +97,29 @@ impl IntoResponse for ProxyError {
             Self::OtherInternal(msg)
             | Self::ManagerApi(msg)
             | Self::Deserialize(msg)
+            | Self::InvalidConfig(msg)
             | Self::UserNotFound(msg) => {
                 let (r, t) = response_and_trace(internal_err, msg, self.name());
                 error!("{}", t);
                 r
             }
+            // inconsistent state must not take down request handling
+            Self::AssertFail(msg) => {
+                let (r, t) = response_and_trace(internal_err, msg, self.name());
+                error!("assertion failed: {}", t);
+                r
+            }
             Self::FatalError(msg) => {
                 error!("fatal error happened: {}", msg);
                 panic!("fatal error happened: {msg}");
             }
-            Self::AssertFail(msg) => {
-                error!("assertion failed: {}", msg);
-                panic!("assertion failed: {msg}");
-            }
         };
         res.add_piam_headers(id).into_response()
     }
 }
 
 pub fn rejected_by_policy() -> HttpResponse {
-    forbidden(
-        "RejectedByPolicy",
-        "RejectedByPolicy",
-        &Uuid::new_v4().to_string(),
-    )
+    forbidden("RejectedByPolicy", "RejectedByPolicy", &request_id())
 }
 
 pub fn bad_request(code: &str, message: &str, request_id: &str) -> HttpResponse {
@@ -157,3 +176,19 @@ fn aws_xml_error_payload(code: &str, message: &str, request_id: &str) -> String
     };
     serde_xml_rs::to_string(&error).ex("aws_xml_error_payload should not fail")
 }
+
+#[cfg(test)]
+mod test {
+    use axum::response::IntoResponse;
+
+    use crate::{error::ProxyError, telemetry::with_request_id};
+
+    #[tokio::test]
+    async fn error_response_keeps_request_id() {
+        let response = with_request_id("request-id-1".to_string(), async {
+            ProxyError::BadRequest("bad".to_string()).into_response()
+        })
+        .await;
+        assert_eq!(response.headers()["x-patsnap-request-id"], "request-id-1");
+    }
+}
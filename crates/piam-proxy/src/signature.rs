@@ -42,16 +42,31 @@ pub mod aws {
 
     use async_trait::async_trait;
     use aws_sigv4::http_request::{
-        sign, PercentEncodingMode, SignableRequest, SigningParams, SigningSettings,
+        sign, PercentEncodingMode, SignableBody, SignableRequest, SigningParams, SigningSettings,
     };
     use busylib::prelude::EnhancedExpect;
-    use http::{header::AUTHORIZATION, Request};
-    use hyper::{body, Body};
+    use chrono::{NaiveDateTime, TimeZone, Utc};
+    use hmac::{Hmac, Mac};
+    use http::{
+        header::{AUTHORIZATION, HOST},
+        uri::PathAndQuery,
+        HeaderValue, Uri,
+    };
     use piam_core::account::aws::AwsAccount;
+    use sha2::{Digest, Sha256};
+    use tracing::instrument;
+
+    pub use crate::signature::aws::canonical_request::header::X_AMZ_SECURITY_TOKEN;
 
+    /// Seconds a request may be signed away from now, as allowed by AWS
+    pub const MAX_CLOCK_SKEW: u64 = 15 * 60;
+    /// Longest `X-Amz-Expires` of a presigned url, 7 days as allowed by AWS
+    pub const MAX_PRESIGNED_EXPIRES: u64 = 7 * 24 * 3600;
     use crate::{
         error::{ProxyError, ProxyResult},
-        signature::aws::canonical_request::header::*,
+        signature::aws::canonical_request::{
+            header::*, param, AMZ_DATE_FORMAT, HMAC_256, UNSIGNED_PAYLOAD,
+        },
         type_alias::HttpRequest,
     };
 
@@ -82,8 +97,10 @@ pub mod aws {
         }
 
         pub const HMAC_256: &str = "AWS4-HMAC-SHA256";
+        /// Format of `x-amz-date` and `X-Amz-Date`
+        pub const AMZ_DATE_FORMAT: &str = "%Y%m%dT%H%M%SZ";
 
-        const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+        pub const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
         const STREAMING_UNSIGNED_PAYLOAD_TRAILER: &str = "STREAMING-UNSIGNED-PAYLOAD-TRAILER";
     }
 
@@ -93,6 +110,16 @@ pub mod aws {
 
         fn extract_access_key_and_region_from_aws(&self) -> ProxyResult<(&str, &str)>;
 
+        /// `x-amz-security-token`, or `X-Amz-Security-Token` of a presigned url
+        fn security_token(&self) -> Option<String>;
+
+        /// Checks the signature the client made, in `Authorization` or in the query string of a
+        /// presigned url, against `secret_key`. The payload hash is the one the client declared
+        /// in `x-amz-content-sha256`, which upstream checks against the body. Like AWS, requests
+        /// signed more than `MAX_CLOCK_SKEW` away from `now` (unix seconds) are rejected, and
+        /// presigned urls once `X-Amz-Expires` has passed, so that they can not be replayed.
+        fn verify_aws_sigv4(&self, secret_key: &str, now: u64) -> ProxyResult<()>;
+
         async fn sign_with_aws_sigv4_params(
             self,
             params: &AwsSigv4SignParams<'_>,
@@ -115,9 +142,19 @@ pub mod aws {
         }
 
         fn extract_access_key_and_region_from_aws(&self) -> ProxyResult<(&str, &str)> {
-            let auth = self.headers().get(AUTHORIZATION).ok_or_else(|| {
-                ProxyError::InvalidAuthorizationHeader("Missing authorization header".into())
-            })?;
+            let auth = match self.headers().get(AUTHORIZATION) {
+                Some(auth) => auth,
+                None => match raw_query_param(self, param::X_AMZ_CREDENTIAL) {
+                    Some(credential) => {
+                        return extract_access_key_and_region_from_presigned(credential)
+                    }
+                    None => {
+                        return Err(ProxyError::InvalidAuthorizationHeader(
+                            "Missing authorization header".into(),
+                        ))
+                    }
+                },
+            };
             let auth_str = auth.to_str().map_err(|_| {
                 ProxyError::InvalidAuthorizationHeader(format!(
                     "Malformed authorization header, only visible ASCII chars allowed. \
@@ -128,30 +165,94 @@ pub mod aws {
             extract_aws_access_key_and_region_from_auth_header(auth_str)
         }
 
+        fn security_token(&self) -> Option<String> {
+            self.headers().get(X_AMZ_SECURITY_TOKEN).map_or_else(
+                || raw_query_param(self, param::X_AMZ_SECURITY_TOKEN).map(uri_decode),
+                |token| token.to_str().ok().map(str::to_string),
+            )
+        }
+
+        fn verify_aws_sigv4(&self, secret_key: &str, now: u64) -> ProxyResult<()> {
+            let signed = SignedParts::from_request(self)?;
+            let invalid = |msg: &str| ProxyError::InvalidAuthorizationHeader(msg.to_string());
+            let signed_at = NaiveDateTime::parse_from_str(&signed.amz_date, AMZ_DATE_FORMAT)
+                .map_err(|_| invalid("malformed X-Amz-Date"))?;
+            let signed_at = Utc.from_utc_datetime(&signed_at).timestamp();
+            let (now, skew) = (now as i64, MAX_CLOCK_SKEW as i64);
+            if signed_at > now + skew {
+                return Err(invalid("request is signed in the future"));
+            }
+            match signed.expires {
+                Some(expires) if signed_at + (expires as i64) < now => {
+                    return Err(invalid("presigned url expired"))
+                }
+                None if signed_at < now - skew => return Err(invalid("request signature expired")),
+                _ => {}
+            }
+            let signature =
+                hex::decode(&signed.signature).map_err(|_| invalid("malformed signature"))?;
+            // date/region/service/aws4_request
+            let scope = signed
+                .credential
+                .split_once('/')
+                .ok_or_else(|| invalid("malformed credential"))?
+                .1;
+            let scope_parts: Vec<&str> = scope.split('/').collect();
+            let [date, region, service, "aws4_request"] = scope_parts[..] else {
+                return Err(invalid("malformed credential scope"));
+            };
+            if !signed.amz_date.starts_with(date) {
+                return Err(invalid(
+                    "date of credential scope does not match X-Amz-Date",
+                ));
+            }
+
+            let canonical_request = canonical_request(self, &signed)?;
+            let string_to_sign = format!(
+                "{HMAC_256}\n{}\n{scope}\n{}",
+                signed.amz_date,
+                hex::encode(Sha256::digest(canonical_request.as_bytes()))
+            );
+            let mut key = format!("AWS4{secret_key}").into_bytes();
+            for part in [date, region, service, "aws4_request"] {
+                key = hmac(&key, part.as_bytes()).finalize().into_bytes().to_vec();
+            }
+            hmac(&key, string_to_sign.as_bytes())
+                .verify_slice(&signature)
+                .map_err(|_| {
+                    ProxyError::InvalidAuthorizationHeader("signature does not match".into())
+                })
+        }
+
+        #[instrument(
+            skip_all,
+            fields(account_code = %params.account.code, region = params.region)
+        )]
         async fn sign_with_aws_sigv4_params(
             mut self,
             params: &AwsSigv4SignParams<'_>,
         ) -> ProxyResult<Self> {
-            // save checksum before signing
+            // save checksum before signing, presigned urls come without it
             let checksum = self
-                .headers()
-                .get(X_AMZ_CONTENT_SHA_256)
-                .ex("X_AMZ_CONTENT_SHA_256 should be set")
-                .clone();
+                .headers_mut()
+                .remove(X_AMZ_CONTENT_SHA_256)
+                .unwrap_or_else(|| HeaderValue::from_static(UNSIGNED_PAYLOAD));
+            let payload = checksum
+                .to_str()
+                .map_err(|_| {
+                    ProxyError::BadRequest(format!("invalid {X_AMZ_CONTENT_SHA_256} header"))
+                })?
+                .to_string();
 
             // see `aws_sigv4::http_request::sign::calculate_signing_headers`
             self.headers_mut().remove(X_AMZ_DATE);
-            self.headers_mut().remove(X_AMZ_CONTENT_SHA_256);
             self.headers_mut().remove(X_AMZ_SECURITY_TOKEN);
             self.headers_mut().remove(AUTHORIZATION);
             // x-forwarded-for causes SignatureDoesNotMatch(from aws),
             // it is usually added by gateways like kong
             self.headers_mut().remove("x-forwarded-for");
-
-            // convert body to bytes for signing
-            let (p, b) = self.into_parts();
-            let bytes = body::to_bytes(b).await.ex("body to bytes should work");
-            let mut byte_req = Request::from_parts(p, bytes);
+            // the signature of a presigned url, and its session token, are not for upstream
+            *self.uri_mut() = without_presigned_params(self.uri())?;
 
             // do signing
             let mut signing_settings = SigningSettings::default();
@@ -167,15 +268,17 @@ pub mod aws {
                 .settings(signing_settings)
                 .build()
                 .ex("build signing_params should work");
-            let signable_request = SignableRequest::from(&byte_req);
+            // the payload hash the client declared, which upstream checks against the body
+            let signable_request = SignableRequest::new(
+                self.method(),
+                self.uri(),
+                self.headers(),
+                SignableBody::Precomputed(payload),
+            );
             let (signing_instructions, _signature) = sign(signable_request, &signing_params)
                 .ex("sign should work")
                 .into_parts();
-            signing_instructions.apply_to_request(&mut byte_req);
-
-            // restore body from bytes
-            let (p, b) = byte_req.into_parts();
-            self = Self::from_parts(p, Body::from(b));
+            signing_instructions.apply_to_request(&mut self);
 
             // restore checksum after signing
             self.headers_mut().insert(X_AMZ_CONTENT_SHA_256, checksum);
@@ -183,6 +286,243 @@ pub mod aws {
         }
     }
 
+    /// Parts of the signature made by the client
+    struct SignedParts {
+        /// e.g. `AKPSSVCSPROXYDEV/20221012/cn-northwest-1/s3/aws4_request`
+        credential: String,
+        amz_date: String,
+        signed_headers: String,
+        signature: String,
+        presigned: bool,
+        /// `X-Amz-Expires` of a presigned url, in seconds
+        expires: Option<u64>,
+    }
+
+    impl SignedParts {
+        fn from_request(req: &HttpRequest) -> ProxyResult<Self> {
+            let missing = |name: &str| {
+                ProxyError::InvalidAuthorizationHeader(format!("{name} missing for SigV4"))
+            };
+            let Some(auth) = req.headers().get(AUTHORIZATION) else {
+                let param = |name: &'static str| {
+                    raw_query_param(req, name)
+                        .map(uri_decode)
+                        .ok_or_else(|| missing(name))
+                };
+                if param(param::X_AMZ_ALGORITHM)? != HMAC_256 {
+                    return Err(missing(HMAC_256));
+                }
+                let expires = param(param::X_AMZ_EXPIRES)?
+                    .parse()
+                    .ok()
+                    .filter(|expires| *expires <= MAX_PRESIGNED_EXPIRES)
+                    .ok_or_else(|| {
+                        ProxyError::InvalidAuthorizationHeader(format!(
+                            "{} must be at most {MAX_PRESIGNED_EXPIRES} seconds",
+                            param::X_AMZ_EXPIRES
+                        ))
+                    })?;
+                return Ok(Self {
+                    credential: param(param::X_AMZ_CREDENTIAL)?,
+                    amz_date: param(param::X_AMZ_DATE)?,
+                    signed_headers: param(param::X_AMZ_SIGNED_HEADERS)?,
+                    signature: param(param::X_AMZ_SIGNATURE)?,
+                    presigned: true,
+                    expires: Some(expires),
+                });
+            };
+            // AWS4-HMAC-SHA256 Credential=..., SignedHeaders=..., Signature=...
+            let auth = auth.to_str().map_err(|_| missing("Authorization"))?;
+            let fields = auth
+                .strip_prefix(HMAC_256)
+                .ok_or_else(|| missing(HMAC_256))?;
+            let field = |name: &str| {
+                fields
+                    .split(',')
+                    .find_map(|f| f.trim().strip_prefix(name)?.strip_prefix('='))
+                    .map(str::to_string)
+                    .ok_or_else(|| missing(name))
+            };
+            let amz_date = req
+                .headers()
+                .get(X_AMZ_DATE)
+                .and_then(|v| v.to_str().ok())
+                .ok_or_else(|| missing(X_AMZ_DATE))?;
+            Ok(Self {
+                credential: field("Credential")?,
+                amz_date: amz_date.to_string(),
+                signed_headers: field("SignedHeaders")?,
+                signature: field("Signature")?,
+                presigned: false,
+                expires: None,
+            })
+        }
+    }
+
+    /// The way S3 canonicalizes requests, the path is taken as sent since it is not encoded twice
+    fn canonical_request(req: &HttpRequest, signed: &SignedParts) -> ProxyResult<String> {
+        let mut query: Vec<(String, String)> = req
+            .uri()
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+            .filter(|(key, _)| *key != param::X_AMZ_SIGNATURE)
+            .map(|(key, value)| (uri_decode(key), uri_decode(value)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(key, value)| format!("{}={}", uri_encode(key), uri_encode(value)))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let mut headers = String::new();
+        for name in signed.signed_headers.split(';') {
+            let values: Vec<String> = req
+                .headers()
+                .get_all(name)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .map(|v| v.split_whitespace().collect::<Vec<_>>().join(" "))
+                .collect();
+            let value = match (values.is_empty(), name == HOST.as_str()) {
+                (false, _) => values.join(","),
+                (true, true) => req
+                    .uri()
+                    .authority()
+                    .map(|a| a.to_string())
+                    .unwrap_or_default(),
+                (true, false) => {
+                    return Err(ProxyError::InvalidAuthorizationHeader(format!(
+                        "signed header {name} missing"
+                    )))
+                }
+            };
+            headers.push_str(&format!("{name}:{value}\n"));
+        }
+
+        let payload = match req.headers().get(X_AMZ_CONTENT_SHA_256) {
+            Some(v) => v.to_str().unwrap_or_default(),
+            None if signed.presigned => UNSIGNED_PAYLOAD,
+            None => {
+                return Err(ProxyError::InvalidAuthorizationHeader(format!(
+                    "{X_AMZ_CONTENT_SHA_256} missing"
+                )))
+            }
+        };
+        let path = match req.uri().path() {
+            "" => "/",
+            path => path,
+        };
+        Ok(format!(
+            "{}\n{path}\n{query}\n{headers}\n{}\n{payload}",
+            req.method(),
+            signed.signed_headers
+        ))
+    }
+
+    /// `uri` without the query parameters of a presigned url, the others are kept as sent
+    fn without_presigned_params(uri: &Uri) -> ProxyResult<Uri> {
+        let Some(query) = uri.query() else {
+            return Ok(uri.clone());
+        };
+        let presigned = [
+            param::X_AMZ_ALGORITHM,
+            param::X_AMZ_CREDENTIAL,
+            param::X_AMZ_DATE,
+            param::X_AMZ_EXPIRES,
+            param::X_AMZ_SECURITY_TOKEN,
+            param::X_AMZ_SIGNED_HEADERS,
+            param::X_AMZ_SIGNATURE,
+        ];
+        let kept: Vec<&str> = query
+            .split('&')
+            .filter(|pair| {
+                let key = pair.split_once('=').map_or(*pair, |(key, _)| key);
+                !presigned.contains(&key)
+            })
+            .collect();
+        let path_and_query = match kept.is_empty() {
+            true => uri.path().to_string(),
+            false => format!("{}?{}", uri.path(), kept.join("&")),
+        };
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = Some(
+            PathAndQuery::try_from(path_and_query)
+                .map_err(|e| ProxyError::BadRequest(format!("invalid uri: {e}")))?,
+        );
+        Uri::from_parts(parts).map_err(|e| ProxyError::BadRequest(format!("invalid uri: {e}")))
+    }
+
+    /// The value of `name` in the query string, still percent encoded
+    fn raw_query_param<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+        req.uri()
+            .query()?
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find_map(|(key, value)| (key == name).then_some(value))
+    }
+
+    /// Everything but the unreserved characters, `/` included
+    fn uri_encode(s: &str) -> String {
+        let mut encoded = String::with_capacity(s.len());
+        for b in s.bytes() {
+            match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    encoded.push(b as char)
+                }
+                _ => encoded.push_str(&format!("%{b:02X}")),
+            }
+        }
+        encoded
+    }
+
+    /// `+` is taken as a space like in forms, invalid escapes are kept as they are
+    fn uri_decode(s: &str) -> String {
+        let bytes = s.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            let escaped = match bytes[i] {
+                b'%' => s
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+                _ => None,
+            };
+            match (escaped, bytes[i]) {
+                (Some(b), _) => {
+                    decoded.push(b);
+                    i += 3;
+                    continue;
+                }
+                (None, b'+') => decoded.push(b' '),
+                (None, b) => decoded.push(b),
+            }
+            i += 1;
+        }
+        String::from_utf8_lossy(&decoded).to_string()
+    }
+
+    fn hmac(key: &[u8], data: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).ex("HMAC should accept keys of any size");
+        mac.update(data);
+        mac
+    }
+
+    /// `AKPSSVCSPROXYDEV%2F20221012%2Fcn-northwest-1%2Fs3%2Faws4_request` of a presigned url
+    fn extract_access_key_and_region_from_presigned(credential: &str) -> ProxyResult<(&str, &str)> {
+        let mut split = credential.split("%2F").flat_map(|s| s.split("%2f"));
+        match (split.next(), split.nth(1)) {
+            (Some(access_key), Some(region)) if !access_key.is_empty() => Ok((access_key, region)),
+            _ => Err(ProxyError::InvalidAuthorizationHeader(format!(
+                "Malformed X-Amz-Credential of presigned url: {}",
+                credential
+            ))),
+        }
+    }
+
     /// example auth_str: "AWS4-HMAC-SHA256 Credential=AKPSSVCSPROXYDEV/20221012/cn-northwest-1/s3/aws4_request ..."
     fn extract_aws_access_key_and_region_from_auth_header(
         auth_str: &str,
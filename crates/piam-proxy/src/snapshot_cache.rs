@@ -0,0 +1,130 @@
+//! Last-known-good config on local disk, so that a proxy can start while the manager is down.
+//!
+//! The snapshot is stored as served by the manager, which is encrypted by `META_KEY`.
+
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use log::warn;
+
+use crate::{
+    config::SNAPSHOT_CACHE_PATH,
+    error::{ProxyError, ProxyResult},
+};
+
+#[derive(Debug)]
+pub struct SnapshotCache {
+    path: PathBuf,
+}
+
+/// A snapshot read from the cache
+#[derive(Debug)]
+pub struct Cached {
+    pub encrypted: String,
+    /// When the snapshot was loaded from the manager
+    pub saved_at: SystemTime,
+}
+
+impl SnapshotCache {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// [`None`] if `SNAPSHOT_CACHE_PATH` is not set
+    pub fn from_env() -> Option<Self> {
+        let path = SNAPSHOT_CACHE_PATH.load();
+        (!path.is_empty()).then(|| Self::new(path.as_str()))
+    }
+
+    /// Replaces the cache atomically, so a crash never leaves a partial snapshot behind. Only the
+    /// owner can read it, as it holds the secrets of all accounts and users.
+    pub fn save(&self, encrypted: &str) -> ProxyResult<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).map_err(|e| io_error("create", parent, e))?;
+        }
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        // a leftover of a crash may have another mode, which is kept by opening it
+        match fs::remove_file(&tmp) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(io_error("remove", &tmp, e))
+            }
+            _ => {}
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options
+            .open(&tmp)
+            .map_err(|e| io_error("create", &tmp, e))?;
+        file.write_all(encrypted.as_bytes())
+            .map_err(|e| io_error("write", &tmp, e))?;
+        fs::rename(&tmp, &self.path).map_err(|e| io_error("rename", &tmp, e))
+    }
+
+    /// [`None`] if nothing has been cached yet
+    pub fn load(&self) -> ProxyResult<Option<Cached>> {
+        let encrypted = match fs::read_to_string(&self.path) {
+            Ok(encrypted) => encrypted,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(io_error("read", &self.path, e)),
+        };
+        let saved_at = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .unwrap_or_else(|e| {
+                warn!("unknown modification time of {:?}: {}", self.path, e);
+                SystemTime::UNIX_EPOCH
+            });
+        Ok(Some(Cached {
+            encrypted,
+            saved_at,
+        }))
+    }
+}
+
+fn io_error(op: &str, path: &Path, e: io::Error) -> ProxyError {
+    ProxyError::OtherInternal(format!(
+        "failed to {} snapshot cache {}: {}",
+        op,
+        path.display(),
+        e
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use crate::snapshot_cache::SnapshotCache;
+
+    #[test]
+    fn save_and_load() {
+        let dir = std::env::temp_dir().join(format!("piam-snapshot-{}", Uuid::new_v4()));
+        let cache = SnapshotCache::new(dir.join("snapshot"));
+        assert!(cache.load().unwrap().is_none());
+
+        cache.save("encrypted1").unwrap();
+        cache.save("encrypted2").unwrap();
+        let cached = cache.load().unwrap().unwrap();
+        assert_eq!(cached.encrypted, "encrypted2");
+        assert!(cached.saved_at.elapsed().unwrap().as_secs() < 60);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(dir.join("snapshot"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,18 +1,29 @@
-use std::{fmt::Debug, sync::Arc, time::Instant};
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
 
 use arc_swap::ArcSwap;
 use async_trait::async_trait;
-use busylib::{config::dev_mode, logger::LogHandle, prelude::EnhancedUnwrap};
+use busylib::{
+    config::dev_mode,
+    logger::LogHandle,
+    prelude::{EnhancedExpect, EnhancedUnwrap},
+};
 use log::{debug, warn};
 use piam_core::policy::Modeled;
 use serde::de::DeserializeOwned;
 
 use crate::{
-    config::{CoreConfig, EXTENDED_CONFIG_TYPE},
+    config::{CoreConfig, STATE_UPDATE_INTERVAL, STATE_WATCH_WAIT},
     container::IamContainer,
     error::ProxyResult,
-    manager_api::ManagerClient,
-    type_alias::HttpClient,
+    health::{staleness_threshold, HealthCheck, SharedHealth},
+    manager_api::{ManagerClient, Revision, Snapshot},
+    metrics,
+    snapshot_cache::SnapshotCache,
+    upstream::UpstreamClients,
 };
 
 pub type ArcState<P, C> = Arc<ArcSwap<ProxyState<P, C>>>;
@@ -27,10 +38,36 @@ pub trait ExtendedState<C, P: Modeled>: Sized {
     async fn with_core_config(self, core_config: &CoreConfig<P>) -> ProxyResult<Self>;
 }
 
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct Health {
+    /// Consecutive failures since the last successful update
     pub state_update_failed_times: i32,
     pub state_last_successful_update_at: Option<Instant>,
+    /// Set if the state is built from the config cached on disk, as the manager was unreachable.
+    /// It is when that config was loaded from the manager, so how stale it may be.
+    pub config_cached_at: Option<SystemTime>,
+}
+
+impl Health {
+    /// The config in use is confirmed up to date with the manager
+    pub fn record_success(&mut self) {
+        self.state_update_failed_times = 0;
+        self.state_last_successful_update_at = Some(Instant::now());
+        self.config_cached_at = None;
+    }
+
+    pub const fn record_failure(&mut self) {
+        self.state_update_failed_times += 1;
+    }
+
+    /// How long ago the config in use was last confirmed up to date, [`None`] if never
+    pub fn staleness(&self) -> Option<Duration> {
+        match (self.state_last_successful_update_at, self.config_cached_at) {
+            (Some(at), _) => Some(at.elapsed()),
+            (None, Some(at)) => Some(at.elapsed().unwrap_or_default()),
+            (None, None) => None,
+        }
+    }
 }
 
 #[derive(Debug, Default)]
@@ -40,7 +77,10 @@ pub struct ProxyState<P: Modeled, C: ExtendedState<C, P>> {
     pub iam_container: IamContainer<P>,
     pub extended_config: C,
     pub manager_client: ManagerClient,
-    pub http_client: HttpClient,
+    /// Clients to forward requests to upstream with, by endpoint
+    pub http_clients: UpstreamClients,
+    /// The revision of the config this state is built from, [`None`] if unknown
+    pub revision: Option<String>,
 }
 
 impl<
@@ -48,23 +88,60 @@ impl<
         C: ExtendedState<C, P> + DeserializeOwned,
     > ProxyState<P, C>
 {
+    /// Also saves the config to the disk cache once the state is built from it
     pub async fn new_from_manager() -> ProxyResult<Self> {
         let manager_client = ManagerClient::default();
         if !dev_mode() {
             debug!("start fetching config");
         }
-        let core_config = manager_client.get_core_config().await?;
-        let extended_config = manager_client
-            .get_extended_config(&EXTENDED_CONFIG_TYPE.load())
-            .await?;
+        let encrypted = manager_client.get_snapshot_string().await?;
         if !dev_mode() {
             debug!("end fetching config");
         }
+        let snapshot = Snapshot::from_encrypted(encrypted.clone())?;
+        let mut state = Self::new_from_snapshot(manager_client, snapshot).await?;
+        state.health.state_last_successful_update_at = Some(Instant::now());
+
+        if let Some(cache) = SnapshotCache::from_env() {
+            if let Err(e) = cache.save(&encrypted) {
+                warn!("caching config snapshot failed, error: {}", e);
+            }
+        }
+        Ok(state)
+    }
+
+    /// [`None`] if nothing is cached. The revision of the state is unknown, so that the whole
+    /// config is fetched once the manager is reachable again.
+    pub async fn new_from_cache() -> ProxyResult<Option<Self>> {
+        let cached = match SnapshotCache::from_env() {
+            Some(cache) => cache.load()?,
+            None => None,
+        };
+        let Some(cached) = cached else {
+            return Ok(None);
+        };
+        let snapshot = Snapshot::from_encrypted(cached.encrypted)?;
+        let mut state = Self::new_from_snapshot(ManagerClient::default(), snapshot).await?;
+        state.revision = None;
+        state.health.config_cached_at = Some(cached.saved_at);
+        Ok(Some(state))
+    }
+
+    async fn new_from_snapshot(
+        manager_client: ManagerClient,
+        snapshot: Snapshot<P, C>,
+    ) -> ProxyResult<Self> {
+        let Snapshot {
+            revision,
+            core_config,
+            extended_config,
+        } = snapshot;
 
         let extended_config = C::new_from(extended_config)?
             .with_core_config(&core_config)
             .await?;
 
+        let http_clients = UpstreamClients::new(&core_config.endpoints)?;
         let iam_container = IamContainer::new_from(core_config)?;
 
         let state = Self {
@@ -74,7 +151,8 @@ impl<
             extended_config,
             manager_client,
             // TODO: config timeout and stuff
-            http_client: Default::default(),
+            http_clients,
+            revision: Some(revision),
         };
         Ok(state)
     }
@@ -82,7 +160,8 @@ impl<
 
 /// StateManager updating proxy state from piam manager periodically.
 pub struct StateManager<P: Modeled, C: ExtendedState<C, P>> {
-    pub health_state: Health,
+    /// Shared with the readiness endpoint, see [`StateManager::health_check`]
+    pub health_state: SharedHealth,
     pub arc_state: ArcState<P, C>,
 }
 
@@ -93,20 +172,115 @@ impl<
 {
     pub async fn initialize() -> Self {
         let state = Self::get_new(When::Initializing).await.unwp();
+        metrics::set_container_sizes(&state.iam_container.sizes());
         Self {
-            health_state: Default::default(),
+            health_state: Arc::new(Mutex::new(state.health.clone())),
             arc_state: Arc::new(ArcSwap::from_pointee(state)),
         }
     }
 
+    /// Keeps the state updated. The revision of the config is watched to update as soon as it
+    /// changes, polling every `STATE_UPDATE_INTERVAL` is the fallback while watching fails.
+    pub async fn run(&self) {
+        let interval = Duration::from_secs(STATE_UPDATE_INTERVAL);
+        loop {
+            let current = self.arc_state.load_full();
+            let wait = Duration::from_secs(STATE_WATCH_WAIT);
+            let watch_start = Instant::now();
+            let watched = match &current.revision {
+                Some(known) => current.manager_client.watch_revision(known, wait).await,
+                None => Ok(Revision::Unsupported),
+            };
+            match watched {
+                Ok(Revision::NotModified) => {
+                    self.health().record_success();
+                    // e.g. a manager not holding the long poll, do not spin on it
+                    if watch_start.elapsed() < wait / 2 {
+                        tokio::time::sleep(interval).await;
+                    }
+                    continue;
+                }
+                Ok(Revision::Current(_)) => self.update_state().await,
+                Ok(Revision::Unsupported) => {
+                    tokio::time::sleep(interval).await;
+                    self.update_state().await;
+                }
+                Err(e) => {
+                    warn!(
+                        "ProxyState watching failed, fall back to polling, error: {}",
+                        e
+                    );
+                    tokio::time::sleep(interval).await;
+                    self.update_state().await;
+                }
+            }
+            if self.arc_state.load().revision == current.revision {
+                // not updated, do not watch the same revision again at once
+                tokio::time::sleep(interval).await;
+            }
+        }
+    }
+
+    /// Skips fetching and rebuilding when the revision of the config is not modified
     pub async fn update_state(&self) {
+        let current = self.arc_state.load();
+        if let Some(known) = &current.revision {
+            match current.manager_client.get_revision(Some(known)).await {
+                Ok(Revision::NotModified) => {
+                    debug!("config not modified, revision: {}", known);
+                    self.health().record_success();
+                    return;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    self.health().record_failure();
+                    metrics::observe_config_refresh_failure();
+                    warn!(
+                        "ProxyState revision checking failed, keep using the current one, error: {}",
+                        e
+                    );
+                    return;
+                }
+            }
+        }
+        let started_at = Instant::now();
         let get_result: ProxyResult<ProxyState<P, C>> = Self::get_new(When::Updating).await;
+        metrics::observe_config_refresh(get_result.is_ok(), started_at.elapsed());
         match get_result {
-            Ok(s) => self.arc_state.store(Arc::new(s)),
-            Err(e) => warn!("ProxyState updating failed, error: {}", e),
+            Ok(s) => {
+                metrics::set_container_sizes(&s.iam_container.sizes());
+                self.arc_state.store(Arc::new(s));
+                self.health().record_success();
+            }
+            Err(e) => {
+                let failed_times = {
+                    let mut health = self.health();
+                    health.record_failure();
+                    health.state_update_failed_times
+                };
+                warn!(
+                    "ProxyState updating failed {} times in a row, keep using the current one, \
+                    error: {}",
+                    failed_times, e
+                );
+            }
         };
     }
 
+    /// Liveness and readiness endpoints reporting the health tracked here, see [`HealthCheck::routes`]
+    pub fn health_check(&self) -> ProxyResult<HealthCheck> {
+        Ok(HealthCheck::new(
+            self.health_state.clone(),
+            staleness_threshold()?,
+        ))
+    }
+
+    fn health(&self) -> std::sync::MutexGuard<'_, Health> {
+        self.health_state
+            .lock()
+            .ex("health lock should not be poisoned")
+    }
+
     async fn get_new(when: When) -> ProxyResult<ProxyState<P, C>> {
         let retry_interval = 5;
         let mut retries = 0;
@@ -121,6 +295,20 @@ impl<
                             "ProxyState {} failed, error: {}, retries: {}",
                             when, e, retries
                         );
+                        if retries == 0 {
+                            // keep retrying in the background, see `update_state`
+                            match ProxyState::new_from_cache().await {
+                                Ok(Some(state)) => {
+                                    warn!(
+                                        "ProxyState initialized from the config cached at {:?}",
+                                        state.health.config_cached_at
+                                    );
+                                    return Ok(state);
+                                }
+                                Ok(None) => {}
+                                Err(e) => warn!("ProxyState loading cache failed, error: {}", e),
+                            }
+                        }
                         if dev_mode() && retries > 1 {
                             tokio::time::sleep(std::time::Duration::from_secs(retries * 5)).await;
                         }
@@ -0,0 +1,605 @@
+//! Temporary credentials of assumed roles, compatible with `AssumeRole` of AWS STS.
+//!
+//! The session token is the encrypted [`SessionClaims`] signed with `STS_SIGNING_KEY`, so any
+//! proxy sharing the key can resolve a session without shared storage. Requests of a session carry
+//! the token in `x-amz-security-token`, are signed with the temporary secret issued with it, and
+//! get the policies of the role instead of those of the user.
+
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use busylib::prelude::EnhancedExpect;
+use chrono::{SecondsFormat, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use http::{header::CONTENT_TYPE, Response, StatusCode};
+use hyper::{body, Body};
+use piam_core::{
+    crypto::{decrypt, encrypt},
+    policy::Modeled,
+    principal::{RoleId, UserId},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    config::STS_SIGNING_KEY,
+    container::IamContainer,
+    error::{ProxyError, ProxyResult},
+    signature::aws::AwsSigv4,
+    type_alias::{HttpRequest, HttpResponse},
+};
+
+pub const DEFAULT_SESSION_DURATION: u64 = 3600;
+pub const MIN_SESSION_DURATION: u64 = 900;
+/// Prefix of the temporary access key ids, like the ones issued by AWS STS
+pub const SESSION_ACCESS_KEY_PREFIX: &str = "ASIA";
+
+/// Parameters of the `AssumeRole` action, from the query string or the form encoded body
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AssumeRoleParams {
+    pub action: String,
+    /// Either an arn like `arn:aws:iam::123456789012:role/role1` or a role id
+    pub role_arn: String,
+    pub role_session_name: String,
+    pub duration_seconds: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionClaims {
+    pub user_id: UserId,
+    pub role_id: RoleId,
+    pub session_name: String,
+    /// The temporary access key id issued with the session, requests must be signed with it
+    pub access_key: String,
+    /// Hex SHA-256 of the temporary secret access key
+    secret_hash: String,
+    /// Unix timestamp in seconds
+    pub expires_at: u64,
+    nonce: String,
+}
+
+#[derive(Debug)]
+pub struct SessionCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: String,
+    pub expires_at: u64,
+}
+
+impl AssumeRoleParams {
+    pub fn from_urlencoded(s: &str) -> ProxyResult<Self> {
+        let params: Self = serde_urlencoded::from_str(s)
+            .map_err(|e| ProxyError::BadRequest(format!("invalid AssumeRole parameters: {e}")))?;
+        if params.action != "AssumeRole" {
+            return Err(ProxyError::OperationNotSupported(format!(
+                "STS action not supported: {}",
+                params.action
+            )));
+        }
+        if !valid_session_name(&params.role_session_name) {
+            return Err(ProxyError::BadRequest(format!(
+                "RoleSessionName must be 2 to 64 characters of [\\w+=,.@-], got {}",
+                params.role_session_name
+            )));
+        }
+        Ok(params)
+    }
+
+    pub fn role_id(&self) -> &str {
+        match self.role_arn.rsplit_once(":role/") {
+            // a role arn may have a path, e.g. role/path/role1
+            Some((_, name)) => name.rsplit('/').next().unwrap_or(name),
+            None => &self.role_arn,
+        }
+    }
+}
+
+impl SessionClaims {
+    /// The encrypted claims followed by their HMAC under `key`, hex encoded to be sent in headers
+    /// and query strings
+    pub fn to_token(&self, key: &str) -> String {
+        let payload = hex::encode(encrypt(
+            serde_yaml::to_string(self).ex("session claims should be serializable"),
+        ));
+        let signature = hex::encode(mac(key, &payload).finalize().into_bytes());
+        format!("{payload}.{signature}")
+    }
+
+    /// Tokens not signed with `key`, expired or used with another access key are rejected, only
+    /// the signed ones get decrypted
+    pub fn from_token(token: &str, key: &str, access_key: &str, now: u64) -> ProxyResult<Self> {
+        let (payload, signature) = token
+            .rsplit_once('.')
+            .ok_or_else(|| invalid("malformed session token"))?;
+        let signature = hex::decode(signature).map_err(|_| invalid("malformed session token"))?;
+        mac(key, payload)
+            .verify_slice(&signature)
+            .map_err(|_| invalid("bad session token signature"))?;
+        let claims: Self = hex::decode(payload)
+            .ok()
+            .and_then(|encrypted| String::from_utf8(encrypted).ok())
+            .and_then(|encrypted| serde_yaml::from_str(&decrypt(encrypted)).ok())
+            .ok_or_else(|| invalid("malformed session token"))?;
+        if claims.access_key != access_key {
+            return Err(invalid("session token issued to another access key"));
+        }
+        if claims.expires_at <= now {
+            return Err(invalid("session token expired"));
+        }
+        Ok(claims)
+    }
+
+    /// Claims of the session token in `req`, [`None`] if it has none. The request must be signed
+    /// with `access_key` and the temporary secret issued with the token.
+    pub fn from_request(
+        req: &HttpRequest,
+        key: &str,
+        access_key: &str,
+        now: u64,
+    ) -> ProxyResult<Option<Self>> {
+        let Some(token) = req.security_token() else {
+            return Ok(None);
+        };
+        let claims = Self::from_token(&token, key, access_key, now)?;
+        let secret = session_secret(key, &claims.nonce);
+        if hash(&secret) != claims.secret_hash {
+            return Err(invalid("secret of the session does not match"));
+        }
+        req.verify_aws_sigv4(&secret, now)
+            .map_err(|e| invalid(&e.to_string()))?;
+        Ok(Some(claims))
+    }
+}
+
+impl SessionCredentials {
+    pub fn to_xml(&self, params: &AssumeRoleParams, request_id: &str) -> String {
+        let expiration = Utc
+            .timestamp_opt(self.expires_at as i64, 0)
+            .single()
+            .ex("expiration should be a valid timestamp")
+            .to_rfc3339_opts(SecondsFormat::Secs, true);
+        format!(
+            "<AssumeRoleResponse xmlns=\"https://sts.amazonaws.com/doc/2011-06-15/\">\
+            <AssumeRoleResult>\
+            <AssumedRoleUser>\
+            <Arn>{role_arn}/{session_name}</Arn>\
+            <AssumedRoleId>{role_id}:{session_name}</AssumedRoleId>\
+            </AssumedRoleUser>\
+            <Credentials>\
+            <AccessKeyId>{access_key_id}</AccessKeyId>\
+            <SecretAccessKey>{secret_access_key}</SecretAccessKey>\
+            <SessionToken>{session_token}</SessionToken>\
+            <Expiration>{expiration}</Expiration>\
+            </Credentials>\
+            </AssumeRoleResult>\
+            <ResponseMetadata><RequestId>{request_id}</RequestId></ResponseMetadata>\
+            </AssumeRoleResponse>",
+            role_arn = xml_escape(&params.role_arn),
+            session_name = xml_escape(&params.role_session_name),
+            role_id = xml_escape(params.role_id()),
+            access_key_id = xml_escape(&self.access_key_id),
+            secret_access_key = xml_escape(&self.secret_access_key),
+            session_token = xml_escape(&self.session_token),
+            expiration = xml_escape(&expiration),
+            request_id = xml_escape(request_id),
+        )
+    }
+}
+
+/// Issues credentials of the role for the user of `base_access_key`, signed with `key`. The
+/// returned access key id ends with the account code of `access_key`, so that it keeps working.
+pub fn assume_role<P: Modeled>(
+    container: &IamContainer<P>,
+    key: &str,
+    base_access_key: &str,
+    access_key: &str,
+    params: &AssumeRoleParams,
+    now: u64,
+) -> ProxyResult<SessionCredentials> {
+    let user = container.find_user_by_base_access_key(base_access_key)?;
+    let role = container.find_role_by_user(user, params.role_id())?;
+    let max_duration = role
+        .max_session_duration
+        .unwrap_or(DEFAULT_SESSION_DURATION);
+    let duration = params
+        .duration_seconds
+        .unwrap_or_else(|| DEFAULT_SESSION_DURATION.min(max_duration));
+    if !(MIN_SESSION_DURATION..=max_duration).contains(&duration) {
+        return Err(ProxyError::BadRequest(format!(
+            "DurationSeconds must be between {MIN_SESSION_DURATION} and {max_duration}, got {duration}",
+        )));
+    }
+
+    let account_code = access_key
+        .strip_prefix(base_access_key)
+        .ok_or_else(|| invalid("access key does not start with the base access key"))?;
+    let id = Uuid::new_v4().simple().to_string().to_ascii_uppercase();
+    let nonce = Uuid::new_v4().simple().to_string();
+    let secret = session_secret(key, &nonce);
+    let claims = SessionClaims {
+        user_id: user.id.clone(),
+        role_id: role.id.clone(),
+        session_name: params.role_session_name.clone(),
+        access_key: format!("{SESSION_ACCESS_KEY_PREFIX}{}{account_code}", &id[..16]),
+        secret_hash: hash(&secret),
+        expires_at: now + duration,
+        nonce,
+    };
+    Ok(SessionCredentials {
+        access_key_id: claims.access_key.clone(),
+        secret_access_key: secret,
+        session_token: claims.to_token(key),
+        expires_at: claims.expires_at,
+    })
+}
+
+/// Handles an STS `AssumeRole` request, with parameters either in the query string or in the
+/// form encoded body as the AWS SDKs send them
+pub async fn assume_role_response<P: Modeled + Sync>(
+    container: &IamContainer<P>,
+    base_access_key: &str,
+    access_key: &str,
+    req: HttpRequest,
+) -> ProxyResult<HttpResponse> {
+    let (parts, b) = req.into_parts();
+    let params = match parts.uri.query() {
+        Some(query) if query.contains("Action=") => query.to_string(),
+        _ => {
+            let bytes = body::to_bytes(b)
+                .await
+                .map_err(|e| ProxyError::BadRequest(format!("failed to read body: {e}")))?;
+            String::from_utf8_lossy(&bytes).to_string()
+        }
+    };
+    let params = AssumeRoleParams::from_urlencoded(&params)?;
+    let key = signing_key()?;
+    let credentials = assume_role(container, &key, base_access_key, access_key, &params, now())?;
+    let xml = credentials.to_xml(&params, &Uuid::new_v4().to_string());
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/xml")
+        .body(Body::from(xml))
+        .ex("building AssumeRole response should work"))
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ex("system time should be after unix epoch")
+        .as_secs()
+}
+
+/// `STS_SIGNING_KEY`, sessions are not supported without it
+pub fn signing_key() -> ProxyResult<Arc<String>> {
+    let key = STS_SIGNING_KEY.load_full();
+    match key.is_empty() {
+        true => Err(ProxyError::OperationNotSupported(
+            "STS_SIGNING_KEY is not set".to_string(),
+        )),
+        false => Ok(key),
+    }
+}
+
+/// `RoleSessionName` as AWS accepts it, `[\w+=,.@-]{2,64}`
+fn valid_session_name(name: &str) -> bool {
+    (2..=64).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_+=,.@-".contains(c))
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The temporary secret of a session, derived from its nonce so that it needs no storage
+fn session_secret(key: &str, nonce: &str) -> String {
+    hex::encode(mac(key, &format!("secret.{nonce}")).finalize().into_bytes())
+}
+
+fn hash(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+fn mac(key: &str, payload: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).ex("HMAC should accept keys of any size");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+fn invalid(msg: &str) -> ProxyError {
+    ProxyError::InvalidAccessKey(format!("Invalid session: {msg}"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use aws_sigv4::http_request::{
+        sign, PercentEncodingMode, SignableBody, SignableRequest, SignatureLocation, SigningParams,
+        SigningSettings,
+    };
+    use busylib::ANY;
+    use hyper::Body;
+    use piam_core::{
+        account::aws::AwsAccount,
+        policy::{condition::ConditionPolicy, Policy},
+        principal::{Role, User},
+        relation_model::{PolicyRelationship, UserRoleRelationship},
+    };
+
+    use crate::{
+        config::CoreConfig,
+        container::IamContainer,
+        error::ProxyError,
+        signature::{
+            aws::{AwsSigv4, AwsSigv4SignParams},
+            SigHeader,
+        },
+        state::CoreState,
+        sts::{assume_role, AssumeRoleParams, SessionClaims, SessionCredentials},
+        type_alias::HttpRequest,
+    };
+
+    const KEY: &str = "sts-signing-key";
+
+    /// Signed the way the AWS SDKs sign S3 requests
+    fn signed_request(
+        location: SignatureLocation,
+        access_key: &str,
+        secret_key: &str,
+        token: &str,
+        signed_at: u64,
+    ) -> HttpRequest {
+        let mut req = http::Request::get("/bucket1/a%20b?list-type=2&prefix=x%2Fy")
+            .header("host", "s3.piam.test")
+            .header("x-amz-content-sha256", "UNSIGNED-PAYLOAD")
+            .body(Body::empty())
+            .unwrap();
+        let mut settings = SigningSettings::default();
+        settings.percent_encoding_mode = PercentEncodingMode::Single;
+        if location == SignatureLocation::QueryParams {
+            settings.expires_in = Some(Duration::from_secs(300));
+        }
+        settings.signature_location = location;
+        let params = SigningParams::builder()
+            .access_key(access_key)
+            .secret_key(secret_key)
+            .security_token(token)
+            .region("us-east-1")
+            .service_name("s3")
+            .time(UNIX_EPOCH + Duration::from_secs(signed_at))
+            .settings(settings)
+            .build()
+            .unwrap();
+        let signable = SignableRequest::new(
+            req.method(),
+            req.uri(),
+            req.headers(),
+            SignableBody::UnsignedPayload,
+        );
+        let (instructions, _) = sign(signable, &params).unwrap().into_parts();
+        instructions.apply_to_request(&mut req);
+        req
+    }
+
+    #[test]
+    fn assume_role_and_find_policies() {
+        let mut config = CoreConfig::<ConditionPolicy>::default();
+        config.accounts.push(AwsAccount {
+            id: "account1".to_string(),
+            code: "0001".to_string(),
+            ..Default::default()
+        });
+        for (id, key) in [("user1", "AKPSPERSALICE"), ("user2", "AKPSPERSBOB")] {
+            config.users.push(User {
+                id: id.to_string(),
+                base_access_key: key.to_string(),
+                ..Default::default()
+            });
+        }
+        config.roles.push(Role {
+            id: "role1".to_string(),
+            ..Default::default()
+        });
+        config.user_role_relationships.push(UserRoleRelationship {
+            id: "rel1".to_string(),
+            user_id: "user1".to_string(),
+            role_id: "role1".to_string(),
+        });
+        config.condition_policies.push(Policy {
+            id: "policy1".to_string(),
+            modeled_policy: vec![ConditionPolicy::default()],
+            ..Default::default()
+        });
+        config.policy_relationships.push(PolicyRelationship {
+            id: "rel2".to_string(),
+            policy_model: "Condition".to_string(),
+            role_id: Some("role1".to_string()),
+            account_id: ANY.to_string(),
+            region: ANY.to_string(),
+            policy_id: "policy1".to_string(),
+            ..Default::default()
+        });
+        let container = IamContainer::new_from(config).unwrap();
+        let account = container.find_account_by_code("0001").unwrap();
+        let params = AssumeRoleParams::from_urlencoded(
+            "Action=AssumeRole&Version=2011-06-15&RoleSessionName=s1\
+            &RoleArn=arn%3Aaws%3Aiam%3A%3A000000000001%3Arole%2Fteam%2Frole1",
+        )
+        .unwrap();
+        assert_eq!(params.role_id(), "role1");
+
+        let now = 1_700_000_000;
+        let credentials = assume_role(
+            &container,
+            KEY,
+            "AKPSPERSALICE",
+            "AKPSPERSALICE0001",
+            &params,
+            now,
+        )
+        .unwrap();
+        assert_eq!(credentials.expires_at, now + 3600);
+        let access_key = credentials.access_key_id.as_str();
+        assert!(access_key.starts_with("ASIA") && access_key.ends_with("0001"));
+        let token = credentials.session_token.as_str();
+        let claims = SessionClaims::from_token(token, KEY, access_key, now).unwrap();
+        let found = container
+            .find_policies_by_session(&claims, account, "us-east-1")
+            .unwrap();
+        assert_eq!(found.condition[0].id, "policy1");
+
+        // expired, used by another access key, or not signed with the key
+        let from_token = |token: &str, key: &str, access_key: &str, now: u64| {
+            SessionClaims::from_token(token, key, access_key, now)
+        };
+        assert!(from_token(token, KEY, access_key, credentials.expires_at).is_err());
+        assert!(from_token(token, KEY, "AKPSPERSALICE0001", now).is_err());
+        assert!(from_token(token, "another key", access_key, now).is_err());
+        let (payload, signature) = token.rsplit_once('.').unwrap();
+        let tampered = format!("{}x.{signature}", payload);
+        assert!(matches!(
+            from_token(&tampered, KEY, access_key, now),
+            Err(ProxyError::InvalidAccessKey(_))
+        ));
+        assert!(from_token("not a token", KEY, access_key, now).is_err());
+
+        // requests of the session must be signed with the issued secret
+        let secret = credentials.secret_access_key.as_str();
+        for location in [SignatureLocation::Headers, SignatureLocation::QueryParams] {
+            let req = signed_request(location, access_key, secret, token, now);
+            let (key_of_req, region) = req.extract_access_key_and_region().unwrap();
+            assert_eq!((key_of_req, region), (access_key, "us-east-1"));
+            let claims = SessionClaims::from_request(&req, KEY, access_key, now)
+                .unwrap()
+                .unwrap();
+            assert_eq!(claims.role_id, "role1");
+        }
+        let req = signed_request(
+            SignatureLocation::Headers,
+            access_key,
+            "guessed",
+            token,
+            now,
+        );
+        assert!(matches!(
+            SessionClaims::from_request(&req, KEY, access_key, now),
+            Err(ProxyError::InvalidAccessKey(_))
+        ));
+        let req = http::Request::get("/bucket1").body(Body::empty()).unwrap();
+        assert!(SessionClaims::from_request(&req, KEY, access_key, now)
+            .unwrap()
+            .is_none());
+
+        // replayed out of the allowed clock skew, or after the presigned url expired
+        let req = signed_request(SignatureLocation::Headers, access_key, secret, token, now);
+        assert!(SessionClaims::from_request(&req, KEY, access_key, now + 15 * 60).is_ok());
+        for at in [now - 15 * 60 - 1, now + 15 * 60 + 1] {
+            assert!(matches!(
+                SessionClaims::from_request(&req, KEY, access_key, at),
+                Err(ProxyError::InvalidAccessKey(_))
+            ));
+        }
+        let req = signed_request(
+            SignatureLocation::QueryParams,
+            access_key,
+            secret,
+            token,
+            now,
+        );
+        assert!(SessionClaims::from_request(&req, KEY, access_key, now + 300).is_ok());
+        assert!(matches!(
+            SessionClaims::from_request(&req, KEY, access_key, now + 301),
+            Err(ProxyError::InvalidAccessKey(_))
+        ));
+
+        // not trusted
+        assert!(matches!(
+            assume_role(
+                &container,
+                KEY,
+                "AKPSPERSBOB",
+                "AKPSPERSBOB0001",
+                &params,
+                now
+            ),
+            Err(ProxyError::InvalidAccessKey(_))
+        ));
+    }
+
+    #[test]
+    fn escape_assume_role_response() {
+        let invalid = |name: &str| {
+            AssumeRoleParams::from_urlencoded(&format!(
+                "Action=AssumeRole&RoleArn=role1&RoleSessionName={name}"
+            ))
+        };
+        assert!(invalid("s1%2B%3D%2C.%40-_").is_ok());
+        for name in ["s", "s%3C%2FArn%3E", "s%261", &"s".repeat(65)] {
+            assert!(matches!(invalid(name), Err(ProxyError::BadRequest(_))));
+        }
+
+        let params = AssumeRoleParams::from_urlencoded(
+            "Action=AssumeRole&RoleSessionName=s1\
+            &RoleArn=arn%3Aaws%3Aiam%3A%3A1%3Arole%2F%3CArn%3E%26%27%22",
+        )
+        .unwrap();
+        let credentials = SessionCredentials {
+            access_key_id: "ASIA1".to_string(),
+            secret_access_key: "secret".to_string(),
+            session_token: "token".to_string(),
+            expires_at: 1_700_000_000,
+        };
+        let xml = credentials.to_xml(&params, "<id>");
+        assert!(xml.contains(
+            "<Arn>arn:aws:iam::1:role/&lt;Arn&gt;&amp;&apos;&quot;/s1</Arn>\
+            <AssumedRoleId>&lt;Arn&gt;&amp;&apos;&quot;:s1</AssumedRoleId>"
+        ));
+        assert!(xml.contains("<RequestId>&lt;id&gt;</RequestId>"));
+    }
+
+    #[tokio::test]
+    async fn resign_presigned_request() {
+        let account = AwsAccount {
+            access_key: "AKUPSTREAM".to_string(),
+            secret_key: "upstream".to_string(),
+            ..Default::default()
+        };
+        let params = AwsSigv4SignParams::new_with(&account, "s3", "us-east-1");
+        let mut req = signed_request(
+            SignatureLocation::QueryParams,
+            "ASIA1",
+            "secret",
+            "token",
+            1_700_000_000,
+        );
+        // presigned urls come without it
+        req.headers_mut().remove("x-amz-content-sha256");
+        let req = req.sign_with_aws_sigv4_params(&params).await.unwrap();
+        assert_eq!(
+            req.uri().path_and_query().unwrap().as_str(),
+            "/bucket1/a%20b?list-type=2&prefix=x%2Fy"
+        );
+        assert_eq!(req.headers()["x-amz-content-sha256"], "UNSIGNED-PAYLOAD");
+        let authorization = req.headers()["authorization"].to_str().unwrap();
+        assert!(authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKUPSTREAM/"));
+        assert!(!req.headers().contains_key("x-amz-security-token"));
+    }
+}
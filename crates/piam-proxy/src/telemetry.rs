@@ -0,0 +1,205 @@
+//! OpenTelemetry tracing of the request stages, exported over OTLP/HTTP to `OTLP_ENDPOINT`.
+//!
+//! Stages such as `find_policies` or `forward` are `tracing` spans, which cost next to nothing
+//! until [`Telemetry::init_from_env`] installs the exporter. Each request should run in a
+//! [`request_span`], so that its stages make up one trace, and within [`with_request_id`] with
+//! the same id, so that error responses carry it.
+
+use std::future::Future;
+
+use log::{info, warn};
+use opentelemetry::{
+    global,
+    trace::{TraceContextExt, TracerProvider as _},
+    KeyValue,
+};
+use opentelemetry_http::HeaderExtractor;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{self, Tracer, TracerProvider},
+    Resource,
+};
+use tracing::{info_span, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::{
+    config::{proxy_region_env, OTLP_ENDPOINT, PROXY_TYPE},
+    error::{ProxyError, ProxyResult},
+    type_alias::HttpRequest,
+};
+
+/// Keeps exporting spans until [`Telemetry::shutdown`]
+#[derive(Debug)]
+pub struct Telemetry {
+    provider: TracerProvider,
+}
+
+impl Telemetry {
+    /// [`None`] if `OTLP_ENDPOINT` is not set. Must be called within a tokio runtime, and only
+    /// once as it installs the global `tracing` subscriber.
+    pub fn init_from_env() -> ProxyResult<Option<Self>> {
+        let endpoint = OTLP_ENDPOINT.load();
+        if endpoint.is_empty() {
+            return Ok(None);
+        }
+        let (telemetry, tracer) = Self::new(&endpoint)?;
+        tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .try_init()
+            .map_err(|e| {
+                ProxyError::OtherInternal(format!("failed to install tracing subscriber: {e}"))
+            })?;
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        info!("OTLP_ENDPOINT: {}", endpoint);
+        Ok(Some(telemetry))
+    }
+
+    /// `endpoint` is the base url of the collector such as `http://localhost:4318`, spans are
+    /// posted to its `/v1/traces`
+    fn new(endpoint: &str) -> ProxyResult<(Self, Tracer)> {
+        let exporter = opentelemetry_otlp::new_exporter()
+            .http()
+            .with_endpoint(endpoint)
+            .build_span_exporter()
+            .map_err(|e| ProxyError::InvalidConfig(format!("invalid OTLP_ENDPOINT: {e}")))?;
+        let resource = Resource::new([
+            KeyValue::new("service.name", "piam-proxy"),
+            KeyValue::new("piam.proxy_type", PROXY_TYPE.load().to_string()),
+            KeyValue::new("piam.region_env", proxy_region_env()),
+        ]);
+        let provider = TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_config(trace::config().with_resource(resource))
+            .build();
+        let tracer = provider.tracer("piam-proxy");
+        Ok((Self { provider }, tracer))
+    }
+
+    /// Exports the spans left, blocks until done
+    pub fn shutdown(self) {
+        for result in self.provider.force_flush() {
+            if let Err(e) = result {
+                warn!("failed to export spans: {}", e);
+            }
+        }
+    }
+}
+
+/// Span of a whole request, continuing the trace of its `traceparent` header if any.
+///
+/// The request id is recorded with it, and the trace id is returned as `x-patsnap-trace-id` by
+/// `add_piam_headers`, so either one leads to the other.
+pub fn request_span(req: &HttpRequest, request_id: &str) -> Span {
+    let span = info_span!(
+        "request",
+        otel.kind = "server",
+        http.method = %req.method(),
+        http.target = req.uri().path(),
+        request_id = request_id,
+    );
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(req.headers()))
+    });
+    span.set_parent(parent);
+    span
+}
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Runs the handling of a request with its id, so that responses made from errors carry the same
+/// `x-patsnap-request-id` as the [`request_span`] and the logs
+pub async fn with_request_id<F: Future>(request_id: String, f: F) -> F::Output {
+    REQUEST_ID.scope(request_id, f).await
+}
+
+/// Id of the request being handled, [`None`] outside of [`with_request_id`]
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Trace id of the current span, [`None`] outside of any trace or if tracing is not initialized
+pub fn current_trace_id() -> Option<String> {
+    let context = Span::current().context();
+    let span = context.span();
+    let span_context = span.span_context();
+    span_context
+        .is_valid()
+        .then(|| span_context.trace_id().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{body::Bytes, extract::State, routing::post, Router};
+    use http::Request;
+    use hyper::Body;
+    use opentelemetry::global;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use crate::telemetry::{current_trace_id, request_span, Telemetry};
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+    /// Collects the bodies posted to `/v1/traces` like an OTLP collector
+    async fn stand_in_collector() -> (SocketAddr, Arc<Mutex<Vec<Bytes>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let app = Router::new()
+            .route(
+                "/v1/traces",
+                post(
+                    |State(received): State<Arc<Mutex<Vec<Bytes>>>>, body: Bytes| async move {
+                        received.lock().unwrap().push(body);
+                    },
+                ),
+            )
+            .with_state(received.clone());
+        let server = axum::Server::bind(&([127, 0, 0, 1], 0).into()).serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, received)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn export_request_spans() {
+        let (addr, received) = stand_in_collector().await;
+        let (telemetry, tracer) = Telemetry::new(&format!("http://{addr}")).unwrap();
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        let req = Request::builder()
+            .uri("/bucket/key")
+            .header("traceparent", format!("00-{TRACE_ID}-00f067aa0ba902b7-01"))
+            .body(Body::empty())
+            .unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            let span = request_span(&req, "request-id-1");
+            let _entered = span.enter();
+            assert_eq!(current_trace_id().as_deref(), Some(TRACE_ID));
+            tracing::info_span!("forward").in_scope(|| {
+                assert_eq!(current_trace_id().as_deref(), Some(TRACE_ID));
+            });
+        });
+        assert_eq!(current_trace_id(), None);
+
+        tokio::task::spawn_blocking(|| telemetry.shutdown())
+            .await
+            .unwrap();
+        let received = std::mem::take(&mut *received.lock().unwrap());
+        let trace_id = u128::from_str_radix(TRACE_ID, 16).unwrap().to_be_bytes();
+        assert!(!received.is_empty());
+        assert!(received
+            .iter()
+            .all(|body| body.windows(16).any(|w| w == trace_id)));
+    }
+}
@@ -0,0 +1,339 @@
+//! TLS termination of client connections, served on `TLS_PORT` next to plain HTTP.
+//!
+//! Certificates are picked by the server name the client asks for, wildcard ones such as
+//! `*.s3.example.com` included so that virtual-hosted buckets are covered. Their files are checked
+//! every `TLS_RELOAD_INTERVAL` and reloaded once changed, so that renewing them takes no restart.
+
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use arc_swap::ArcSwap;
+use axum::{extract::ConnectInfo, Extension, Router};
+use hyper::server::conn::Http;
+use log::{debug, error, info, warn};
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{any_supported_type, CertifiedKey, SigningKey},
+    Certificate, PrivateKey, ServerConfig,
+};
+use rustls_pemfile::Item;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use webpki::{EndEntityCert, SubjectNameRef};
+
+use crate::{
+    client_addr::{TrustedProxies, TRUSTED},
+    config::{
+        TLS_CERTS, TLS_HANDSHAKE_TIMEOUT, TLS_HEADER_READ_TIMEOUT, TLS_PORT, TLS_RELOAD_INTERVAL,
+    },
+    error::{ProxyError, ProxyResult},
+};
+
+/// PEM files of a certificate chain and its private key
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CertFiles {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl CertFiles {
+    pub fn new(cert: &str, key: &str) -> Self {
+        Self {
+            cert: cert.into(),
+            key: key.into(),
+        }
+    }
+
+    /// Modification times of both files, to tell whether they changed since loaded
+    fn modified(&self) -> [Option<SystemTime>; 2] {
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        [modified(&self.cert), modified(&self.key)]
+    }
+
+    fn load(&self) -> ProxyResult<Arc<CertifiedKey>> {
+        let invalid = |path: &Path, e: String| {
+            ProxyError::InvalidConfig(format!("invalid TLS file '{}': {e}", path.display()))
+        };
+        let read = |path: &Path| fs::read(path).map_err(|e| invalid(path, e.to_string()));
+
+        let certs = rustls_pemfile::certs(&mut read(&self.cert)?.as_slice())
+            .map_err(|e| invalid(&self.cert, e.to_string()))?;
+        if certs.is_empty() {
+            return Err(invalid(&self.cert, "no certificate found".to_string()));
+        }
+        let key = rustls_pemfile::read_all(&mut read(&self.key)?.as_slice())
+            .map_err(|e| invalid(&self.key, e.to_string()))?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => Some(der),
+                _ => None,
+            })
+            .ok_or_else(|| invalid(&self.key, "no private key found".to_string()))?;
+        let key: Arc<dyn SigningKey> =
+            any_supported_type(&PrivateKey(key)).map_err(|e| invalid(&self.key, e.to_string()))?;
+        let certs = certs.into_iter().map(Certificate).collect();
+        Ok(Arc::new(CertifiedKey::new(certs, key)))
+    }
+}
+
+struct Loaded {
+    /// In the order of the files
+    keys: Vec<Arc<CertifiedKey>>,
+    modified: Vec<[Option<SystemTime>; 2]>,
+}
+
+/// Certificates to serve by server name, reloaded by [`CertResolver::reload`]
+pub struct CertResolver {
+    files: Vec<CertFiles>,
+    loaded: ArcSwap<Loaded>,
+}
+
+impl CertResolver {
+    pub fn new(files: Vec<CertFiles>) -> ProxyResult<Self> {
+        let loaded = Self::load(&files)?;
+        Ok(Self {
+            files,
+            loaded: ArcSwap::from_pointee(loaded),
+        })
+    }
+
+    /// [`None`] if `TLS_CERTS` is not set
+    pub fn from_env() -> ProxyResult<Option<Self>> {
+        let certs = TLS_CERTS.load();
+        if certs.trim().is_empty() {
+            return Ok(None);
+        }
+        let files = certs
+            .split(',')
+            .map(|pair| match pair.trim().split_once(':') {
+                Some((cert, key)) => Ok(CertFiles::new(cert, key)),
+                None => Err(ProxyError::InvalidConfig(format!(
+                    "invalid TLS_CERTS '{certs}': '{pair}' is not 'cert.pem:key.pem'"
+                ))),
+            })
+            .collect::<ProxyResult<Vec<_>>>()?;
+        Self::new(files).map(Some)
+    }
+
+    fn load(files: &[CertFiles]) -> ProxyResult<Loaded> {
+        // taken before reading, so that a change while reading is picked up by the next reload
+        let modified = files.iter().map(CertFiles::modified).collect();
+        let keys = files
+            .iter()
+            .map(CertFiles::load)
+            .collect::<ProxyResult<_>>()?;
+        Ok(Loaded { keys, modified })
+    }
+
+    /// Reloads all certificates if any of their files changed, returns whether reloaded. The ones
+    /// loaded are kept if any fails to load, e.g. a certificate renewed before its key.
+    pub fn reload(&self) -> ProxyResult<bool> {
+        let modified: Vec<_> = self.files.iter().map(CertFiles::modified).collect();
+        if modified == self.loaded.load().modified {
+            return Ok(false);
+        }
+        self.loaded.store(Arc::new(Self::load(&self.files)?));
+        Ok(true)
+    }
+
+    /// Keeps reloading every `TLS_RELOAD_INTERVAL`
+    pub async fn watch(self: Arc<Self>) {
+        let interval = Duration::from_secs(TLS_RELOAD_INTERVAL);
+        loop {
+            tokio::time::sleep(interval).await;
+            match self.reload() {
+                Ok(true) => info!("TLS certificates reloaded"),
+                Ok(false) => {}
+                Err(e) => warn!(
+                    "reloading TLS certificates failed, keep serving the loaded ones: {}",
+                    e
+                ),
+            }
+        }
+    }
+
+    /// The first certificate valid for `server_name`, or the first one if none is or the client
+    /// sent no server name, e.g. connecting by ip
+    fn find(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let loaded = self.loaded.load();
+        server_name
+            .and_then(|name| loaded.keys.iter().find(|key| is_valid_for(key, name)))
+            .or_else(|| loaded.keys.first())
+            .cloned()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.find(client_hello.server_name())
+    }
+}
+
+/// Matches names the way clients verify them, a wildcard covers exactly one label
+fn is_valid_for(key: &CertifiedKey, server_name: &str) -> bool {
+    let Ok(name) = SubjectNameRef::try_from_ascii_str(server_name) else {
+        return false;
+    };
+    key.end_entity_cert()
+        .ok()
+        .and_then(|cert| EndEntityCert::try_from(cert.0.as_slice()).ok())
+        .is_some_and(|cert| cert.verify_is_valid_for_subject_name(name).is_ok())
+}
+
+pub fn tls_port() -> ProxyResult<u16> {
+    let port = TLS_PORT.load();
+    port.trim()
+        .parse()
+        .map_err(|e| ProxyError::InvalidConfig(format!("invalid TLS_PORT '{port}': {e}")))
+}
+
+/// Serves `app` over TLS on `TLS_PORT` and keeps the certificates reloaded, meant to be spawned.
+///
+/// Handlers get the address of the client as `ConnectInfo<SocketAddr>`, like with
+/// `into_make_service_with_connect_info` on plain HTTP. It is the source address of the PROXY
+/// protocol header of a trusted load balancer if enabled, see `TrustedProxies::accept`.
+pub async fn serve(app: Router, resolver: Arc<CertResolver>) {
+    let port = match tls_port() {
+        Ok(port) => port,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("binding TLS port {} failed: {}", port, e);
+            return;
+        }
+    };
+    info!("serving TLS on {}", addr);
+    tokio::spawn(resolver.clone().watch());
+    serve_on(listener, app, resolver, Arc::new(TRUSTED.clone())).await;
+}
+
+pub(crate) async fn serve_on(
+    listener: TcpListener,
+    app: Router,
+    resolver: Arc<CertResolver>,
+    trusted: Arc<TrustedProxies>,
+) {
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // e.g. too many open files, which a busy loop would not help
+                warn!("accepting TLS connection failed: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        let trusted = trusted.clone();
+        tokio::spawn(async move {
+            // clients stalling the handshake or the headers must not pin connections forever
+            let handshake = Duration::from_secs(TLS_HANDSHAKE_TIMEOUT);
+            let accepted = tokio::time::timeout(handshake, async {
+                let client = trusted.accept(peer, &mut stream).await?;
+                let stream = acceptor.accept(stream).await.map_err(|e| {
+                    ProxyError::MalformedProtocol(format!("TLS handshake failed: {e}"))
+                })?;
+                Ok::<_, ProxyError>((client, stream))
+            })
+            .await;
+            let (client, stream) = match accepted {
+                Ok(Ok(accepted)) => accepted,
+                Ok(Err(e)) => {
+                    debug!("accepting TLS connection from {} failed: {}", peer, e);
+                    return;
+                }
+                Err(_) => {
+                    debug!("TLS handshake with {} timed out", peer);
+                    return;
+                }
+            };
+            let app = app.layer(Extension(ConnectInfo(client)));
+            let served = Http::new()
+                .http1_header_read_timeout(Duration::from_secs(TLS_HEADER_READ_TIMEOUT))
+                .serve_connection(stream, app)
+                .await;
+            if let Err(e) = served {
+                debug!("TLS connection with {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use rustls::sign::CertifiedKey;
+    use uuid::Uuid;
+
+    use crate::tls::{CertFiles, CertResolver};
+
+    #[test]
+    fn resolve_by_server_name() {
+        let testdata = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls");
+        let dir = std::env::temp_dir().join(format!("piam-tls-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let copy = |from: &str, to: &str| {
+            let to = dir.join(to);
+            fs::copy(format!("{testdata}/{from}"), &to).unwrap();
+            to.to_str().unwrap().to_string()
+        };
+        let other = CertFiles::new(&copy("other.pem", "a.pem"), &copy("other.key.pem", "a.key"));
+        let s3 = CertFiles::new(&copy("s3.pem", "b.pem"), &copy("s3.key.pem", "b.key"));
+        let resolver = CertResolver::new(vec![other.clone(), s3.clone()]).unwrap();
+
+        let cert = |key: Arc<CertifiedKey>| key.end_entity_cert().unwrap().clone();
+        let found = |name: Option<&str>| cert(resolver.find(name).unwrap());
+        let s3_cert = cert(s3.load().unwrap());
+        let other_cert = cert(other.load().unwrap());
+        assert_eq!(found(Some("bucket1.s3.piam.test")), s3_cert);
+        assert_eq!(found(Some("localhost")), s3_cert);
+        assert_eq!(found(Some("other.piam.test")), other_cert);
+        // falls back to the first one
+        assert_eq!(found(Some("a.bucket1.s3.piam.test")), other_cert);
+        assert_eq!(found(None), other_cert);
+
+        assert!(!resolver.reload().unwrap());
+        // renewed in place
+        copy("s3.pem", "a.pem");
+        copy("s3.key.pem", "a.key");
+        let later = SystemTime::now() + Duration::from_secs(1);
+        for file in ["a.pem", "a.key"] {
+            let file = fs::File::options()
+                .write(true)
+                .open(dir.join(file))
+                .unwrap();
+            file.set_modified(later).unwrap();
+        }
+        assert!(resolver.reload().unwrap());
+        assert_eq!(found(Some("other.piam.test")), s3_cert);
+
+        // kept if failed to reload
+        fs::write(dir.join("a.key"), "not a key").unwrap();
+        assert!(resolver.reload().is_err());
+        assert_eq!(found(None), s3_cert);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,6 +1,7 @@
 use hyper::{client::HttpConnector, Body};
+use hyper_rustls::HttpsConnector;
 
 pub type HttpRequest = http::Request<Body>;
 pub type HttpResponse = http::Response<Body>;
-pub type HttpClient = hyper::Client<HttpConnector, Body>;
+pub type HttpClient = hyper::Client<HttpsConnector<HttpConnector>, Body>;
 pub type IamEntityIdType = String;
@@ -0,0 +1,179 @@
+//! Clients forwarding requests to upstream endpoints, over HTTPS unless an endpoint is `http`.
+//!
+//! Certificates of upstream are always verified, against the system CAs and `UPSTREAM_CA_PATH`,
+//! or only against `ca_pem` of the endpoint if it has one, e.g. a MinIO cluster with a private CA.
+
+use std::{collections::HashMap, fs};
+
+use busylib::prelude::EnhancedExpect;
+use hyper_rustls::HttpsConnectorBuilder;
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
+use piam_core::endpoint::{Endpoint, EndpointId};
+use rustls::{Certificate, ClientConfig, RootCertStore};
+
+use crate::{
+    config::UPSTREAM_CA_PATH,
+    error::{ProxyError, ProxyResult},
+    type_alias::HttpClient,
+};
+
+/// Shared by the states built from every config update, so that its connections are kept
+static DEFAULT_CLIENT: Lazy<HttpClient> = Lazy::new(|| {
+    https_client(default_roots().ex("UPSTREAM_CA_PATH should be validated by set_constants"))
+});
+
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamClients {
+    /// Clients of the endpoints with their own `ca_pem`
+    by_endpoint: HashMap<EndpointId, HttpClient>,
+}
+
+impl UpstreamClients {
+    pub fn new(endpoints: &[Endpoint]) -> ProxyResult<Self> {
+        let mut by_endpoint = HashMap::new();
+        for endpoint in endpoints {
+            let Some(ca_pem) = &endpoint.ca_pem else {
+                continue;
+            };
+            let mut roots = RootCertStore::empty();
+            add_pem(&mut roots, ca_pem).map_err(|e| {
+                ProxyError::InvalidConfig(format!(
+                    "invalid ca_pem of endpoint {}: {e}",
+                    endpoint.id
+                ))
+            })?;
+            by_endpoint.insert(endpoint.id.clone(), https_client(roots));
+        }
+        Ok(Self { by_endpoint })
+    }
+
+    /// The client to forward requests to `endpoint` with
+    pub fn get(&self, endpoint: &Endpoint) -> &HttpClient {
+        self.by_endpoint
+            .get(&endpoint.id)
+            .unwrap_or(&DEFAULT_CLIENT)
+    }
+}
+
+fn https_client(roots: RootCertStore) -> HttpClient {
+    let tls = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = HttpsConnectorBuilder::new()
+        .with_tls_config(tls)
+        .https_or_http()
+        .enable_http1()
+        .build();
+    hyper::Client::builder().build(connector)
+}
+
+/// The system CAs and the ones in `UPSTREAM_CA_PATH`
+pub fn default_roots() -> ProxyResult<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) => {
+            let (added, ignored) = roots.add_parsable_certificates(&certs);
+            debug!("system CA certificates added: {added}, ignored: {ignored}");
+        }
+        Err(e) => warn!("failed to load system CA certificates: {}", e),
+    }
+
+    let path = UPSTREAM_CA_PATH.load();
+    if !path.is_empty() {
+        fs::read_to_string(path.as_str())
+            .map_err(|e| e.to_string())
+            .and_then(|pem| add_pem(&mut roots, &pem))
+            .map_err(|e| {
+                ProxyError::InvalidConfig(format!("invalid UPSTREAM_CA_PATH '{path}': {e}"))
+            })?;
+        info!("UPSTREAM_CA_PATH: {}", path);
+    }
+    Ok(roots)
+}
+
+fn add_pem(roots: &mut RootCertStore, pem: &str) -> Result<(), String> {
+    let certs = rustls_pemfile::certs(&mut pem.as_bytes()).map_err(|e| e.to_string())?;
+    if certs.is_empty() {
+        return Err("no certificate found".to_string());
+    }
+    for der in certs {
+        roots.add(&Certificate(der)).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use axum::{extract::ConnectInfo, routing::get, Router};
+    use piam_core::endpoint::{Endpoint, Scheme};
+    use tokio::net::TcpListener;
+
+    use crate::{
+        client_addr::TrustedProxies,
+        tls::{serve_on, CertFiles, CertResolver},
+        upstream::UpstreamClients,
+    };
+
+    #[tokio::test]
+    async fn verify_with_endpoint_ca() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls");
+        let resolver = CertResolver::new(vec![CertFiles::new(
+            &format!("{dir}/s3.pem"),
+            &format!("{dir}/s3.key.pem"),
+        )])
+        .unwrap();
+        let app = Router::new().route(
+            "/",
+            get(|ConnectInfo(peer): ConnectInfo<SocketAddr>| async move { peer.ip().to_string() }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve_on(
+            listener,
+            app,
+            Arc::new(resolver),
+            Arc::new(TrustedProxies::default()),
+        ));
+
+        let endpoint = Endpoint {
+            ca_pem: Some(include_str!("../testdata/tls/ca.pem").to_string()),
+            ..Endpoint::new(
+                "local",
+                "local",
+                Scheme::Https,
+                &format!("localhost:{port}"),
+            )
+        };
+        let clients = UpstreamClients::new(std::slice::from_ref(&endpoint)).unwrap();
+        let res = clients
+            .get(&endpoint)
+            .get(endpoint.url(None, "/").parse().unwrap())
+            .await
+            .unwrap();
+        assert!(res.status().is_success());
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, "127.0.0.1");
+
+        // not trusted by the system CAs
+        let untrusted = Endpoint {
+            id: "untrusted".to_string(),
+            ca_pem: None,
+            ..endpoint
+        };
+        assert!(clients
+            .get(&untrusted)
+            .get(untrusted.url(None, "/").parse().unwrap())
+            .await
+            .is_err());
+
+        let invalid = Endpoint {
+            ca_pem: Some("not a certificate".to_string()),
+            ..untrusted
+        };
+        assert!(UpstreamClients::new(&[invalid]).is_err());
+    }
+}